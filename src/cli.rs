@@ -0,0 +1,305 @@
+//! # Command-Line Arguments
+//!
+//! This module parses the adapter's command-line arguments. Without arguments
+//! the adapter runs as an HTTP server; `--once --output <path>` instead runs a
+//! single discovery, writes the targets as a Prometheus `file_sd` JSON file and
+//! exits, for cron-style target generation. `--config <path>` reads the
+//! configuration from an explicit file in either mode.
+//!
+//! The remaining flags mirror config fields and take precedence over both the
+//! config file and the environment variables. `--help` lists them all.
+
+use crate::config::Config;
+use clap::{Args as ClapArgs, Parser};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// How the adapter runs
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    /// Serve discovery over HTTP
+    Serve,
+    /// Run discovery once and write the targets to a file
+    Once {
+        /// `file_sd` output file
+        output: PathBuf,
+    },
+}
+
+/// Parsed command-line arguments
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    /// How the adapter runs
+    pub mode: Mode,
+    /// Config file to read instead of looking one up (None = `CONFIG_FILE` or
+    /// the working directory)
+    pub config_file: Option<PathBuf>,
+    /// Config fields set on the command line
+    pub overrides: Overrides,
+}
+
+/// Prometheus HTTP service discovery for AWS Cloud Map
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Run discovery once, write the targets to --output and exit
+    #[arg(long, requires = "output")]
+    once: bool,
+
+    /// `file_sd` JSON file written by --once
+    #[arg(long, value_name = "PATH", requires = "once")]
+    output: Option<PathBuf>,
+
+    /// Config file to read instead of looking one up in the working directory
+    /// [env: CONFIG_FILE]
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    overrides: Overrides,
+}
+
+/// Config fields that can be set on the command line
+///
+/// Each flag left out keeps the value from the environment, the config file
+/// or the default.
+#[derive(ClapArgs, Debug, Default, PartialEq)]
+pub struct Overrides {
+    /// Server bind address [env: HOST]
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Server port [env: PORT]
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// AWS region for Cloud Map operations [env: AWS_REGION]
+    #[arg(long, value_name = "REGION")]
+    pub aws_region: Option<String>,
+
+    /// AWS regions to discover together, comma-separated or repeated
+    /// [env: AWS_REGIONS]
+    #[arg(long, value_name = "REGION", value_delimiter = ',')]
+    pub aws_regions: Vec<String>,
+
+    /// Namespaces to discover, comma-separated or repeated
+    /// [env: CLOUDMAP_NAMESPACE]
+    #[arg(long, value_name = "NAMESPACE", value_delimiter = ',')]
+    pub namespace: Vec<String>,
+
+    /// Instance attributes holding the target IP, in order of preference
+    /// [env: IP_ATTRIBUTE_NAMES]
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    pub ip_attribute_names: Vec<String>,
+
+    /// Port appended to instances registered without one [env: DEFAULT_PORT]
+    #[arg(long, value_name = "PORT")]
+    pub default_port: Option<u16>,
+
+    /// Seconds between background discovery runs [env: REFRESH_INTERVAL]
+    #[arg(long, value_name = "SECONDS")]
+    pub refresh_interval: Option<u64>,
+}
+
+impl Overrides {
+    /// Applies the flags that were set on top of `config`
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(host) = &self.host {
+            config.host = host.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(region) = &self.aws_region {
+            config.aws_region = Some(region.clone());
+        }
+        if !self.aws_regions.is_empty() {
+            config.aws_regions = self.aws_regions.clone();
+        }
+        if !self.namespace.is_empty() {
+            config.cloudmap_namespace = self.namespace.clone();
+        }
+        if !self.ip_attribute_names.is_empty() {
+            config.ip_attribute_names = self.ip_attribute_names.clone();
+        }
+        if let Some(port) = self.default_port {
+            config.default_port = Some(port);
+        }
+        if let Some(interval) = self.refresh_interval {
+            config.refresh_interval_seconds = interval;
+        }
+    }
+}
+
+/// Parses command-line arguments (including the program name)
+///
+/// # Returns
+///
+/// * `Ok(Args)` - The requested run mode, config file and overrides
+/// * `Err(clap::Error)` - Invalid arguments, or `--help`/`--version`; the
+///   error prints itself and exits with the matching status
+pub fn parse<I, T>(args: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let cli = Cli::try_parse_from(args)?;
+
+    let mode = match cli.output {
+        Some(output) => Mode::Once { output },
+        None => Mode::Serve,
+    };
+    Ok(Args {
+        mode,
+        config_file: cli.config,
+        overrides: cli.overrides,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("cloudmap-sd")
+            .chain(args.iter().copied())
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_defaults_to_serve() {
+        assert_eq!(
+            parse(args(&[])).unwrap(),
+            Args {
+                mode: Mode::Serve,
+                config_file: None,
+                overrides: Overrides::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let expected = Some(PathBuf::from("/etc/cloudmap-sd/config.yaml"));
+
+        assert_eq!(
+            parse(args(&["--config", "/etc/cloudmap-sd/config.yaml"]))
+                .unwrap()
+                .config_file,
+            expected
+        );
+        assert_eq!(
+            parse(args(&[
+                "--once",
+                "--config=/etc/cloudmap-sd/config.yaml",
+                "--output",
+                "targets.json"
+            ]))
+            .unwrap()
+            .config_file,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_once_with_output() {
+        let expected = Mode::Once {
+            output: PathBuf::from("/etc/prometheus/cloudmap.json"),
+        };
+
+        assert_eq!(
+            parse(args(&[
+                "--once",
+                "--output",
+                "/etc/prometheus/cloudmap.json"
+            ]))
+            .unwrap()
+            .mode,
+            expected
+        );
+        assert_eq!(
+            parse(args(&["--output=/etc/prometheus/cloudmap.json", "--once"]))
+                .unwrap()
+                .mode,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_arguments() {
+        assert!(parse(args(&["--once"])).is_err());
+        assert!(parse(args(&["--output", "targets.json"])).is_err());
+        assert!(parse(args(&["--once", "--output"])).is_err());
+        assert!(parse(args(&["--verbose"])).is_err());
+        assert!(parse(args(&["--config"])).is_err());
+        assert!(parse(args(&["--port", "http"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_config_overrides() {
+        let overrides = parse(args(&[
+            "--host",
+            "127.0.0.1",
+            "--port=9090",
+            "--aws-region",
+            "eu-west-1",
+            "--namespace",
+            "production,staging",
+            "--namespace",
+            "canary",
+            "--refresh-interval",
+            "30",
+        ]))
+        .unwrap()
+        .overrides;
+
+        assert_eq!(
+            overrides,
+            Overrides {
+                host: Some("127.0.0.1".to_string()),
+                port: Some(9090),
+                aws_region: Some("eu-west-1".to_string()),
+                namespace: vec![
+                    "production".to_string(),
+                    "staging".to_string(),
+                    "canary".to_string()
+                ],
+                refresh_interval: Some(30),
+                ..Overrides::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_help_is_generated() {
+        let error = parse(args(&["--help"])).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::DisplayHelp);
+        let help = error.to_string();
+        assert!(help.contains("--namespace <NAMESPACE>"));
+        assert!(help.contains("Seconds between background discovery runs"));
+    }
+
+    #[test]
+    fn test_overrides_take_precedence_over_env() {
+        let mut config = Config::default();
+        config.apply_env(|name| match name {
+            "PORT" => Some("8080".to_string()),
+            "CLOUDMAP_NAMESPACE" => Some("staging".to_string()),
+            "AWS_REGION" => Some("eu-west-1".to_string()),
+            _ => None,
+        });
+        let overrides = parse(args(&["--port", "9090", "--namespace", "production"]))
+            .unwrap()
+            .overrides;
+
+        overrides.apply(&mut config);
+
+        assert_eq!(config.port, 9090);
+        assert_eq!(config.cloudmap_namespace, vec!["production"]);
+        // Left alone without a flag
+        assert_eq!(config.aws_region, Some("eu-west-1".to_string()));
+    }
+}
@@ -0,0 +1,652 @@
+//! # Cloud Map API Client
+//!
+//! This module abstracts the Cloud Map operations used by discovery behind
+//! the `CloudMapApi` trait, so discovery can be exercised against a mock client
+//! in tests. The AWS SDK client implements the trait directly.
+//!
+//! Each list call fetches a single page; callers thread `next_token` through
+//! repeated calls to read every page. A page request rejected because its
+//! token expired fails with [`ExpiredTokenError`], so callers can restart the
+//! listing instead of failing.
+//!
+//! Clients are created through a [`ClientFactory`], so discovery can swap in a
+//! freshly built client (with freshly resolved credentials) while running.
+//! With an [`AssumeRole`] configured, the factory's clients use credentials
+//! from STS `AssumeRole`, which the SDK refreshes before they expire.
+
+use crate::discovery::AwsError;
+use aws_config::SdkConfig;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::sts::AssumeRoleProvider;
+use aws_sdk_servicediscovery::Client as ServiceDiscoveryClient;
+use aws_sdk_servicediscovery::config::SharedAsyncSleep;
+use aws_sdk_servicediscovery::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_servicediscovery::types::{
+    HealthStatusFilter, HttpInstanceSummary, InstanceSummary, Namespace, NamespaceSummary, Service,
+    ServiceFilter, ServiceFilterName, ServiceSummary,
+};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Error returned by Cloud Map API calls
+pub type ClientError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Future returned by Cloud Map API calls
+pub type ClientFuture<T> = Pin<Box<dyn Future<Output = Result<T, ClientError>> + Send>>;
+
+/// A single page of results from a Cloud Map list operation
+#[derive(Debug)]
+pub struct Page<T> {
+    /// Items on this page
+    pub items: Vec<T>,
+    /// Token for the next page (None = this was the last page)
+    pub next_token: Option<String>,
+}
+
+/// Error for a page request whose pagination token has expired
+#[derive(Debug)]
+pub struct ExpiredTokenError(pub String);
+
+impl fmt::Display for ExpiredTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pagination token expired: {}", self.0)
+    }
+}
+
+impl std::error::Error for ExpiredTokenError {}
+
+/// Cloud Map operations used by discovery
+#[cfg_attr(test, mockall::automock)]
+pub trait CloudMapApi: Send + Sync {
+    /// Lists one page of the namespaces visible to the caller
+    fn list_namespaces(&self, next_token: Option<String>) -> ClientFuture<Page<NamespaceSummary>>;
+
+    /// Lists one page of the services registered in a namespace
+    fn list_services(
+        &self,
+        namespace_id: String,
+        next_token: Option<String>,
+    ) -> ClientFuture<Page<ServiceSummary>>;
+
+    /// Lists one page of the instances registered to a service
+    fn list_instances(
+        &self,
+        service_id: String,
+        next_token: Option<String>,
+    ) -> ClientFuture<Page<InstanceSummary>>;
+
+    /// Gets one page of raw health statuses for a batch of a service's instances,
+    /// as `(instance ID, status)` pairs
+    fn get_instances_health_status(
+        &self,
+        service_id: String,
+        instance_ids: Vec<String>,
+        next_token: Option<String>,
+    ) -> ClientFuture<Page<(String, String)>>;
+
+    /// Lists one page of the tags of a Cloud Map resource, as `(key, value)` pairs
+    fn list_tags_for_resource(
+        &self,
+        resource_arn: String,
+        next_token: Option<String>,
+    ) -> ClientFuture<Page<(String, String)>>;
+
+    /// Gets a single service by ID
+    fn get_service(&self, service_id: String) -> ClientFuture<Service>;
+
+    /// Gets a single namespace by ID
+    fn get_namespace(&self, namespace_id: String) -> ClientFuture<Namespace>;
+
+    /// Discovers a service's instances by namespace and service name, with
+    /// their computed health status
+    ///
+    /// `DiscoverInstances` isn't paginated; it returns every matching instance.
+    fn discover_instances(
+        &self,
+        namespace_name: String,
+        service_name: String,
+        health_status: Option<HealthStatusFilter>,
+    ) -> ClientFuture<Vec<HttpInstanceSummary>>;
+}
+
+/// Builds Cloud Map clients
+#[cfg_attr(test, mockall::automock)]
+pub trait ClientFactory: Send + Sync {
+    /// Builds a client from freshly loaded AWS configuration
+    ///
+    /// The new client resolves credentials from scratch instead of reusing the
+    /// ones cached by a previous client.
+    fn build(&self) -> ClientFuture<Arc<dyn CloudMapApi>>;
+
+    /// Builds a client like [`ClientFactory::build`], but for `region`
+    fn build_in_region(&self, region: String) -> ClientFuture<Arc<dyn CloudMapApi>>;
+}
+
+/// Session name reported to STS for assumed-role sessions
+const ASSUME_ROLE_SESSION_NAME: &str = "cloudmap-prometheus-sd";
+
+/// IAM role to assume for Cloud Map calls, e.g. in another account
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssumeRole {
+    /// ARN of the role to assume
+    pub role_arn: String,
+    /// External ID required by the role's trust policy, if any
+    pub external_id: Option<String>,
+}
+
+/// Builds AWS SDK clients from the default configuration chain
+#[derive(Clone, Debug)]
+pub struct AwsClientFactory {
+    /// AWS region override (None = region from environment/profile)
+    region: Option<String>,
+    /// Service endpoint override, e.g. LocalStack (None = the AWS endpoint)
+    endpoint_url: Option<String>,
+    /// Role assumed with the default chain's credentials (None = use them directly)
+    assume_role: Option<AssumeRole>,
+    /// Sleep implementation shared with our own retry backoff
+    sleep: SharedAsyncSleep,
+}
+
+impl AwsClientFactory {
+    /// Creates a factory
+    ///
+    /// # Arguments
+    ///
+    /// * `region` - AWS region override (None = region from environment/profile)
+    /// * `sleep` - Sleep implementation for the SDK's retries and timeouts
+    pub fn new(region: Option<String>, sleep: SharedAsyncSleep) -> Self {
+        Self {
+            region,
+            endpoint_url: None,
+            assume_role: None,
+            sleep,
+        }
+    }
+
+    /// Makes clients call Cloud Map as `assume_role`
+    ///
+    /// # Arguments
+    ///
+    /// * `assume_role` - Role to assume (None = the default chain's credentials)
+    pub fn with_assume_role(mut self, assume_role: Option<AssumeRole>) -> Self {
+        self.assume_role = assume_role;
+        self
+    }
+
+    /// Returns a copy of this factory building clients for `region`
+    pub fn in_region(&self, region: &str) -> Self {
+        Self {
+            region: Some(region.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Sends requests to `endpoint_url` instead of the AWS endpoint
+    ///
+    /// Meant for testing against LocalStack or another Cloud Map mock.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint_url` - Endpoint override (None = the AWS endpoint)
+    pub fn with_endpoint_url(mut self, endpoint_url: Option<String>) -> Self {
+        self.endpoint_url = endpoint_url;
+        self
+    }
+
+    /// Loads the AWS configuration from the default provider chain
+    ///
+    /// With a role to assume, the chain's credentials are only used to call
+    /// STS, and the returned configuration carries the assumed credentials.
+    pub async fn load_sdk_config(&self) -> SdkConfig {
+        let sdk_config = self.loader().load().await;
+        match self.assume_role_provider(&sdk_config).await {
+            Some(provider) => self.loader().credentials_provider(provider).load().await,
+            None => sdk_config,
+        }
+    }
+
+    /// Builds the credentials provider for the role to assume, if any
+    ///
+    /// The provider calls STS with `base`'s credentials and region. It caches
+    /// the assumed credentials and fetches new ones before they expire.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Configuration resolved from the default provider chain
+    pub async fn assume_role_provider(&self, base: &SdkConfig) -> Option<AssumeRoleProvider> {
+        let assume_role = self.assume_role.as_ref()?;
+        let mut builder = AssumeRoleProvider::builder(&assume_role.role_arn)
+            .session_name(ASSUME_ROLE_SESSION_NAME)
+            .configure(base);
+        if let Some(external_id) = &assume_role.external_id {
+            builder = builder.external_id(external_id);
+        }
+        Some(builder.build().await)
+    }
+
+    /// Starts a configuration loader with the region and endpoint overrides
+    fn loader(&self) -> aws_config::ConfigLoader {
+        let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest())
+            .sleep_impl(self.sleep.clone());
+        if let Some(region) = &self.region {
+            loader = loader.region(aws_config::Region::new(region.clone()));
+        }
+        if let Some(endpoint_url) = &self.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+        loader
+    }
+
+    /// Resolves the region the SDK would pick on its own, from the environment,
+    /// the shared profile or instance metadata, ignoring `aws_region`
+    pub async fn default_region() -> Option<String> {
+        RegionProviderChain::default_provider()
+            .region()
+            .await
+            .map(|region| region.to_string())
+    }
+}
+
+impl ClientFactory for AwsClientFactory {
+    fn build(&self) -> ClientFuture<Arc<dyn CloudMapApi>> {
+        let factory = self.clone();
+        Box::pin(async move {
+            let sdk_config = factory.load_sdk_config().await;
+            let client: Arc<dyn CloudMapApi> = Arc::new(build_client(&sdk_config)?);
+            Ok(client)
+        })
+    }
+
+    fn build_in_region(&self, region: String) -> ClientFuture<Arc<dyn CloudMapApi>> {
+        self.in_region(&region).build()
+    }
+}
+
+impl CloudMapApi for ServiceDiscoveryClient {
+    fn list_namespaces(&self, next_token: Option<String>) -> ClientFuture<Page<NamespaceSummary>> {
+        let request = ServiceDiscoveryClient::list_namespaces(self).set_next_token(next_token);
+        Box::pin(async move {
+            let response = request.send().await.map_err(classify_error)?;
+            Ok(Page {
+                items: response.namespaces().to_vec(),
+                next_token: response.next_token().map(str::to_string),
+            })
+        })
+    }
+
+    fn list_services(
+        &self,
+        namespace_id: String,
+        next_token: Option<String>,
+    ) -> ClientFuture<Page<ServiceSummary>> {
+        let request = ServiceDiscoveryClient::list_services(self).set_next_token(next_token);
+        Box::pin(async move {
+            let service_filter = ServiceFilter::builder()
+                .name(ServiceFilterName::NamespaceId)
+                .values(namespace_id)
+                .build()?;
+            let response = request
+                .filters(service_filter)
+                .send()
+                .await
+                .map_err(classify_error)?;
+            Ok(Page {
+                items: response.services().to_vec(),
+                next_token: response.next_token().map(str::to_string),
+            })
+        })
+    }
+
+    fn list_instances(
+        &self,
+        service_id: String,
+        next_token: Option<String>,
+    ) -> ClientFuture<Page<InstanceSummary>> {
+        let request = ServiceDiscoveryClient::list_instances(self)
+            .service_id(service_id)
+            .set_next_token(next_token);
+        Box::pin(async move {
+            let response = request.send().await.map_err(classify_error)?;
+            Ok(Page {
+                items: response.instances().to_vec(),
+                next_token: response.next_token().map(str::to_string),
+            })
+        })
+    }
+
+    fn get_instances_health_status(
+        &self,
+        service_id: String,
+        instance_ids: Vec<String>,
+        next_token: Option<String>,
+    ) -> ClientFuture<Page<(String, String)>> {
+        let request = ServiceDiscoveryClient::get_instances_health_status(self)
+            .service_id(service_id)
+            .set_instances(Some(instance_ids))
+            .set_next_token(next_token);
+        Box::pin(async move {
+            let response = request.send().await.map_err(classify_error)?;
+            Ok(Page {
+                items: response
+                    .status()
+                    .into_iter()
+                    .flatten()
+                    .map(|(id, status)| (id.clone(), status.as_str().to_string()))
+                    .collect(),
+                next_token: response.next_token().map(str::to_string),
+            })
+        })
+    }
+
+    fn list_tags_for_resource(
+        &self,
+        resource_arn: String,
+        next_token: Option<String>,
+    ) -> ClientFuture<Page<(String, String)>> {
+        // Cloud Map returns every tag in a single response
+        if next_token.is_some() {
+            return Box::pin(async {
+                Ok(Page {
+                    items: Vec::new(),
+                    next_token: None,
+                })
+            });
+        }
+        let request =
+            ServiceDiscoveryClient::list_tags_for_resource(self).resource_arn(resource_arn);
+        Box::pin(async move {
+            let response = request.send().await.map_err(classify_error)?;
+            Ok(Page {
+                items: response
+                    .tags()
+                    .iter()
+                    .map(|tag| (tag.key().to_string(), tag.value().to_string()))
+                    .collect(),
+                next_token: None,
+            })
+        })
+    }
+
+    fn get_service(&self, service_id: String) -> ClientFuture<Service> {
+        let request = ServiceDiscoveryClient::get_service(self).id(service_id);
+        Box::pin(async move {
+            let response = request.send().await.map_err(classify_error)?;
+            response
+                .service()
+                .cloned()
+                .ok_or_else(|| ClientError::from("get_service returned no service"))
+        })
+    }
+
+    fn get_namespace(&self, namespace_id: String) -> ClientFuture<Namespace> {
+        let request = ServiceDiscoveryClient::get_namespace(self).id(namespace_id);
+        Box::pin(async move {
+            let response = request.send().await.map_err(classify_error)?;
+            response
+                .namespace()
+                .cloned()
+                .ok_or_else(|| ClientError::from("get_namespace returned no namespace"))
+        })
+    }
+
+    fn discover_instances(
+        &self,
+        namespace_name: String,
+        service_name: String,
+        health_status: Option<HealthStatusFilter>,
+    ) -> ClientFuture<Vec<HttpInstanceSummary>> {
+        let request = ServiceDiscoveryClient::discover_instances(self)
+            .namespace_name(namespace_name)
+            .service_name(service_name)
+            .set_health_status(health_status);
+        Box::pin(async move {
+            let response = request.send().await.map_err(classify_error)?;
+            Ok(response.instances().to_vec())
+        })
+    }
+}
+
+/// Builds the Cloud Map client from the loaded AWS configuration
+///
+/// The SDK client itself is built lazily and only fails on the first request,
+/// so settings that can never work are checked here instead.
+///
+/// # Returns
+///
+/// * `Ok(ServiceDiscoveryClient)` - Client ready for requests
+/// * `Err(ClientError)` - No region or no credentials provider could be resolved
+pub fn build_client(sdk_config: &SdkConfig) -> Result<ServiceDiscoveryClient, ClientError> {
+    if sdk_config.region().is_none() {
+        return Err("no AWS region configured (set aws_region or AWS_REGION)".into());
+    }
+    if sdk_config.credentials_provider().is_none() {
+        return Err("no AWS credentials provider could be resolved".into());
+    }
+    Ok(ServiceDiscoveryClient::new(sdk_config))
+}
+
+/// Describes a configured `aws_region` that differs from the region the SDK
+/// resolves on its own
+///
+/// The configured region is always the one the client uses; the mismatch is
+/// only reported so it doesn't go unnoticed.
+///
+/// # Returns
+///
+/// A warning naming both regions, or `None` when they agree or either is unset
+pub fn region_mismatch(configured: Option<&str>, resolved: Option<&str>) -> Option<String> {
+    match (configured, resolved) {
+        (Some(configured), Some(resolved)) if configured != resolved => Some(format!(
+            "aws_region is {} but the AWS environment/profile resolves {}; using {}",
+            configured, resolved, configured
+        )),
+        _ => None,
+    }
+}
+
+/// Converts an SDK error, singling out expired pagination tokens and
+/// classifying everything else as an [`AwsError`]
+fn classify_error<E, R>(error: SdkError<E, R>) -> ClientError
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+    R: fmt::Debug + Send + Sync + 'static,
+{
+    if is_expired_token(error.code(), error.message()) {
+        let message = error.message().unwrap_or("no details").to_string();
+        return Box::new(ExpiredTokenError(message));
+    }
+    let unreachable = matches!(
+        error,
+        SdkError::DispatchFailure(_) | SdkError::TimeoutError(_)
+    );
+    let kind = error_kind(error.code(), unreachable);
+    Box::new(kind(Box::new(error)))
+}
+
+/// Picks the [`AwsError`] variant for an AWS error code
+///
+/// # Arguments
+///
+/// * `code` - AWS error code, if the service responded with one
+/// * `unreachable` - Whether the request never got a response
+fn error_kind(code: Option<&str>, unreachable: bool) -> fn(ClientError) -> AwsError {
+    match code {
+        Some(
+            "AccessDenied"
+            | "AccessDeniedException"
+            | "UnrecognizedClientException"
+            | "InvalidClientTokenId"
+            | "InvalidSignatureException"
+            | "SignatureDoesNotMatch"
+            | "MissingAuthenticationToken"
+            | "ExpiredToken"
+            | "ExpiredTokenException",
+        ) => AwsError::Auth,
+        Some(
+            "Throttling"
+            | "ThrottlingException"
+            | "TooManyRequestsException"
+            | "RequestLimitExceeded",
+        ) => AwsError::Throttled,
+        _ if unreachable => AwsError::Network,
+        _ => AwsError::Other,
+    }
+}
+
+/// Checks whether an AWS error code and message describe an expired `NextToken`
+///
+/// Cloud Map reports this as a generic `InvalidInput` error, so the message is
+/// inspected as well as the dedicated codes other AWS services use.
+/// `ExpiredTokenException` is about expired session credentials, not a page
+/// token, so it is left to [`error_kind`] as an authentication failure.
+fn is_expired_token(code: Option<&str>, message: Option<&str>) -> bool {
+    let message = message.unwrap_or("").to_ascii_lowercase();
+    match code {
+        Some("ExpiredNextTokenException") => true,
+        Some("InvalidInput" | "InvalidNextTokenException") => {
+            message.contains("token") && message.contains("expired")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_expired_token() {
+        assert!(is_expired_token(Some("ExpiredNextTokenException"), None));
+        assert!(is_expired_token(
+            Some("InvalidInput"),
+            Some("The NextToken has expired")
+        ));
+        assert!(!is_expired_token(
+            Some("InvalidInput"),
+            Some("Invalid namespace ID")
+        ));
+        assert!(!is_expired_token(
+            Some("ThrottlingException"),
+            Some("token expired")
+        ));
+        assert!(!is_expired_token(
+            Some("ExpiredTokenException"),
+            Some("The security token included in the request is expired")
+        ));
+        assert!(!is_expired_token(None, None));
+    }
+
+    #[test]
+    fn test_build_client_requires_region() {
+        let sdk_config = SdkConfig::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .build();
+
+        let error = build_client(&sdk_config).unwrap_err();
+
+        assert!(error.to_string().contains("no AWS region configured"));
+    }
+
+    #[test]
+    fn test_build_client_requires_credentials() {
+        let sdk_config = SdkConfig::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .region(aws_config::Region::new("us-west-2"))
+            .build();
+
+        let error = build_client(&sdk_config).unwrap_err();
+
+        assert!(error.to_string().contains("no AWS credentials provider"));
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let kind = |code, unreachable| error_kind(code, unreachable)("failed".into());
+
+        assert!(matches!(
+            kind(Some("AccessDeniedException"), false),
+            AwsError::Auth(_)
+        ));
+        assert!(matches!(
+            kind(Some("UnrecognizedClientException"), false),
+            AwsError::Auth(_)
+        ));
+        assert!(matches!(
+            kind(Some("ExpiredTokenException"), false),
+            AwsError::Auth(_)
+        ));
+        assert!(matches!(
+            kind(Some("ThrottlingException"), false),
+            AwsError::Throttled(_)
+        ));
+        assert!(matches!(kind(None, true), AwsError::Network(_)));
+        assert!(matches!(
+            kind(Some("NamespaceNotFound"), false),
+            AwsError::Other(_)
+        ));
+        assert!(matches!(kind(None, false), AwsError::Other(_)));
+    }
+
+    /// Loads a configuration carrying the default time source and sleep,
+    /// which the assume-role provider needs, without resolving credentials
+    async fn test_sdk_config() -> SdkConfig {
+        aws_config::defaults(aws_config::BehaviorVersion::latest())
+            .region(aws_config::Region::new("us-west-2"))
+            .no_credentials()
+            .load()
+            .await
+    }
+
+    fn test_factory() -> AwsClientFactory {
+        AwsClientFactory::new(
+            Some("us-west-2".to_string()),
+            SharedAsyncSleep::new(crate::retry::TokioSleep),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_assume_role_provider_built_when_role_set() {
+        let factory = test_factory().with_assume_role(Some(AssumeRole {
+            role_arn: "arn:aws:iam::123456789012:role/cloudmap-reader".to_string(),
+            external_id: Some("sd-external".to_string()),
+        }));
+
+        let provider = factory.assume_role_provider(&test_sdk_config().await).await;
+
+        assert!(provider.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_assume_role_provider_absent_without_role() {
+        let provider = test_factory()
+            .assume_role_provider(&test_sdk_config().await)
+            .await;
+
+        assert!(provider.is_none());
+    }
+
+    #[test]
+    fn test_region_mismatch() {
+        assert_eq!(
+            region_mismatch(Some("us-west-2"), Some("us-east-1")),
+            Some(
+                "aws_region is us-west-2 but the AWS environment/profile resolves us-east-1; using us-west-2"
+                    .to_string()
+            )
+        );
+        assert_eq!(region_mismatch(Some("us-west-2"), Some("us-west-2")), None);
+        assert_eq!(region_mismatch(Some("us-west-2"), None), None);
+        assert_eq!(region_mismatch(None, Some("us-east-1")), None);
+    }
+
+    #[test]
+    fn test_expired_token_error_display() {
+        let error = ExpiredTokenError("The NextToken has expired".to_string());
+
+        assert_eq!(
+            error.to_string(),
+            "pagination token expired: The NextToken has expired"
+        );
+    }
+}
@@ -1,35 +1,291 @@
 //! # Configuration Management
 //!
-//! This module handles configuration loading from JSON files and environment variables.
+//! This module handles configuration loading from JSON, YAML or TOML files and environment
+//! variables.
 //! It supports a hierarchical configuration system where environment variables override
-//! JSON file settings, which in turn override default values.
+//! file settings, which in turn override default values.
 //!
 //! ## Configuration Sources (in order of precedence)
 //!
-//! 1. Environment variables (highest priority)
-//! 2. JSON configuration file (`config.json`)
-//! 3. Default values (lowest priority)
+//! 1. Command-line flags (highest priority), see `cli`
+//! 2. Environment variables
+//! 3. Configuration file: the `--config` flag or `CONFIG_FILE` if set,
+//!    otherwise the first of `config.json`, `config.yaml`, `config.yml` and
+//!    `config.toml` that exists; the others are ignored
+//! 4. Default values (lowest priority)
 //!
 //! ## Environment Variables
 //!
+//! - `CONFIG_FILE`: Configuration file to read instead of looking one up (optional)
 //! - `HOST`: Server bind address
 //! - `PORT`: Server port number
 //! - `AWS_REGION`: AWS region for Cloud Map operations
-//! - `CLOUDMAP_NAMESPACE`: Specific namespace to filter (optional)
+//! - `AWS_REGIONS`: Comma-separated AWS regions to discover together (optional)
+//! - `CLOUDMAP_NAMESPACE`: Comma-separated namespaces to filter (optional)
+//! - `REFRESH_INTERVAL`: Seconds between background discovery runs (optional)
 
+use crate::client::AssumeRole;
+use crate::discovery::{
+    DEFAULT_IP_ATTRIBUTE_NAMES, DiscoverHealthStatus, DiscoveryMode, GroupBy, ServiceArn,
+};
 use log::{info, warn};
-use serde::{Deserialize, Serialize};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+/// Configuration files looked up in the working directory, in order of
+/// precedence; only the first one found is read
+const CONFIG_FILES: &[&str] = &["config.json", "config.yaml", "config.yml", "config.toml"];
+
+/// Key fragments marking a value as secret in the `/config` dump
+const SENSITIVE_KEY_PARTS: &[&str] = &["token", "secret", "password", "credential"];
+
+/// Placeholder substituted for redacted values
+const REDACTED: &str = "[REDACTED]";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct Config {
     pub host: String,
     pub port: u16,
     pub aws_region: Option<String>,
-    /// Specific Cloud Map namespace to discover
-    /// If None, discovers all namespaces
-    /// Set via config file or CLOUDMAP_NAMESPACE environment variable
-    pub cloudmap_namespace: Option<String>,
+    /// AWS regions to discover, merged into one response (empty = only
+    /// `aws_region`); takes precedence over `aws_region`
+    pub aws_regions: Vec<String>,
+    /// Endpoint the Cloud Map client talks to instead of AWS, for testing
+    /// against LocalStack or a custom endpoint (None = the AWS endpoint)
+    pub aws_endpoint_url: Option<String>,
+    /// IAM role to assume for Cloud Map calls, e.g. to discover namespaces in
+    /// another account (None = use the default credentials directly)
+    pub assume_role_arn: Option<String>,
+    /// External ID required by the assumed role's trust policy
+    pub external_id: Option<String>,
+    /// Cloud Map namespaces to discover
+    /// If empty, discovers all namespaces
+    /// Set via config file (a name or a list of names) or the comma-separated
+    /// CLOUDMAP_NAMESPACE environment variable
+    #[serde(deserialize_with = "deserialize_namespaces")]
+    pub cloudmap_namespace: Vec<String>,
+    /// Regular expression namespace names must match, e.g. `^team-.*-prod$`
+    /// When `cloudmap_namespace` is also set, a namespace must match both
+    pub namespace_regex: Option<String>,
+    /// Cloud Map service ARNs to discover directly, skipping the namespace and
+    /// service listings (empty = discover by listing)
+    pub service_arns: Vec<String>,
+    /// Instance attributes holding per-protocol ports, keyed by protocol name
+    /// When set, each protocol is emitted as its own target group labeled
+    /// with `__meta_cloudmap_protocol`
+    pub protocol_port_attributes: HashMap<String, String>,
+    /// Labels added to every target group only when discovery did not already
+    /// produce a value for that key
+    pub fallback_labels: HashMap<String, String>,
+    /// Instance attribute `(key, value)` pair marking instances to leave out of
+    /// discovery, e.g. `["monitoring", "disabled"]`
+    pub exclude_instance_attribute: Option<(String, String)>,
+    /// Remove duplicate targets within a group and report how many were removed
+    /// in the `__meta_cloudmap_duplicate_ip_count` label
+    pub deduplicate_targets: bool,
+    /// Instance attribute listing comma-separated ports (e.g. `ports=9100,9200`);
+    /// each valid port becomes its own `IP:PORT` target
+    pub multi_port_attribute: Option<String>,
+    /// Port appended to instances registered without `AWS_INSTANCE_PORT`, e.g.
+    /// 9100 for node_exporter (None = emit the bare IP)
+    /// Set via config file or the DEFAULT_PORT environment variable
+    pub default_port: Option<u16>,
+    /// Port appended to instances registered without `AWS_INSTANCE_PORT`,
+    /// keyed by service name
+    /// Precedence: instance attribute > service override > default_port > bare IP
+    pub service_port_overrides: HashMap<String, u16>,
+    /// Instance attributes holding an address, in priority order: the first one
+    /// present on an instance wins
+    /// Set via config file or the comma-separated IP_ATTRIBUTE_NAMES
+    /// environment variable
+    pub ip_attribute_names: Vec<String>,
+    /// Log a prominent warning when `list_namespaces` returns nothing, which
+    /// usually means the wrong AWS account or region
+    pub warn_on_no_namespaces: bool,
+    /// Return an error response instead of `[]` when no namespaces are found
+    pub fail_on_no_namespaces: bool,
+    /// Whether instances without a `scrape` attribute are emitted. Set to false
+    /// to make scraping opt-in via `scrape=true` on instances
+    pub default_scrape_enabled: bool,
+    /// Look up instance health for services with health checks, skip instances
+    /// Cloud Map reports as `UNHEALTHY`, and label groups with
+    /// `__meta_cloudmap_health_status`
+    pub only_healthy: bool,
+    /// Drop a health-checked service when the fraction of its instances that
+    /// are `HEALTHY` falls below this value (0.0 - 1.0), labeling served groups
+    /// with `__meta_cloudmap_healthy_ratio`
+    pub min_healthy_ratio: Option<f32>,
+    /// Drop a health-checked service entirely when none of its instances left
+    /// after filtering is `HEALTHY`
+    pub drop_unhealthy_services: bool,
+    /// Raw health status values (e.g. `OK`, `DOWN`) mapped to `HEALTHY`,
+    /// `UNHEALTHY` or `UNKNOWN` before filtering and labeling
+    pub health_status_map: HashMap<String, String>,
+    /// Seconds to keep serving targets that disappeared from discovery, labeled
+    /// with `__meta_cloudmap_stale_since` (0 = disabled)
+    pub target_retention_secs: u64,
+    /// Label every target group with a stable hash of its targets and labels in
+    /// `__meta_cloudmap_group_hash`, for cheap change detection
+    pub group_hash_label: bool,
+    /// Label every target group with the sum of its instances'
+    /// `AWS_INSTANCE_WEIGHT` in `__meta_cloudmap_service_weight_total`
+    pub service_weight_total_label: bool,
+    /// Emit every address attribute of an instance (`AWS_INSTANCE_IPV4`, `IPv4`,
+    /// `ip`, `address`, `AWS_INSTANCE_IPV6`) as a target instead of only the
+    /// first match
+    pub collect_all_addresses: bool,
+    /// Maximum number of labels emitted per target group; excess non-meta labels
+    /// are dropped in sorted key order with a warning
+    pub max_labels_per_group: Option<usize>,
+    /// Instance attribute whose value becomes the `job` label of each target
+    /// group, falling back to the Cloud Map service name
+    pub job_label_attribute: Option<String>,
+    /// Instance attribute holding a service VIP (e.g. `AWS_ALIAS_DNS_NAME`); VIPs
+    /// are emitted as an extra group labeled `__meta_cloudmap_role=vip`
+    pub vip_attribute: Option<String>,
+    /// Instance attribute holding the ECS task ARN (e.g. `ECS_TASK_ARN`),
+    /// emitted as `__meta_cloudmap_ecs_task_arn` with `group_by: instance`
+    pub ecs_task_arn_attribute: Option<String>,
+    /// File the latest discovery result is persisted to and loaded from at
+    /// startup, served until the first refresh succeeds
+    pub snapshot_file: Option<String>,
+    /// Minimum seconds between two snapshot writes
+    pub snapshot_interval_secs: u64,
+    /// Path to a Rhai script run against every target group to drop or relabel it
+    pub transform_script: Option<String>,
+    /// Maximum retries for a single failed AWS call
+    pub max_retries: u32,
+    /// Delay before the first retry of a failed AWS call in milliseconds
+    pub retry_base_delay_ms: u64,
+    /// Retries allowed across all discovery runs per budget window, so the
+    /// service doesn't amplify an AWS incident
+    pub retry_budget: u32,
+    /// Length of the retry budget window in seconds
+    pub retry_budget_window_secs: u64,
+    /// Timeout for each AWS operation including retries, in milliseconds, used
+    /// when no per-operation timeout is set (None = no timeout)
+    pub aws_call_timeout_ms: Option<u64>,
+    /// Timeout for `list_namespaces` in milliseconds
+    pub list_namespaces_timeout_ms: Option<u64>,
+    /// Timeout for each `list_services` call in milliseconds
+    pub list_services_timeout_ms: Option<u64>,
+    /// Timeout for each `list_instances` call in milliseconds
+    pub list_instances_timeout_ms: Option<u64>,
+    /// Times a listing restarts from the first page when its pagination token
+    /// expires mid-crawl, before discovery fails
+    pub pagination_restarts: u32,
+    /// Times `list_instances` is called again while a service returns no
+    /// instances, to ride out eventual consistency right after registration
+    /// (0 = never)
+    pub retry_empty_instances: u32,
+    /// Delay in milliseconds before each relisting of an empty service
+    pub retry_empty_instances_delay_ms: u64,
+    /// Crawl duration in milliseconds above which `discover_targets` logs a
+    /// warning and counts a slow crawl, without failing
+    pub max_crawl_duration_warn_ms: Option<u64>,
+    /// Seconds after which a whole discovery run is abandoned, cancelling its
+    /// in-flight AWS calls; `/cloudmap_sd` then returns a 504 (None = no limit)
+    pub discovery_timeout_seconds: Option<u64>,
+    /// Maximum number of discoveries requests may run at once; requests
+    /// beyond it are answered with a 429 right away (None = no limit)
+    pub max_concurrent_discoveries: Option<usize>,
+    /// How instances are grouped: `service` (one group per service, the
+    /// default) or `instance` (one group per instance)
+    pub group_by: GroupBy,
+    /// How each service's instances are fetched: `list` (`ListInstances`, the
+    /// default) or `discover` (`DiscoverInstances`, which also returns health)
+    pub discovery_mode: DiscoveryMode,
+    /// Health filter for `DiscoverInstances`: `healthy`, `unhealthy`, `all` or
+    /// `healthy_or_else_all` (None = Cloud Map's default)
+    pub discover_health_status: Option<DiscoverHealthStatus>,
+    /// Split each service's groups by the instances' `AVAILABILITY_ZONE`
+    /// attribute, labeled with `__meta_cloudmap_instance_az`, for AZ-aware
+    /// scrape sharding
+    pub group_by_az: bool,
+    /// Look up each service's tags (one extra API call per service) and emit
+    /// them as `__meta_cloudmap_tag_<key>` labels
+    pub include_service_tags: bool,
+    /// Label every target group with when its targets or labels last changed
+    /// (RFC 3339) in `__meta_cloudmap_group_last_changed`
+    pub group_last_changed_label: bool,
+    /// Label every target group with the sorted, comma-separated IDs of its
+    /// instances in `__meta_cloudmap_instance_id`
+    pub instance_id_label: bool,
+    /// Label every target group with the discovery path that produced it in
+    /// `__meta_cloudmap_discovery_method`: `list` or `discover` per
+    /// `discovery_mode`, `service_arn` or `service_arn_discover` with
+    /// `service_arns`
+    pub discovery_method_label: bool,
+    /// Copy custom instance attributes into `__meta_cloudmap_custom_<key>`
+    /// labels (reserved `AWS_INSTANCE_*` attributes are skipped)
+    pub include_custom_attributes: bool,
+    /// Name custom attribute labels `__meta_cloudmap_<namespace>_attr_<key>`
+    /// instead, so the same key can mean different things per namespace
+    pub namespace_scoped_attr_labels: bool,
+    /// Label whose value orders the `/cloudmap_sd` target groups, such as
+    /// `__meta_cloudmap_service_name` (None = namespace, then service order)
+    pub sort_groups_by: Option<String>,
+    /// Seconds between background discovery runs; when set, `/cloudmap_sd`
+    /// serves the latest background result (0 = discover on each request)
+    pub refresh_interval_seconds: u64,
+    /// Seconds to serve cached discovery results before crawling Cloud Map
+    /// again (0 = crawl on every request)
+    pub cache_ttl_seconds: u64,
+    /// Consecutive failed discovery runs after which `/ready` reports not
+    /// ready again (0 = stay ready once discovery has succeeded)
+    pub ready_failure_threshold: u32,
+    /// Label every target group with the adapter's start time (RFC 3339) in
+    /// `__meta_cloudmap_adapter_started`
+    pub adapter_started_label: bool,
+    /// Content-Type sent with `/cloudmap_sd` responses (default: `application/json`)
+    pub response_content_type: Option<String>,
+    /// Leave namespaces without services out of `/topology` by default
+    /// (overridable per request with `?omit_empty=`)
+    pub topology_omit_empty_namespaces: bool,
+    /// Refresh interval advertised to Prometheus in the
+    /// `X-Prometheus-Refresh-Interval-Seconds` header of `/cloudmap_sd`
+    /// responses (None = no header)
+    pub refresh_interval_hint_seconds: Option<u64>,
+    /// Smallest `/cloudmap_sd` response body, in bytes, that is compressed
+    /// when the request accepts gzip or deflate
+    pub compression_min_bytes: usize,
+    /// On discovery failure, serve the last successful response with an
+    /// `X-Cloudmap-Warning` header, or a 503 when there is none
+    pub soft_fail: bool,
+    /// Bearer token required by `POST /refresh-credentials` (None = endpoint
+    /// disabled)
+    pub refresh_credentials_token: Option<String>,
+    /// Bearer token required by `/cloudmap_sd` and `/topology` (None = open)
+    pub auth_token: Option<String>,
+    /// Browser origins allowed to fetch `/cloudmap_sd` cross-origin, such as
+    /// `https://dashboard.example.com`, or `*` for any (empty = CORS disabled)
+    pub cors_allowed_origins: Vec<String>,
+    /// Start even when the AWS client can't be built (no region or
+    /// credentials), e.g. to serve a snapshot; discovery requests then fail
+    pub offline_mode: bool,
+    /// Serve the adapter's own metrics at `GET /metrics`
+    pub metrics_enabled: bool,
+    /// PEM certificate chain to serve HTTPS with; requires `tls_key_path`
+    pub tls_cert_path: Option<String>,
+    /// PEM private key to serve HTTPS with; requires `tls_cert_path`
+    pub tls_key_path: Option<String>,
+    /// Unix domain socket to serve on instead of `host` and `port`
+    /// (None = TCP); the socket file is removed on shutdown
+    pub unix_socket: Option<String>,
+    /// Environment overrides that couldn't be parsed, or an explicit config
+    /// file that couldn't be loaded, reported by `validate`
+    #[serde(skip)]
+    pub load_errors: Vec<String>,
+    /// Number of times to retry binding the listener before giving up
+    pub bind_retries: u32,
+    /// Delay before the first bind retry in milliseconds (doubled on each retry)
+    pub bind_retry_delay_ms: u64,
 }
 
 impl Default for Config {
@@ -38,65 +294,325 @@ impl Default for Config {
             host: "0.0.0.0".to_string(),
             port: 3030,
             aws_region: None,
-            cloudmap_namespace: None,
+            aws_regions: Vec::new(),
+            aws_endpoint_url: None,
+            assume_role_arn: None,
+            external_id: None,
+            cloudmap_namespace: Vec::new(),
+            namespace_regex: None,
+            service_arns: Vec::new(),
+            protocol_port_attributes: HashMap::new(),
+            fallback_labels: HashMap::new(),
+            exclude_instance_attribute: None,
+            deduplicate_targets: false,
+            multi_port_attribute: None,
+            default_port: None,
+            service_port_overrides: HashMap::new(),
+            ip_attribute_names: DEFAULT_IP_ATTRIBUTE_NAMES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            warn_on_no_namespaces: true,
+            fail_on_no_namespaces: false,
+            default_scrape_enabled: true,
+            only_healthy: false,
+            min_healthy_ratio: None,
+            drop_unhealthy_services: false,
+            health_status_map: HashMap::new(),
+            target_retention_secs: 0,
+            group_hash_label: false,
+            service_weight_total_label: false,
+            collect_all_addresses: false,
+            max_labels_per_group: None,
+            job_label_attribute: None,
+            vip_attribute: None,
+            ecs_task_arn_attribute: None,
+            snapshot_file: None,
+            snapshot_interval_secs: 60,
+            transform_script: None,
+            max_retries: 2,
+            retry_base_delay_ms: 200,
+            retry_budget: 10,
+            retry_budget_window_secs: 60,
+            aws_call_timeout_ms: None,
+            list_namespaces_timeout_ms: None,
+            list_services_timeout_ms: None,
+            list_instances_timeout_ms: None,
+            pagination_restarts: 2,
+            retry_empty_instances: 0,
+            retry_empty_instances_delay_ms: 500,
+            max_crawl_duration_warn_ms: None,
+            discovery_timeout_seconds: None,
+            max_concurrent_discoveries: None,
+            group_by: GroupBy::Service,
+            discovery_mode: DiscoveryMode::List,
+            discover_health_status: None,
+            group_by_az: false,
+            include_service_tags: false,
+            group_last_changed_label: false,
+            instance_id_label: false,
+            discovery_method_label: false,
+            include_custom_attributes: false,
+            namespace_scoped_attr_labels: false,
+            sort_groups_by: None,
+            refresh_interval_seconds: 0,
+            cache_ttl_seconds: 0,
+            ready_failure_threshold: 3,
+            adapter_started_label: false,
+            response_content_type: None,
+            topology_omit_empty_namespaces: false,
+            refresh_interval_hint_seconds: None,
+            compression_min_bytes: 1024,
+            soft_fail: false,
+            refresh_credentials_token: None,
+            auth_token: None,
+            cors_allowed_origins: Vec::new(),
+            offline_mode: false,
+            metrics_enabled: true,
+            tls_cert_path: None,
+            tls_key_path: None,
+            unix_socket: None,
+            load_errors: Vec::new(),
+            bind_retries: 5,
+            bind_retry_delay_ms: 500,
         }
     }
 }
 
 impl Config {
-    pub fn load() -> Self {
+    /// Loads the configuration file and applies the environment overrides
+    ///
+    /// # Arguments
+    ///
+    /// * `config_file` - Explicit config file from the command line; takes
+    ///   precedence over `CONFIG_FILE`, and without either the file is looked
+    ///   up in the working directory
+    pub fn load(config_file: Option<PathBuf>) -> Self {
         // Try to read config from file
-        let mut config = match fs::read_to_string("config.json") {
-            Ok(content) => match serde_json::from_str::<Config>(&content) {
-                Ok(config) => {
-                    info!("📄 Loaded config from config.json");
-                    config
-                }
-                Err(e) => {
-                    warn!("⚠️  Failed to parse config.json: {}, using defaults", e);
-                    Config::default()
-                }
-            },
-            Err(_) => {
-                info!("📄 No config.json found, using defaults");
-                Config::default()
-            }
+        let config_file =
+            config_file.or_else(|| std::env::var_os("CONFIG_FILE").map(PathBuf::from));
+        let mut config = match config_file {
+            Some(path) => Self::load_explicit(&path),
+            None => Self::load_file(Path::new(".")),
         };
 
         // Override with environment variables if present
-        if let Ok(host) = std::env::var("HOST") {
+        config.apply_env(|name| std::env::var(name).ok());
+        config
+    }
+
+    /// Applies the environment variable overrides on top of the file settings
+    ///
+    /// # Arguments
+    ///
+    /// * `var` - Looks up an environment variable by name
+    pub(crate) fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) {
+        if let Some(host) = var("HOST") {
             info!("🌍 HOST environment variable found, overriding config");
-            config.host = host;
+            self.host = host;
         }
 
-        if let Ok(port_str) = std::env::var("PORT") {
+        if let Some(port_str) = var("PORT") {
             if let Ok(port) = port_str.parse::<u16>() {
                 info!("🔌 PORT environment variable found, overriding config");
-                config.port = port;
+                self.port = port;
             } else {
-                warn!("⚠️  Invalid PORT environment variable: {}", port_str);
+                self.load_errors
+                    .push(format!("Invalid PORT environment variable: '{}'", port_str));
             }
         }
 
-        if let Ok(region) = std::env::var("AWS_REGION") {
+        if let Some(region) = var("AWS_REGION") {
             info!("🌍 AWS_REGION environment variable found, overriding config");
-            config.aws_region = Some(region);
+            self.aws_region = Some(region);
         }
 
-        if let Ok(namespace) = std::env::var("CLOUDMAP_NAMESPACE") {
+        if let Some(regions) = var("AWS_REGIONS") {
+            info!("🌍 AWS_REGIONS environment variable found, overriding config");
+            self.aws_regions = parse_list(&regions);
+        }
+
+        if let Some(namespace) = var("CLOUDMAP_NAMESPACE") {
             info!("🗂️  CLOUDMAP_NAMESPACE environment variable found, overriding config");
-            config.cloudmap_namespace = Some(namespace);
+            self.cloudmap_namespace = parse_list(&namespace);
         }
 
-        config
+        if let Some(port_str) = var("DEFAULT_PORT") {
+            match port_str.parse::<u16>() {
+                Ok(port) if port > 0 => {
+                    info!("🔌 DEFAULT_PORT environment variable found, overriding config");
+                    self.default_port = Some(port);
+                }
+                _ => self.load_errors.push(format!(
+                    "Invalid DEFAULT_PORT environment variable: '{}'",
+                    port_str
+                )),
+            }
+        }
+
+        if let Some(names) = var("IP_ATTRIBUTE_NAMES") {
+            info!("🏷️  IP_ATTRIBUTE_NAMES environment variable found, overriding config");
+            self.ip_attribute_names = parse_list(&names);
+        }
+
+        if let Some(interval_str) = var("REFRESH_INTERVAL") {
+            if let Ok(interval) = interval_str.parse::<u64>() {
+                info!("🔄 REFRESH_INTERVAL environment variable found, overriding config");
+                self.refresh_interval_seconds = interval;
+            } else {
+                self.load_errors.push(format!(
+                    "Invalid REFRESH_INTERVAL environment variable: '{}'",
+                    interval_str
+                ));
+            }
+        }
+    }
+
+    /// Reads a config file the user pointed at explicitly
+    ///
+    /// Unlike a file looked up in the working directory, a missing or broken
+    /// explicit file is recorded in `load_errors` so `validate` stops the
+    /// service instead of it running on defaults.
+    fn load_explicit(path: &Path) -> Self {
+        match Self::read_file(path) {
+            Ok(config) => config,
+            Err(e) => Config {
+                load_errors: vec![e],
+                ..Config::default()
+            },
+        }
     }
 
-    pub fn parse_host(&self) -> Result<[u8; 4], String> {
+    /// Reads the first of `CONFIG_FILES` found in `dir`
+    ///
+    /// Without a file, or when it can't be parsed, the defaults are used.
+    fn load_file(dir: &Path) -> Self {
+        let mut found = CONFIG_FILES
+            .iter()
+            .map(|name| dir.join(name))
+            .filter(|path| path.is_file());
+        let Some(path) = found.next() else {
+            info!(
+                "📄 No config file found ({}), using defaults",
+                CONFIG_FILES.join(", ")
+            );
+            return Config::default();
+        };
+        for ignored in found {
+            warn!(
+                "⚠️  Ignoring {}, {} takes precedence",
+                ignored.display(),
+                path.display()
+            );
+        }
+
+        match Self::read_file(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("⚠️  {}, using defaults", e);
+                Config::default()
+            }
+        }
+    }
+
+    /// Reads and parses one config file
+    ///
+    /// Files ending in `.yaml` or `.yml` are parsed as YAML, `.toml` as TOML
+    /// and others as JSON.
+    fn read_file(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let parsed = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str::<Config>(&content).map_err(|e| e.to_string())
+            }
+            Some("toml") => toml::from_str::<Config>(&content).map_err(|e| e.to_string()),
+            _ => serde_json::from_str::<Config>(&content).map_err(|e| e.to_string()),
+        };
+        let config = parsed.map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        info!("📄 Loaded config from {}", path.display());
+        Ok(config)
+    }
+
+    /// Checks for settings the service can't run with
+    ///
+    /// Covers unparseable environment overrides, incomplete or contradictory
+    /// combinations, and every value a `parse_*` method would reject, so a
+    /// broken configuration stops the service at startup instead of leaving it
+    /// running in a degraded state.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(error) = self.load_errors.first() {
+            return Err(error.clone());
+        }
+        if self.unix_socket.is_none() {
+            if self.port == 0 {
+                return Err("port must be between 1 and 65535".to_string());
+            }
+            self.parse_host()
+                .map_err(|e| format!("Invalid host '{}': {}", self.host, e))?;
+        }
+        if self.ip_attribute_names.is_empty() {
+            return Err(
+                "ip_attribute_names is empty, so no instance would have an address".to_string(),
+            );
+        }
+        if let Some(region) = &self.aws_region
+            && !is_valid_region(region)
+        {
+            return Err(format!("Invalid aws_region '{}'", region));
+        }
+        if self.discovery_timeout_seconds == Some(0) {
+            return Err("discovery_timeout_seconds must be at least 1".to_string());
+        }
+        if self.max_concurrent_discoveries == Some(0) {
+            return Err("max_concurrent_discoveries must be at least 1".to_string());
+        }
+        self.parse_aws_regions()?;
+        self.parse_assume_role()?;
+        self.parse_tls()?;
+        self.parse_sort_groups_by()?;
+        self.parse_namespace_regex()?;
+        self.parse_service_arns()?;
+        self.parse_response_content_type()?;
+        self.parse_cors_allowed_origins()?;
+        Ok(())
+    }
+
+    /// Returns the effective configuration as JSON with secrets redacted
+    ///
+    /// Any value whose key contains a sensitive fragment (token, secret,
+    /// password, credential) is replaced, at any nesting depth, so new secret
+    /// fields are covered without updating this method.
+    pub fn redacted(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or(Value::Null);
+        redact_secrets(&mut value);
+        value
+    }
+
+    /// Parses `host` as the IP address to bind to
+    ///
+    /// Accepts IPv4 literals such as `0.0.0.0` and IPv6 literals such as `::`
+    /// or `::1`, optionally in brackets (`[::1]`).
+    pub fn parse_host(&self) -> Result<IpAddr, String> {
+        if self.host.contains(':') {
+            let literal = self
+                .host
+                .strip_prefix('[')
+                .and_then(|host| host.strip_suffix(']'))
+                .unwrap_or(&self.host);
+            return literal.parse::<Ipv6Addr>().map(IpAddr::V6).map_err(|_| {
+                format!(
+                    "Invalid IPv6 address: '{}' is not a valid IPv6 literal",
+                    self.host
+                )
+            });
+        }
+
         let parts: Vec<&str> = self.host.split('.').collect();
 
         if parts.len() != 4 {
             return Err(format!(
-                "Invalid IP format: expected 4 parts, got {}",
+                "Invalid IP format: expected 4 IPv4 parts or an IPv6 address, got {} parts",
                 parts.len()
             ));
         }
@@ -109,7 +625,263 @@ impl Config {
             }
         }
 
-        Ok(result)
+        Ok(IpAddr::from(result))
+    }
+
+    /// Returns the validated `sort_groups_by` label name
+    ///
+    /// The name must be a valid Prometheus label name
+    /// (`[a-zA-Z_][a-zA-Z0-9_]*`).
+    pub fn parse_sort_groups_by(&self) -> Result<Option<String>, String> {
+        match &self.sort_groups_by {
+            Some(label) if !is_valid_label_name(label) => Err(format!(
+                "Invalid sort_groups_by: '{}' is not a valid label name",
+                label
+            )),
+            label => Ok(label.clone()),
+        }
+    }
+
+    /// Returns the compiled `namespace_regex`
+    pub fn parse_namespace_regex(&self) -> Result<Option<Regex>, String> {
+        self.namespace_regex
+            .as_deref()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|e| format!("Invalid namespace_regex '{}': {}", pattern, e))
+            })
+            .transpose()
+    }
+
+    /// Returns the parsed `service_arns`
+    ///
+    /// Every entry must be a Cloud Map service ARN,
+    /// `arn:<partition>:servicediscovery:<region>:<account>:service/<service-id>`.
+    pub fn parse_service_arns(&self) -> Result<Vec<ServiceArn>, String> {
+        self.service_arns
+            .iter()
+            .map(|arn| ServiceArn::parse(arn))
+            .collect()
+    }
+
+    /// Returns the validated regions to discover
+    ///
+    /// Region names must be non-empty, made of lowercase letters, digits and
+    /// dashes (e.g. `us-east-1`), and listed once.
+    pub fn parse_aws_regions(&self) -> Result<Vec<String>, String> {
+        let mut regions: Vec<String> = Vec::with_capacity(self.aws_regions.len());
+        for region in &self.aws_regions {
+            if !is_valid_region(region) {
+                return Err(format!("Invalid region '{}' in aws_regions", region));
+            }
+            if regions.contains(region) {
+                return Err(format!(
+                    "Region '{}' is listed twice in aws_regions",
+                    region
+                ));
+            }
+            regions.push(region.clone());
+        }
+        Ok(regions)
+    }
+
+    /// Returns the validated role to assume for Cloud Map calls
+    ///
+    /// The ARN must name an IAM role (`arn:<partition>:iam::<account>:role/<name>`),
+    /// and `external_id` is only accepted together with it.
+    pub fn parse_assume_role(&self) -> Result<Option<AssumeRole>, String> {
+        let Some(role_arn) = &self.assume_role_arn else {
+            return match self.external_id {
+                Some(_) => Err("external_id is set but assume_role_arn is not".to_string()),
+                None => Ok(None),
+            };
+        };
+        let parts: Vec<&str> = role_arn.splitn(6, ':').collect();
+        let valid = matches!(
+            parts.as_slice(),
+            ["arn", partition, "iam", "", account, resource]
+                if !partition.is_empty()
+                    && account.len() == 12
+                    && account.chars().all(|c| c.is_ascii_digit())
+                    && resource.strip_prefix("role/").is_some_and(|name| !name.is_empty())
+        );
+        if !valid {
+            return Err(format!(
+                "Invalid assume_role_arn '{}': expected arn:<partition>:iam::<account>:role/<name>",
+                role_arn
+            ));
+        }
+        Ok(Some(AssumeRole {
+            role_arn: role_arn.clone(),
+            external_id: self.external_id.clone(),
+        }))
+    }
+
+    /// Returns the validated TLS certificate and key paths
+    ///
+    /// Both paths must be set for HTTPS, and both files must exist; with
+    /// neither set the server uses plain HTTP.
+    pub fn parse_tls(&self) -> Result<Option<(PathBuf, PathBuf)>, String> {
+        let (cert, key) = match (&self.tls_cert_path, &self.tls_key_path) {
+            (None, None) => return Ok(None),
+            (Some(cert), Some(key)) => (PathBuf::from(cert), PathBuf::from(key)),
+            (Some(_), None) => {
+                return Err(
+                    "tls_cert_path is set but tls_key_path is not; set both for HTTPS".to_string(),
+                );
+            }
+            (None, Some(_)) => {
+                return Err(
+                    "tls_key_path is set but tls_cert_path is not; set both for HTTPS".to_string(),
+                );
+            }
+        };
+        for (option, path) in [("tls_cert_path", &cert), ("tls_key_path", &key)] {
+            if !path.is_file() {
+                return Err(format!(
+                    "{} '{}' is not a readable file",
+                    option,
+                    path.display()
+                ));
+            }
+        }
+        Ok(Some((cert, key)))
+    }
+
+    /// Returns the validated origins allowed to make CORS requests
+    ///
+    /// Each entry must be `*` or a `scheme://host[:port]` origin with an
+    /// `http` or `https` scheme and no path; a trailing slash is dropped.
+    pub fn parse_cors_allowed_origins(&self) -> Result<Vec<String>, String> {
+        let mut origins = Vec::with_capacity(self.cors_allowed_origins.len());
+        for origin in &self.cors_allowed_origins {
+            let trimmed = origin.trim().trim_end_matches('/');
+            let valid = trimmed == "*"
+                || ["http://", "https://"].iter().any(|scheme| {
+                    trimmed.strip_prefix(scheme).is_some_and(|authority| {
+                        !authority.is_empty()
+                            && authority
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c))
+                    })
+                });
+            if !valid {
+                return Err(format!(
+                    "Invalid origin '{}' in cors_allowed_origins: expected scheme://host[:port] or *",
+                    origin
+                ));
+            }
+            origins.push(trimmed.to_string());
+        }
+        Ok(origins)
+    }
+
+    /// Returns the validated Content-Type for `/cloudmap_sd` responses
+    ///
+    /// An override must be a `type/subtype` media type (parameters such as
+    /// `; charset=utf-8` are allowed) made of printable ASCII characters.
+    pub fn parse_response_content_type(&self) -> Result<String, String> {
+        let content_type = match &self.response_content_type {
+            Some(content_type) => content_type,
+            None => return Ok("application/json".to_string()),
+        };
+
+        if !content_type
+            .chars()
+            .all(|c| c == ' ' || c.is_ascii_graphic())
+        {
+            return Err(format!(
+                "Invalid media type: '{}' contains non-printable characters",
+                content_type
+            ));
+        }
+
+        let is_token = |part: &str| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+        };
+        let essence = content_type.split(';').next().unwrap_or("").trim();
+        match essence.split_once('/') {
+            Some((kind, subtype)) if is_token(kind) && is_token(subtype) => {
+                Ok(content_type.clone())
+            }
+            _ => Err(format!(
+                "Invalid media type: '{}' is not of the form type/subtype",
+                content_type
+            )),
+        }
+    }
+}
+
+/// Checks that a region name is made of lowercase letters, digits and dashes,
+/// like `us-east-1`
+fn is_valid_region(region: &str) -> bool {
+    !region.is_empty()
+        && region
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Splits a comma-separated list, ignoring blank entries
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|namespace| !namespace.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Deserializes `cloudmap_namespace` from null, a single name or a list of names
+///
+/// A single name keeps configuration files written before namespace lists
+/// were supported working.
+fn deserialize_namespaces<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Namespaces {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Option::<Namespaces>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(Namespaces::One(namespace)) => vec![namespace],
+        Some(Namespaces::Many(namespaces)) => namespaces,
+    })
+}
+
+/// Checks whether a name is a valid Prometheus label name
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Replaces sensitive values in a JSON tree with a placeholder
+fn redact_secrets(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if SENSITIVE_KEY_PARTS.iter().any(|part| key.contains(part)) {
+                    if !value.is_null() {
+                        *value = Value::String(REDACTED.to_string());
+                    }
+                } else {
+                    redact_secrets(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_secrets),
+        _ => {}
     }
 }
 
@@ -124,7 +896,46 @@ mod tests {
         assert_eq!(config.host, "0.0.0.0");
         assert_eq!(config.port, 3030);
         assert_eq!(config.aws_region, None);
-        assert_eq!(config.cloudmap_namespace, None);
+        assert_eq!(config.aws_endpoint_url, None);
+        assert!(config.aws_regions.is_empty());
+        assert_eq!(config.assume_role_arn, None);
+        assert!(config.cloudmap_namespace.is_empty());
+        assert!(config.protocol_port_attributes.is_empty());
+        assert!(config.fallback_labels.is_empty());
+        assert_eq!(config.exclude_instance_attribute, None);
+        assert!(!config.deduplicate_targets);
+        assert!(config.warn_on_no_namespaces);
+        assert!(!config.fail_on_no_namespaces);
+        assert!(config.default_scrape_enabled);
+        assert_eq!(config.transform_script, None);
+        assert_eq!(config.max_retries, 2);
+        assert_eq!(config.retry_budget, 10);
+        assert_eq!(config.bind_retries, 5);
+        assert_eq!(config.bind_retry_delay_ms, 500);
+    }
+
+    #[test]
+    fn test_config_missing_fields_use_defaults() {
+        let json = r#"{"host": "10.0.0.1", "protocol_port_attributes": {"grpc": "GRPC_PORT"}}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+
+        assert_eq!(config.host, "10.0.0.1");
+        assert_eq!(config.port, 3030);
+        assert_eq!(
+            config.protocol_port_attributes.get("grpc"),
+            Some(&"GRPC_PORT".to_string())
+        );
+    }
+
+    #[test]
+    fn test_exclude_instance_attribute_from_json() {
+        let json = r#"{"exclude_instance_attribute": ["monitoring", "disabled"]}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+
+        assert_eq!(
+            config.exclude_instance_attribute,
+            Some(("monitoring".to_string(), "disabled".to_string()))
+        );
     }
 
     #[test]
@@ -133,11 +944,12 @@ mod tests {
             host: "192.168.1.1".to_string(),
             port: 8080,
             aws_region: None,
-            cloudmap_namespace: None,
+            cloudmap_namespace: Vec::new(),
+            ..Config::default()
         };
 
         let result = config.parse_host().unwrap();
-        assert_eq!(result, [192, 168, 1, 1]);
+        assert_eq!(result, IpAddr::from([192, 168, 1, 1]));
     }
 
     #[test]
@@ -146,11 +958,12 @@ mod tests {
             host: "127.0.0.1".to_string(),
             port: 3000,
             aws_region: None,
-            cloudmap_namespace: None,
+            cloudmap_namespace: Vec::new(),
+            ..Config::default()
         };
 
         let result = config.parse_host().unwrap();
-        assert_eq!(result, [127, 0, 0, 1]);
+        assert_eq!(result, IpAddr::from([127, 0, 0, 1]));
     }
 
     #[test]
@@ -159,11 +972,12 @@ mod tests {
             host: "0.0.0.0".to_string(),
             port: 3030,
             aws_region: None,
-            cloudmap_namespace: None,
+            cloudmap_namespace: Vec::new(),
+            ..Config::default()
         };
 
         let result = config.parse_host().unwrap();
-        assert_eq!(result, [0, 0, 0, 0]);
+        assert_eq!(result, IpAddr::from([0, 0, 0, 0]));
     }
 
     #[test]
@@ -172,7 +986,8 @@ mod tests {
             host: "192.168.1".to_string(), // Missing fourth octet
             port: 3030,
             aws_region: None,
-            cloudmap_namespace: None,
+            cloudmap_namespace: Vec::new(),
+            ..Config::default()
         };
 
         let result = config.parse_host();
@@ -186,7 +1001,8 @@ mod tests {
             host: "192.168.1.256".to_string(), // 256 is out of range for u8
             port: 3030,
             aws_region: None,
-            cloudmap_namespace: None,
+            cloudmap_namespace: Vec::new(),
+            ..Config::default()
         };
 
         let result = config.parse_host();
@@ -200,7 +1016,8 @@ mod tests {
             host: "192.168.1.abc".to_string(),
             port: 3030,
             aws_region: None,
-            cloudmap_namespace: None,
+            cloudmap_namespace: Vec::new(),
+            ..Config::default()
         };
 
         let result = config.parse_host();
@@ -208,13 +1025,604 @@ mod tests {
         assert!(result.unwrap_err().contains("Invalid IP part"));
     }
 
+    #[test]
+    fn test_parse_host_ipv6_loopback() {
+        let config = Config {
+            host: "::1".to_string(),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.parse_host().unwrap(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+    }
+
+    #[test]
+    fn test_parse_host_ipv6_all_interfaces() {
+        let config = Config {
+            host: "::".to_string(),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.parse_host().unwrap(),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        );
+    }
+
+    #[test]
+    fn test_parse_host_bracketed_ipv6() {
+        let config = Config {
+            host: "[::1]".to_string(),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.parse_host().unwrap(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+    }
+
+    #[test]
+    fn test_parse_host_invalid_ipv6() {
+        let config = Config {
+            host: "::g1".to_string(),
+            ..Config::default()
+        };
+
+        let result = config.parse_host();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Invalid IPv6 address"));
+    }
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(parse_list("production"), vec!["production"]);
+        assert_eq!(
+            parse_list("production, staging,,"),
+            vec!["production", "staging"]
+        );
+        assert!(parse_list("").is_empty());
+    }
+
+    #[test]
+    fn test_cloudmap_namespace_accepts_name_or_list() {
+        let parse = |json: &str| {
+            serde_json::from_str::<Config>(json)
+                .unwrap()
+                .cloudmap_namespace
+        };
+
+        assert_eq!(
+            parse(r#"{"cloudmap_namespace": "production"}"#),
+            vec!["production"]
+        );
+        assert_eq!(
+            parse(r#"{"cloudmap_namespace": ["production", "staging"]}"#),
+            vec!["production", "staging"]
+        );
+        assert!(parse(r#"{"cloudmap_namespace": null}"#).is_empty());
+        assert!(parse("{}").is_empty());
+    }
+
+    #[test]
+    fn test_parse_namespace_regex() {
+        let config = |pattern: Option<&str>| Config {
+            namespace_regex: pattern.map(str::to_string),
+            ..Config::default()
+        };
+
+        assert!(config(None).parse_namespace_regex().unwrap().is_none());
+        let regex = config(Some("^team-.*-prod$"))
+            .parse_namespace_regex()
+            .unwrap()
+            .unwrap();
+        assert!(regex.is_match("team-payments-prod"));
+        let error = config(Some("team-(")).parse_namespace_regex().unwrap_err();
+        assert!(error.contains("Invalid namespace_regex"));
+    }
+
+    #[test]
+    fn test_validate_accepts_defaults() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_reports_load_errors() {
+        let config = Config {
+            load_errors: vec!["Invalid PORT environment variable: 'http'".to_string()],
+            ..Config::default()
+        };
+
+        assert!(config.validate().unwrap_err().contains("PORT"));
+    }
+
+    #[test]
+    fn test_validate_rejects_port_zero() {
+        let tcp = Config {
+            port: 0,
+            ..Config::default()
+        };
+        let unix = Config {
+            port: 0,
+            unix_socket: Some("/run/cloudmap-sd/sd.sock".to_string()),
+            ..Config::default()
+        };
+
+        assert!(tcp.validate().unwrap_err().contains("port"));
+        assert_eq!(unix.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_host() {
+        let config = Config {
+            host: "localhost".to_string(),
+            ..Config::default()
+        };
+
+        assert!(config.validate().unwrap_err().contains("localhost"));
+    }
+
+    #[test]
+    fn test_validate_rejects_zero_discovery_timeout() {
+        let config = Config {
+            discovery_timeout_seconds: Some(0),
+            ..Config::default()
+        };
+
+        assert!(
+            config
+                .validate()
+                .unwrap_err()
+                .contains("discovery_timeout_seconds")
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_zero_concurrent_discoveries() {
+        let config = Config {
+            max_concurrent_discoveries: Some(0),
+            ..Config::default()
+        };
+
+        assert!(
+            config
+                .validate()
+                .unwrap_err()
+                .contains("max_concurrent_discoveries")
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_empty_ip_attribute_names() {
+        let config = Config {
+            ip_attribute_names: Vec::new(),
+            ..Config::default()
+        };
+
+        assert!(
+            config
+                .validate()
+                .unwrap_err()
+                .contains("ip_attribute_names")
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_region() {
+        let config = |region: &str| Config {
+            aws_region: Some(region.to_string()),
+            ..Config::default()
+        };
+
+        assert_eq!(config("eu-central-1").validate(), Ok(()));
+        assert!(
+            config("US East")
+                .validate()
+                .unwrap_err()
+                .contains("US East")
+        );
+        assert!(config("").validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_tls_key_without_cert() {
+        let config = Config {
+            tls_key_path: Some("/etc/cloudmap-sd/key.pem".to_string()),
+            ..Config::default()
+        };
+
+        assert!(config.validate().unwrap_err().contains("tls_cert_path"));
+    }
+
+    #[test]
+    fn test_validate_runs_parse_checks() {
+        let config = Config {
+            external_id: Some("sd-external".to_string()),
+            ..Config::default()
+        };
+
+        assert!(config.validate().unwrap_err().contains("assume_role_arn"));
+    }
+
+    #[test]
+    fn test_parse_aws_regions() {
+        let config = |regions: &[&str]| Config {
+            aws_regions: regions.iter().map(|region| region.to_string()).collect(),
+            ..Config::default()
+        };
+
+        assert_eq!(config(&[]).parse_aws_regions(), Ok(Vec::new()));
+        assert_eq!(
+            config(&["us-east-1", "eu-west-1"]).parse_aws_regions(),
+            Ok(vec!["us-east-1".to_string(), "eu-west-1".to_string()])
+        );
+        assert!(
+            config(&["us-east-1", "US-WEST-2"])
+                .parse_aws_regions()
+                .unwrap_err()
+                .contains("US-WEST-2")
+        );
+        assert!(config(&[""]).parse_aws_regions().is_err());
+        assert!(
+            config(&["us-east-1", "us-east-1"])
+                .parse_aws_regions()
+                .unwrap_err()
+                .contains("listed twice")
+        );
+    }
+
+    #[test]
+    fn test_parse_assume_role() {
+        let config = |arn: Option<&str>, external_id: Option<&str>| Config {
+            assume_role_arn: arn.map(str::to_string),
+            external_id: external_id.map(str::to_string),
+            ..Config::default()
+        };
+        let arn = "arn:aws:iam::123456789012:role/cloudmap-reader";
+
+        assert_eq!(config(None, None).parse_assume_role(), Ok(None));
+        assert_eq!(
+            config(Some(arn), Some("sd-external")).parse_assume_role(),
+            Ok(Some(AssumeRole {
+                role_arn: arn.to_string(),
+                external_id: Some("sd-external".to_string()),
+            }))
+        );
+        assert_eq!(
+            config(Some("arn:aws-cn:iam::123456789012:role/path/reader"), None)
+                .parse_assume_role()
+                .unwrap()
+                .unwrap()
+                .external_id,
+            None
+        );
+        for invalid in [
+            "cloudmap-reader",
+            "arn:aws:iam::123456789012:user/alice",
+            "arn:aws:iam::1234:role/cloudmap-reader",
+            "arn:aws:sts::123456789012:role/cloudmap-reader",
+            "arn:aws:iam::123456789012:role/",
+        ] {
+            let error = config(Some(invalid), None).parse_assume_role().unwrap_err();
+            assert!(error.contains(invalid), "{}", error);
+        }
+        assert!(
+            config(None, Some("sd-external"))
+                .parse_assume_role()
+                .unwrap_err()
+                .contains("assume_role_arn is not")
+        );
+    }
+
+    /// Creates an empty directory for config file tests
+    fn config_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cloudmap-sd-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_load_yaml_config_file() {
+        let dir = config_dir("yaml");
+        fs::write(
+            dir.join("config.yaml"),
+            "port: 9090\naws_region: eu-west-1\ncloudmap_namespace:\n  - production\n  - staging\ngroup_by: instance\n",
+        )
+        .unwrap();
+
+        let config = Config::load_file(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.port, 9090);
+        assert_eq!(config.aws_region, Some("eu-west-1".to_string()));
+        assert_eq!(config.cloudmap_namespace, vec!["production", "staging"]);
+        assert_eq!(config.group_by, GroupBy::Instance);
+        assert_eq!(config.host, "0.0.0.0");
+    }
+
+    #[test]
+    fn test_load_yml_config_file() {
+        let dir = config_dir("yml");
+        fs::write(dir.join("config.yml"), "cloudmap_namespace: production\n").unwrap();
+
+        let config = Config::load_file(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.cloudmap_namespace, vec!["production"]);
+    }
+
+    #[test]
+    fn test_json_config_file_takes_precedence_over_yaml() {
+        let dir = config_dir("both");
+        fs::write(dir.join("config.json"), r#"{"port": 8080}"#).unwrap();
+        fs::write(dir.join("config.yaml"), "port: 9090\n").unwrap();
+        fs::write(dir.join("config.yml"), "port: 7070\n").unwrap();
+
+        let json = Config::load_file(&dir);
+        fs::remove_file(dir.join("config.json")).unwrap();
+        let yaml = Config::load_file(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(json.port, 8080);
+        assert_eq!(yaml.port, 9090);
+    }
+
+    #[test]
+    fn test_toml_config_file_round_trip() {
+        let dir = config_dir("toml");
+        let written = Config {
+            port: 9090,
+            aws_regions: vec!["us-east-1".to_string(), "eu-west-1".to_string()],
+            cloudmap_namespace: vec!["production".to_string()],
+            group_by: GroupBy::Instance,
+            service_port_overrides: HashMap::from([("api".to_string(), 8080)]),
+            ..Config::default()
+        };
+        fs::write(dir.join("config.toml"), toml::to_string(&written).unwrap()).unwrap();
+
+        let config = Config::load_file(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.port, written.port);
+        assert_eq!(config.aws_regions, written.aws_regions);
+        assert_eq!(config.cloudmap_namespace, written.cloudmap_namespace);
+        assert_eq!(config.group_by, written.group_by);
+        assert_eq!(
+            config.service_port_overrides,
+            written.service_port_overrides
+        );
+        assert_eq!(config.aws_region, None);
+    }
+
+    #[test]
+    fn test_env_overrides_win_over_toml_config_file() {
+        let dir = config_dir("toml-env");
+        fs::write(
+            dir.join("config.toml"),
+            "port = 9090\ncloudmap_namespace = \"production\"\naws_region = \"eu-west-1\"\n",
+        )
+        .unwrap();
+        let env = HashMap::from([("PORT", "8080"), ("CLOUDMAP_NAMESPACE", "staging,canary")]);
+
+        let mut config = Config::load_file(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        config.apply_env(|name| env.get(name).map(|value| value.to_string()));
+
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.cloudmap_namespace, vec!["staging", "canary"]);
+        assert_eq!(config.aws_region, Some("eu-west-1".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_env_override_is_recorded() {
+        let mut config = Config::default();
+
+        config.apply_env(|name| (name == "PORT").then(|| "http".to_string()));
+
+        assert_eq!(config.port, 3030);
+        assert!(config.validate().unwrap_err().contains("'http'"));
+    }
+
+    #[test]
+    fn test_load_config_file_from_custom_path() {
+        let dir = config_dir("custom-path");
+        let path = dir.join("mounted").join("adapter.yaml");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "port: 9090\ncloudmap_namespace: production\n").unwrap();
+
+        let config = Config::load_explicit(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.port, 9090);
+        assert_eq!(config.cloudmap_namespace, vec!["production"]);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_missing_explicit_config_file_is_an_error() {
+        let dir = config_dir("custom-path-missing");
+        let path = dir.join("adapter.json");
+
+        let config = Config::load_explicit(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(config.validate().unwrap_err().contains("adapter.json"));
+    }
+
+    #[test]
+    fn test_missing_config_file_uses_defaults() {
+        let dir = config_dir("none");
+
+        let config = Config::load_file(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.port, 3030);
+    }
+
+    #[test]
+    fn test_parse_tls_requires_both_paths() {
+        let config = |cert: Option<&str>, key: Option<&str>| Config {
+            tls_cert_path: cert.map(str::to_string),
+            tls_key_path: key.map(str::to_string),
+            ..Config::default()
+        };
+        let cert =
+            std::env::temp_dir().join(format!("cloudmap-sd-{}-cert.pem", std::process::id()));
+        std::fs::write(&cert, "cert").unwrap();
+        let cert_path = cert.to_str().unwrap();
+
+        let plain = config(None, None).parse_tls();
+        let cert_only = config(Some(cert_path), None).parse_tls();
+        let key_only = config(None, Some(cert_path)).parse_tls();
+        let missing_key = config(Some(cert_path), Some("/nonexistent/key.pem")).parse_tls();
+        let both = config(Some(cert_path), Some(cert_path)).parse_tls();
+        std::fs::remove_file(&cert).unwrap();
+
+        assert_eq!(plain, Ok(None));
+        assert!(cert_only.unwrap_err().contains("tls_key_path is not"));
+        assert!(key_only.unwrap_err().contains("tls_cert_path is not"));
+        assert!(missing_key.unwrap_err().contains("/nonexistent/key.pem"));
+        assert_eq!(both, Ok(Some((cert.clone(), cert))));
+    }
+
+    #[test]
+    fn test_parse_service_arns() {
+        let config = |arns: &[&str]| Config {
+            service_arns: arns.iter().map(|arn| arn.to_string()).collect(),
+            ..Config::default()
+        };
+
+        assert_eq!(config(&[]).parse_service_arns(), Ok(Vec::new()));
+        assert_eq!(
+            config(&["arn:aws:servicediscovery:us-west-2:123456789012:service/srv-abc123"])
+                .parse_service_arns(),
+            Ok(vec![ServiceArn {
+                region: "us-west-2".to_string(),
+                account_id: "123456789012".to_string(),
+                service_id: "srv-abc123".to_string(),
+            }])
+        );
+        for invalid in [
+            "srv-abc123",
+            "arn:aws:servicediscovery:us-west-2:123456789012:namespace/ns-abc123",
+            "arn:aws:ecs:us-west-2:123456789012:service/srv-abc123",
+            "arn:aws:servicediscovery::123456789012:service/srv-abc123",
+            "arn:aws:servicediscovery:us-west-2:1234:service/srv-abc123",
+            "arn:aws:servicediscovery:us-west-2:123456789012:service/",
+        ] {
+            assert!(
+                config(&[invalid]).parse_service_arns().is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_sort_groups_by() {
+        let config = |label: Option<&str>| Config {
+            sort_groups_by: label.map(str::to_string),
+            ..Config::default()
+        };
+
+        assert_eq!(config(None).parse_sort_groups_by(), Ok(None));
+        assert_eq!(
+            config(Some("__meta_cloudmap_service_name")).parse_sort_groups_by(),
+            Ok(Some("__meta_cloudmap_service_name".to_string()))
+        );
+        assert!(config(Some("service-name")).parse_sort_groups_by().is_err());
+        assert!(config(Some("1st")).parse_sort_groups_by().is_err());
+        assert!(config(Some("")).parse_sort_groups_by().is_err());
+    }
+
+    #[test]
+    fn test_parse_response_content_type_default() {
+        let config = Config::default();
+
+        assert_eq!(
+            config.parse_response_content_type().unwrap(),
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn test_parse_response_content_type_override() {
+        let config = Config {
+            response_content_type: Some("application/json; charset=utf-8".to_string()),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.parse_response_content_type().unwrap(),
+            "application/json; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn test_parse_response_content_type_invalid() {
+        for invalid in ["json", "application/", "/json", "text/plain\n"] {
+            let config = Config {
+                response_content_type: Some(invalid.to_string()),
+                ..Config::default()
+            };
+
+            let result = config.parse_response_content_type();
+            assert!(result.is_err(), "expected '{}' to be rejected", invalid);
+            assert!(result.unwrap_err().contains("Invalid media type"));
+        }
+    }
+
+    #[test]
+    fn test_parse_cors_allowed_origins() {
+        let config = Config {
+            cors_allowed_origins: vec![
+                "https://dashboard.example.com/".to_string(),
+                "http://localhost:8080".to_string(),
+                "*".to_string(),
+            ],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.parse_cors_allowed_origins(),
+            Ok(vec![
+                "https://dashboard.example.com".to_string(),
+                "http://localhost:8080".to_string(),
+                "*".to_string(),
+            ])
+        );
+        assert_eq!(Config::default().parse_cors_allowed_origins(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_parse_cors_allowed_origins_invalid() {
+        for invalid in [
+            "dashboard.example.com",
+            "ftp://dashboard.example.com",
+            "https://",
+            "https://dashboard.example.com/app",
+        ] {
+            let config = Config {
+                cors_allowed_origins: vec![invalid.to_string()],
+                ..Config::default()
+            };
+
+            let result = config.parse_cors_allowed_origins();
+            assert!(result.is_err(), "expected '{}' to be rejected", invalid);
+        }
+    }
+
     #[test]
     fn test_config_clone() {
         let config = Config {
             host: "10.0.0.1".to_string(),
             port: 8080,
             aws_region: Some("us-east-1".to_string()),
-            cloudmap_namespace: Some("test-namespace".to_string()),
+            cloudmap_namespace: vec!["test-namespace".to_string()],
+            ..Config::default()
         };
 
         let cloned = config.clone();
@@ -230,7 +1638,8 @@ mod tests {
             host: "192.168.1.100".to_string(),
             port: 9090,
             aws_region: Some("eu-west-1".to_string()),
-            cloudmap_namespace: Some("production".to_string()),
+            cloudmap_namespace: vec!["production".to_string()],
+            ..Config::default()
         };
 
         let json = serde_json::to_string(&config).unwrap();
@@ -242,6 +1651,38 @@ mod tests {
         assert_eq!(config.cloudmap_namespace, deserialized.cloudmap_namespace);
     }
 
+    #[test]
+    fn test_redacted_config_hides_secrets() {
+        let config = Config {
+            fallback_labels: HashMap::from([("api_token".to_string(), "abc123".to_string())]),
+            ..Config::default()
+        };
+
+        let redacted = config.redacted();
+
+        assert_eq!(redacted["host"], "0.0.0.0");
+        assert_eq!(redacted["port"], 3030);
+        assert_eq!(redacted["fallback_labels"]["api_token"], REDACTED);
+    }
+
+    #[test]
+    fn test_redact_secrets_nested() {
+        let mut value = serde_json::json!({
+            "auth_token": "abc123",
+            "assume_role": {"arn": "arn:aws:iam::123:role/sd", "Secret_Access_Key": "xyz"},
+            "unset_password": null,
+            "regions": [{"session_token": "t"}]
+        });
+
+        redact_secrets(&mut value);
+
+        assert_eq!(value["auth_token"], REDACTED);
+        assert_eq!(value["assume_role"]["arn"], "arn:aws:iam::123:role/sd");
+        assert_eq!(value["assume_role"]["Secret_Access_Key"], REDACTED);
+        assert_eq!(value["unset_password"], Value::Null);
+        assert_eq!(value["regions"][0]["session_token"], REDACTED);
+    }
+
     // Note: Testing Config::load() with actual file I/O and env vars would require
     // more complex setup with temporary files and env var manipulation.
     // For now, we test the individual components that make up the load functionality.
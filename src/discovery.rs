@@ -9,35 +9,568 @@
 //! - `Discovery`: Main service discovery client
 //! - `PrometheusTarget`: Prometheus-compatible target representation
 //! - `Config`: Discovery-specific configuration
+//! - `DiscoveryError`: The Cloud Map operation a failed discovery run stopped at
 //!
 //! ## Discovery Process
 //!
-//! 1. List all Cloud Map namespaces (or filter by specific namespace)
+//! 1. List all Cloud Map namespaces (or filter by specific namespace), in name order
 //! 2. For each namespace, list all services
 //! 3. For each service, list all instances
 //! 4. Extract IP addresses from instance attributes
 //! 5. Create Prometheus targets with appropriate labels
+//!
+//! A lighter topology view (`discover_topology`) stops after step 2 and relies on
+//! the instance counts reported in each service summary.
 
-use aws_sdk_servicediscovery::Client as ServiceDiscoveryClient;
-use log::{debug, info};
+use crate::client::{
+    ClientError, ClientFactory, ClientFuture, CloudMapApi, ExpiredTokenError, Page,
+};
+use crate::metrics::{CrawlCounts, Metrics};
+use crate::retry::{RetryBudget, RetryPolicy};
+use crate::snapshot::SnapshotStore;
+use crate::transform::Transform;
+use aws_sdk_servicediscovery::config::{AsyncSleep, SharedAsyncSleep};
+use aws_sdk_servicediscovery::primitives::{DateTime, DateTimeFormat};
+use aws_sdk_servicediscovery::types::{
+    HealthStatusFilter, InstanceSummary, Namespace, NamespaceSummary, Service, ServiceSummary,
+};
+use log::{debug, info, warn};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// Instance attribute that opts an instance in or out of scraping
+const SCRAPE_ATTRIBUTE: &str = "scrape";
+
+/// Label carrying the Cloud Map health status of a group's instances
+const HEALTH_STATUS_LABEL: &str = "__meta_cloudmap_health_status";
+
+/// Instance attribute holding the instance's routing weight
+const INSTANCE_WEIGHT_ATTRIBUTE: &str = "AWS_INSTANCE_WEIGHT";
+
+/// Label carrying the summed routing weight of a group's instances
+const WEIGHT_TOTAL_LABEL: &str = "__meta_cloudmap_service_weight_total";
+
+/// Label carrying the fraction of a service's instances that are healthy
+const HEALTHY_RATIO_LABEL: &str = "__meta_cloudmap_healthy_ratio";
+
+/// Label carrying when a group's targets or labels last changed
+const LAST_CHANGED_LABEL: &str = "__meta_cloudmap_group_last_changed";
+
+/// Labels that identify a target group across discovery runs, besides the
+/// instance ID under `group_by: instance`
+///
+/// Only the labels groups are split by belong here; values derived from a
+/// group's content, such as its healthy ratio, change without making it a
+/// different group.
+const GROUP_IDENTITY_LABELS: &[&str] = &[
+    "__meta_cloudmap_region",
+    "__meta_cloudmap_namespace_name",
+    "__meta_cloudmap_service_name",
+    "__meta_cloudmap_protocol",
+    "__meta_cloudmap_health_status",
+    "__meta_cloudmap_role",
+    "__meta_cloudmap_instance_az",
+];
+
+/// Label carrying a hash of a group's targets and every other label
+const GROUP_HASH_LABEL: &str = "__meta_cloudmap_group_hash";
+
+/// Label carrying when the targets of a retained group were removed
+const STALE_SINCE_LABEL: &str = "__meta_cloudmap_stale_since";
+
+/// Label carrying the IDs of the instances backing a group
+const INSTANCE_ID_LABEL: &str = "__meta_cloudmap_instance_id";
+
+/// Label naming the discovery path that produced a group
+const DISCOVERY_METHOD_LABEL: &str = "__meta_cloudmap_discovery_method";
+
+/// Label carrying the ECS task ARN of a per-instance group
+const ECS_TASK_ARN_LABEL: &str = "__meta_cloudmap_ecs_task_arn";
+
+/// Label carrying the AWS region a group was discovered in, with `aws_regions`
+const REGION_LABEL: &str = "__meta_cloudmap_region";
+
+/// Instance attribute holding the availability zone, registered by ECS
+const AVAILABILITY_ZONE_ATTRIBUTE: &str = "AVAILABILITY_ZONE";
+
+/// Label carrying the availability zone of a group's instances
+const INSTANCE_AZ_LABEL: &str = "__meta_cloudmap_instance_az";
+
+/// Prefix of the instance attributes reserved by Cloud Map
+const RESERVED_ATTRIBUTE_PREFIX: &str = "AWS_INSTANCE_";
+
+/// Prefix of the labels carrying custom instance attributes
+const CUSTOM_ATTRIBUTE_LABEL_PREFIX: &str = "__meta_cloudmap_custom_";
+
+/// Prefix of the labels carrying service tags
+const TAG_LABEL_PREFIX: &str = "__meta_cloudmap_tag_";
+
+/// Maximum instance IDs per `get_instances_health_status` call
+const HEALTH_STATUS_BATCH_SIZE: usize = 10;
+
+/// Default instance attributes holding an address, in priority order
+pub const DEFAULT_IP_ATTRIBUTE_NAMES: &[&str] = &[
+    "AWS_INSTANCE_IPV4",
+    "IPv4",
+    "ip",
+    "address",
+    "AWS_INSTANCE_IPV6",
+];
+
+/// Instance attribute holding the port registered with the instance
+const INSTANCE_PORT_ATTRIBUTE: &str = "AWS_INSTANCE_PORT";
+
+/// Labels produced by the adapter itself, never dropped by `max_labels_per_group`
+const FIXED_LABELS: &[&str] = &[
+    "__meta_cloudmap_namespace_name",
+    "__meta_cloudmap_service_name",
+    "__meta_cloudmap_namespace_id",
+    "__meta_cloudmap_service_id",
+    "__meta_cloudmap_namespace_type",
+    "__meta_cloudmap_namespace_arn",
+    "__meta_cloudmap_hosted_zone_id",
+    "__meta_cloudmap_health_failure_threshold",
+    "__meta_cloudmap_protocol",
+    "__meta_cloudmap_role",
+    "__meta_cloudmap_health_status",
+    "__meta_cloudmap_healthy_ratio",
+    "__meta_cloudmap_service_weight_total",
+    "__meta_cloudmap_instance_id",
+    "__meta_cloudmap_ecs_task_arn",
+    "__meta_cloudmap_discovery_method",
+    "__meta_cloudmap_region",
+    "__meta_cloudmap_instance_az",
+    "__meta_cloudmap_duplicate_ip_count",
+    "__meta_cloudmap_adapter_started",
+    "__meta_cloudmap_stale_since",
+    "__meta_cloudmap_group_last_changed",
+    "__meta_cloudmap_group_hash",
+    "job",
+];
+
+/// How a service's instances are fetched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    /// `ListInstances`, plus `GetInstancesHealthStatus` when health is needed
+    #[default]
+    List,
+    /// `DiscoverInstances`, which returns each instance's computed health in
+    /// the same call
+    Discover,
+}
+
+/// Health status filter sent with `DiscoverInstances`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscoverHealthStatus {
+    /// Only healthy instances
+    Healthy,
+    /// Only unhealthy instances
+    Unhealthy,
+    /// Every instance
+    All,
+    /// Healthy instances, or every instance when none is healthy
+    HealthyOrElseAll,
+}
+
+impl DiscoverHealthStatus {
+    /// Returns the SDK filter for this status
+    fn filter(self) -> HealthStatusFilter {
+        match self {
+            Self::Healthy => HealthStatusFilter::Healthy,
+            Self::Unhealthy => HealthStatusFilter::Unhealthy,
+            Self::All => HealthStatusFilter::All,
+            Self::HealthyOrElseAll => HealthStatusFilter::HealthyOrElseAll,
+        }
+    }
+}
+
+/// How instances are grouped into target groups
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    /// One group per service (split by protocol and health status)
+    #[default]
+    Service,
+    /// One group per instance, labeled with `__meta_cloudmap_instance_id`
+    Instance,
+}
+
+/// Why a call to AWS failed, as far as the adapter can tell
+///
+/// AWS SDK errors are classified when they are converted by the client, so
+/// operators can tell a misconfigured IAM role from throttling or a network
+/// blip. The original error is kept as the source.
+#[derive(Debug, thiserror::Error)]
+pub enum AwsError {
+    /// AWS rejected the credentials, or the IAM role lacks a permission
+    #[error("AWS authentication failed: {0}")]
+    Auth(#[source] ClientError),
+    /// AWS throttled the request
+    #[error("AWS throttled the request: {0}")]
+    Throttled(#[source] ClientError),
+    /// The request got no response: connection failure or timeout
+    #[error("network error: {0}")]
+    Network(#[source] ClientError),
+    /// Any other failure
+    #[error("{0}")]
+    Other(#[source] ClientError),
+}
+
+impl AwsError {
+    /// Finds the classified failure in an error or its chain of sources
+    pub fn find<'a>(error: &'a (dyn std::error::Error + 'static)) -> Option<&'a AwsError> {
+        let mut current = Some(error);
+        while let Some(error) = current {
+            if let Some(failure) = error.downcast_ref::<AwsError>() {
+                return Some(failure);
+            }
+            current = error.source();
+        }
+        None
+    }
+}
+
+/// Why a discovery run failed
+///
+/// Each variant names the Cloud Map operation that failed and keeps the AWS
+/// error as its source; [`DiscoveryError::aws_error`] tells what kind of AWS
+/// failure it was.
+#[derive(Debug, thiserror::Error)]
+pub enum DiscoveryError {
+    /// Listing the namespaces failed
+    #[error("failed to list namespaces: {0}")]
+    ListNamespaces(#[source] ClientError),
+    /// No namespaces were found and `fail_on_no_namespaces` is set
+    #[error("list_namespaces returned no namespaces; check the AWS account and region")]
+    NoNamespaces,
+    /// Listing a namespace's services failed
+    #[error("failed to list services in namespace {namespace_id}: {source}")]
+    ListServices {
+        namespace_id: String,
+        #[source]
+        source: ClientError,
+    },
+    /// Listing a service's instances failed
+    #[error("failed to list instances of service {service_id}: {source}")]
+    ListInstances {
+        service_id: String,
+        #[source]
+        source: ClientError,
+    },
+    /// Discovering a service's instances with `DiscoverInstances` failed
+    #[error("failed to discover instances of service {service_name} in {namespace_name}: {source}")]
+    DiscoverInstances {
+        namespace_name: String,
+        service_name: String,
+        #[source]
+        source: ClientError,
+    },
+    /// Getting the health status of a service's instances failed
+    #[error("failed to get instance health of service {service_id}: {source}")]
+    InstanceHealth {
+        service_id: String,
+        #[source]
+        source: ClientError,
+    },
+    /// Listing a service's tags failed
+    #[error("failed to list tags of {resource_arn}: {source}")]
+    ListTags {
+        resource_arn: String,
+        #[source]
+        source: ClientError,
+    },
+    /// Looking up a service configured by ARN failed
+    #[error("failed to get service {service_id}: {source}")]
+    GetService {
+        service_id: String,
+        #[source]
+        source: ClientError,
+    },
+    /// Looking up the namespace of a service configured by ARN failed
+    #[error("failed to get namespace {namespace_id}: {source}")]
+    GetNamespace {
+        namespace_id: String,
+        #[source]
+        source: ClientError,
+    },
+    /// A listing's pagination token kept expiring after `pagination_restarts`
+    #[error("pagination token for {operation} expired: {source}")]
+    Pagination {
+        operation: String,
+        #[source]
+        source: ClientError,
+    },
+    /// The whole run took longer than `discovery_timeout_seconds`
+    #[error("discovery did not finish within {0:?}")]
+    Timeout(Duration),
+}
+
+impl DiscoveryError {
+    /// Returns the classified AWS failure behind this error, if any
+    pub fn aws_error(&self) -> Option<&AwsError> {
+        std::error::Error::source(self).and_then(AwsError::find)
+    }
+}
+
+/// A Cloud Map service identified by its ARN,
+/// `arn:<partition>:servicediscovery:<region>:<account>:service/<service-id>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceArn {
+    /// Region the service lives in
+    pub region: String,
+    /// AWS account that owns the service
+    pub account_id: String,
+    /// Cloud Map service ID (`srv-...`)
+    pub service_id: String,
+}
+
+impl ServiceArn {
+    /// Parses a Cloud Map service ARN
+    ///
+    /// Namespace ARNs and ARNs of other AWS services are rejected. Service ARNs
+    /// don't name the namespace, which discovery looks up from the service.
+    pub fn parse(arn: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("Invalid service ARN '{}': {}", arn, reason);
+
+        let parts: Vec<&str> = arn.splitn(6, ':').collect();
+        let [prefix, partition, service, region, account_id, resource] = parts[..] else {
+            return Err(invalid(
+                "expected arn:<partition>:servicediscovery:<region>:<account>:service/<id>",
+            ));
+        };
+        if prefix != "arn" || partition.is_empty() {
+            return Err(invalid("not an ARN"));
+        }
+        if service != "servicediscovery" {
+            return Err(invalid("not a Cloud Map ARN"));
+        }
+        if region.is_empty() {
+            return Err(invalid("missing region"));
+        }
+        if account_id.len() != 12 || !account_id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid("account ID must be 12 digits"));
+        }
+        let service_id = resource
+            .strip_prefix("service/")
+            .filter(|id| !id.is_empty() && !id.contains('/'))
+            .ok_or_else(|| invalid("not a service ARN"))?;
+
+        Ok(Self {
+            region: region.to_string(),
+            account_id: account_id.to_string(),
+            service_id: service_id.to_string(),
+        })
+    }
+}
 
 /// Configuration for service discovery operations
 #[derive(Debug, Clone)]
 pub struct Config {
-    /// AWS region for Cloud Map operations (currently unused, handled at client level)
-    #[allow(dead_code)]
+    /// AWS region being discovered; the client is built for it. Regional copies
+    /// made while crawling several regions label their targets with it
     pub region: Option<String>,
-    /// Specific Cloud Map namespace to discover (None = discover all namespaces)
-    pub namespace: Option<String>,
+    /// Cloud Map namespaces to discover (empty = discover all namespaces)
+    pub namespaces: Vec<String>,
+    /// Pattern namespace names must match, on top of `namespaces` (None = any)
+    pub namespace_regex: Option<Regex>,
+    /// Services to discover directly by ARN, instead of listing namespaces and
+    /// services (empty = list everything; the namespace filter doesn't apply)
+    pub service_arns: Vec<ServiceArn>,
+    /// Instance attributes holding per-protocol ports, keyed by protocol name
+    /// (empty = a single group of bare IPs per service)
+    pub protocol_port_attributes: HashMap<String, String>,
+    /// Labels merged into each group only for keys missing after discovery
+    pub fallback_labels: HashMap<String, String>,
+    /// Instance attribute `(key, value)` pair that excludes matching instances
+    pub exclude_instance_attribute: Option<(String, String)>,
+    /// Deduplicate targets within a group, counting removed duplicates in a label
+    pub deduplicate_targets: bool,
+    /// Instance attribute listing comma-separated ports, expanded to one target per port
+    pub multi_port_attribute: Option<String>,
+    /// Port appended to instances registered without `AWS_INSTANCE_PORT`
+    /// (None = bare IP)
+    pub default_port: Option<u16>,
+    /// Port appended to instances without `AWS_INSTANCE_PORT`, keyed by service
+    /// name; takes precedence over `default_port`
+    pub service_port_overrides: HashMap<String, u16>,
+    /// Instance attributes holding an address, in priority order
+    pub ip_attribute_names: Vec<String>,
+    /// Log a warning when no namespaces are found at all
+    pub warn_on_no_namespaces: bool,
+    /// Fail discovery when no namespaces are found at all
+    pub fail_on_no_namespaces: bool,
+    /// Maximum retries for a single failed AWS call
+    pub max_retries: u32,
+    /// Delay before the first retry of a failed AWS call in milliseconds
+    pub retry_base_delay_ms: u64,
+    /// Retries allowed across all discovery runs per budget window
+    pub retry_budget: u32,
+    /// Length of the retry budget window in seconds
+    pub retry_budget_window_secs: u64,
+    /// Timeout for each AWS operation including retries, in milliseconds,
+    /// used when no per-operation timeout is set (None = no timeout)
+    pub aws_call_timeout_ms: Option<u64>,
+    /// Timeout for `list_namespaces` in milliseconds
+    pub list_namespaces_timeout_ms: Option<u64>,
+    /// Timeout for each `list_services` call in milliseconds
+    pub list_services_timeout_ms: Option<u64>,
+    /// Timeout for each `list_instances` call in milliseconds
+    pub list_instances_timeout_ms: Option<u64>,
+    /// Times a listing restarts from the first page after its pagination token expires
+    pub pagination_restarts: u32,
+    /// Times `list_instances` is called again while a service returns no
+    /// instances (0 = never)
+    pub retry_empty_instances: u32,
+    /// Delay in milliseconds before each relisting of an empty service
+    pub retry_empty_instances_delay_ms: u64,
+    /// Crawl duration in milliseconds above which a slow crawl is logged and counted
+    pub max_crawl_duration_warn_ms: Option<u64>,
+    /// Seconds after which a discovery run is abandoned (None = no limit)
+    pub discovery_timeout_seconds: Option<u64>,
+    /// Whether groups hold a whole service or a single instance
+    pub group_by: GroupBy,
+    /// Whether instances are fetched with `ListInstances` or `DiscoverInstances`
+    pub discovery_mode: DiscoveryMode,
+    /// Health filter sent with `DiscoverInstances` (None = Cloud Map's default)
+    pub discover_health_status: Option<DiscoverHealthStatus>,
+    /// Split groups by the instances' `AVAILABILITY_ZONE` attribute, labeled
+    /// with `__meta_cloudmap_instance_az`
+    pub group_by_az: bool,
+    /// Look up each service's tags and emit them as `__meta_cloudmap_tag_<key>`
+    pub include_service_tags: bool,
+    /// Label groups with when their content last changed
+    /// (`__meta_cloudmap_group_last_changed`)
+    pub group_last_changed_label: bool,
+    /// Label groups with the IDs of their instances (`__meta_cloudmap_instance_id`)
+    pub instance_id_label: bool,
+    /// Label groups with the discovery path that produced them
+    /// (`__meta_cloudmap_discovery_method`: `list`, `discover`, `service_arn`
+    /// or `service_arn_discover`)
+    pub discovery_method_label: bool,
+    /// Copy custom attributes shared by a group's instances into
+    /// `__meta_cloudmap_custom_<key>` labels
+    pub include_custom_attributes: bool,
+    /// Name custom attribute labels `__meta_cloudmap_<namespace>_attr_<key>`
+    /// instead of `__meta_cloudmap_custom_<key>`
+    pub namespace_scoped_attr_labels: bool,
+    /// Label whose value orders the target groups, ties broken by namespace
+    /// and service name (None = crawl order)
+    pub sort_groups_by: Option<String>,
+    /// Seconds to serve cached results before crawling again (0 = no cache)
+    pub cache_ttl_seconds: u64,
+    /// Consecutive failed runs after which discovery is no longer ready (0 = never)
+    pub ready_failure_threshold: u32,
+    /// Label every group with the adapter start time (`__meta_cloudmap_adapter_started`)
+    pub adapter_started_label: bool,
+    /// Whether instances without a `scrape` attribute are emitted
+    pub default_scrape_enabled: bool,
+    /// Skip instances Cloud Map reports as `UNHEALTHY`, labeling the rest by status
+    pub only_healthy: bool,
+    /// Minimum fraction of a health-checked service's instances that must be
+    /// `HEALTHY` for its groups to be served (None = no minimum)
+    pub min_healthy_ratio: Option<f32>,
+    /// Drop a health-checked service once none of its remaining instances is `HEALTHY`
+    pub drop_unhealthy_services: bool,
+    /// Raw health status values mapped to `HEALTHY`, `UNHEALTHY` or `UNKNOWN`
+    pub health_status_map: HashMap<String, String>,
+    /// Seconds to keep serving removed targets, labeled as stale (0 = disabled)
+    pub target_retention_secs: u64,
+    /// Label every group with a stable hash of its targets and labels
+    pub group_hash_label: bool,
+    /// Label groups with the sum of their instances' `AWS_INSTANCE_WEIGHT`
+    pub service_weight_total_label: bool,
+    /// Emit every address attribute of an instance instead of only the first match
+    pub collect_all_addresses: bool,
+    /// Maximum labels per group; excess dynamic labels are dropped (None = unlimited)
+    pub max_labels_per_group: Option<usize>,
+    /// Instance attribute driving the `job` label, falling back to the service name
+    /// (None = no `job` label)
+    pub job_label_attribute: Option<String>,
+    /// Instance attribute holding a service VIP, emitted as its own group next to
+    /// the per-instance targets (None = no VIP group)
+    pub vip_attribute: Option<String>,
+    /// Instance attribute holding the ECS task ARN, emitted on per-instance
+    /// groups (None = no ECS task label)
+    pub ecs_task_arn_attribute: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            region: None,
+            namespaces: Vec::new(),
+            namespace_regex: None,
+            service_arns: Vec::new(),
+            protocol_port_attributes: HashMap::new(),
+            fallback_labels: HashMap::new(),
+            exclude_instance_attribute: None,
+            deduplicate_targets: false,
+            multi_port_attribute: None,
+            default_port: None,
+            service_port_overrides: HashMap::new(),
+            ip_attribute_names: DEFAULT_IP_ATTRIBUTE_NAMES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            warn_on_no_namespaces: false,
+            fail_on_no_namespaces: false,
+            max_retries: 0,
+            retry_base_delay_ms: 0,
+            retry_budget: 0,
+            retry_budget_window_secs: 0,
+            aws_call_timeout_ms: None,
+            list_namespaces_timeout_ms: None,
+            list_services_timeout_ms: None,
+            list_instances_timeout_ms: None,
+            pagination_restarts: 0,
+            retry_empty_instances: 0,
+            retry_empty_instances_delay_ms: 500,
+            max_crawl_duration_warn_ms: None,
+            discovery_timeout_seconds: None,
+            group_by: GroupBy::Service,
+            group_by_az: false,
+            discovery_mode: DiscoveryMode::List,
+            discover_health_status: None,
+            include_service_tags: false,
+            group_last_changed_label: false,
+            instance_id_label: false,
+            discovery_method_label: false,
+            include_custom_attributes: false,
+            namespace_scoped_attr_labels: false,
+            sort_groups_by: None,
+            cache_ttl_seconds: 0,
+            ready_failure_threshold: 0,
+            adapter_started_label: false,
+            default_scrape_enabled: true,
+            only_healthy: false,
+            min_healthy_ratio: None,
+            drop_unhealthy_services: false,
+            health_status_map: HashMap::new(),
+            target_retention_secs: 0,
+            group_hash_label: false,
+            service_weight_total_label: false,
+            collect_all_addresses: false,
+            max_labels_per_group: None,
+            job_label_attribute: None,
+            vip_attribute: None,
+            ecs_task_arn_attribute: None,
+        }
+    }
 }
 
 /// Prometheus-compatible target representation
 ///
 /// This struct represents a group of targets (IP addresses) that belong to the same
 /// service, along with metadata labels that Prometheus can use for relabeling.
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct PrometheusTarget {
     /// List of target addresses (IP addresses or IP:port combinations)
     pub targets: Vec<String>,
@@ -45,19 +578,118 @@ pub struct PrometheusTarget {
     /// Standard labels include:
     /// - `__meta_cloudmap_namespace_name`: Cloud Map namespace name
     /// - `__meta_cloudmap_service_name`: Cloud Map service name
-    pub labels: HashMap<String, String>,
+    /// - `__meta_cloudmap_namespace_id`: Cloud Map namespace ID
+    /// - `__meta_cloudmap_service_id`: Cloud Map service ID
+    /// - `__meta_cloudmap_namespace_type`: `HTTP`, `DNS_PRIVATE` or `DNS_PUBLIC`
+    ///   (when reported)
+    /// - `__meta_cloudmap_namespace_arn`: Cloud Map namespace ARN (when reported)
+    /// - `__meta_cloudmap_hosted_zone_id`: Route 53 hosted zone of DNS namespaces
+    /// - `__meta_cloudmap_health_failure_threshold`: Custom health check failure
+    ///   threshold of the service (when configured)
+    /// - `__meta_cloudmap_instance_id`: Sorted, comma-separated IDs of the
+    ///   instances backing the group (when enabled)
+    /// - `__meta_cloudmap_ecs_task_arn`: ECS task ARN of the group's instance
+    ///   (per-instance groups, when configured and registered)
+    /// - `__meta_cloudmap_instance_az`: Availability zone of the group's
+    ///   instances (when grouping by AZ)
+    /// - `__meta_cloudmap_discovery_method`: Discovery path that produced the
+    ///   group, `list`, `discover`, `service_arn` or `service_arn_discover`
+    ///   (when enabled)
+    /// - `__meta_cloudmap_region`: AWS region of the group (with `aws_regions`)
+    /// - `__meta_cloudmap_group_hash`: Stable hash of the group (when enabled)
+    /// - `__meta_cloudmap_group_last_changed`: When the group's targets or labels
+    ///   last changed, as RFC 3339 (when enabled)
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Topology entry for a single Cloud Map namespace
+///
+/// Lists the namespace's services without any instance-level data, so it can be
+/// produced without the expensive per-service `list_instances` calls.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct NamespaceTopology {
+    /// Cloud Map namespace name
+    pub name: String,
+    /// Cloud Map namespace ID
+    pub id: String,
+    /// Number of services registered in the namespace
+    pub service_count: usize,
+    /// Services registered in the namespace
+    pub services: Vec<ServiceTopology>,
+}
+
+/// Topology entry for a single Cloud Map service
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ServiceTopology {
+    /// Cloud Map service name
+    pub name: String,
+    /// Cloud Map service ID
+    pub id: String,
+    /// Instance count as reported by the service summary (None if not reported)
+    pub instance_count: Option<i32>,
 }
 
+/// Cloud Map clients keyed by the region they call
+pub type RegionalClients = Vec<(String, Arc<dyn CloudMapApi>)>;
+
+/// Latest crawl result and when it finished
+type CachedTargets = Option<(Instant, Vec<PrometheusTarget>)>;
+
 /// AWS Cloud Map service discovery client
 ///
-/// This struct encapsulates the AWS SDK client and configuration needed
+/// This struct encapsulates the Cloud Map client and configuration needed
 /// to perform service discovery operations against AWS Cloud Map.
 #[derive(Clone)]
 pub struct Discovery {
-    /// AWS Service Discovery client
-    client: ServiceDiscoveryClient,
+    /// Cloud Map API client (the AWS SDK client outside of tests)
+    client: Arc<Mutex<Arc<dyn CloudMapApi>>>,
+    /// One client per region to discover, with `aws_regions` (empty = only
+    /// `client`'s region)
+    regions: Arc<Mutex<RegionalClients>>,
     /// Discovery configuration
     config: Config,
+    /// Optional script applied to every target group after discovery
+    transform: Option<Arc<Transform>>,
+    /// Retry policy for AWS calls, sharing one budget across all clones
+    retry: RetryPolicy,
+    /// Sleep implementation, also used by the retry policy
+    sleep: SharedAsyncSleep,
+    /// Time this adapter instance was created, formatted as RFC 3339
+    started_at: String,
+    /// Previously served groups, keyed by their labels, for target retention
+    retained: Arc<Mutex<HashMap<String, RetainedGroup>>>,
+    /// Target groups from the latest successful discovery run
+    last_good: Arc<Mutex<Option<Vec<PrometheusTarget>>>>,
+    /// Optional on-disk store the latest successful run is persisted to
+    snapshot: Option<Arc<SnapshotStore>>,
+    /// Snapshot loaded at startup, served until discovery first succeeds
+    standby: Arc<Mutex<Option<Vec<PrometheusTarget>>>>,
+    /// Whether a background refresh for the standby snapshot is running
+    warming: Arc<AtomicBool>,
+    /// Number of crawls that exceeded `max_crawl_duration_warn_ms`
+    slow_crawls: Arc<AtomicU64>,
+    /// Whether discovery has succeeded and not failed too often since
+    ready: Arc<AtomicBool>,
+    /// Number of discovery runs that failed since the last success
+    consecutive_failures: Arc<AtomicU32>,
+    /// Content hash and last change time of each group from the previous run,
+    /// keyed by group identity
+    group_changes: Arc<Mutex<HashMap<String, (String, SystemTime)>>>,
+    /// Latest crawl result and when it finished, served for `cache_ttl_seconds`
+    cache: Arc<RwLock<CachedTargets>>,
+    /// Optional registry discovery runs are recorded in
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// Previously served state of a target group, used for target retention
+#[derive(Debug, Default)]
+struct RetainedGroup {
+    /// Labels of the group when it was last served
+    labels: BTreeMap<String, String>,
+    /// Targets served by the latest discovery run
+    current: HashSet<String>,
+    /// Recently removed targets and when they were first found missing
+    removed: HashMap<String, SystemTime>,
 }
 
 impl Discovery {
@@ -65,14 +697,179 @@ impl Discovery {
     ///
     /// # Arguments
     ///
-    /// * `client` - AWS Service Discovery client
+    /// * `client` - Cloud Map API client, usually the AWS SDK `Client`
     /// * `config` - Discovery configuration
+    /// * `sleep` - Sleep implementation used for retry backoff
+    ///
+    /// # Returns
+    ///
+    /// A new `Discovery` instance ready to perform service discovery operations.
+    /// Clones share a single retry budget, so retries are limited process-wide.
+    pub fn new(
+        client: impl CloudMapApi + 'static,
+        config: Config,
+        sleep: SharedAsyncSleep,
+    ) -> Self {
+        let retry = RetryPolicy::new(
+            config.max_retries,
+            Duration::from_millis(config.retry_base_delay_ms),
+            RetryBudget::new(
+                config.retry_budget,
+                Duration::from_secs(config.retry_budget_window_secs),
+            ),
+            sleep.clone(),
+        );
+
+        Self {
+            client: Arc::new(Mutex::new(Arc::new(client))),
+            regions: Arc::default(),
+            config,
+            transform: None,
+            retry,
+            sleep,
+            started_at: rfc3339(SystemTime::now()),
+            retained: Arc::new(Mutex::new(HashMap::new())),
+            last_good: Arc::new(Mutex::new(None)),
+            snapshot: None,
+            standby: Arc::new(Mutex::new(None)),
+            warming: Arc::new(AtomicBool::new(false)),
+            slow_crawls: Arc::new(AtomicU64::new(0)),
+            ready: Arc::new(AtomicBool::new(false)),
+            consecutive_failures: Arc::new(AtomicU32::new(0)),
+            group_changes: Arc::new(Mutex::new(HashMap::new())),
+            cache: Arc::new(RwLock::new(None)),
+            metrics: None,
+        }
+    }
+
+    /// Discovers every region in `clients` instead of only the client's own
+    ///
+    /// Each region's groups are merged into one response, labelled with
+    /// `__meta_cloudmap_region`. Service ARNs are looked up in their own
+    /// region; ARNs in regions without a client are skipped. Topology
+    /// requests keep using the client passed to [`Discovery::new`].
+    ///
+    /// # Arguments
+    ///
+    /// * `clients` - Region names and the Cloud Map client for each
+    ///
+    /// # Returns
+    ///
+    /// The `Discovery` instance crawling all of `clients`' regions
+    pub fn with_regions(self, clients: RegionalClients) -> Self {
+        *self.regions.lock().unwrap() = clients;
+        self
+    }
+
+    /// Persists successful runs to a snapshot store, loading its snapshot now
+    ///
+    /// A loaded snapshot is served as a warm standby until discovery first
+    /// succeeds, and seeds the last good response used by soft-fail mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - Snapshot store to load from and persist to
+    ///
+    /// # Returns
+    ///
+    /// The `Discovery` instance with the snapshot store attached
+    pub fn with_snapshot(mut self, store: SnapshotStore) -> Self {
+        if let Some(targets) = store.load() {
+            info!("📦 Loaded {} target groups from snapshot", targets.len());
+            *self.last_good.lock().unwrap() = Some(targets.clone());
+            *self.standby.lock().unwrap() = Some(targets);
+        }
+        self.snapshot = Some(Arc::new(store));
+        self
+    }
+
+    /// Records the outcome, duration and group count of every discovery run
+    ///
+    /// # Arguments
+    ///
+    /// * `metrics` - Registry shared with the `/metrics` endpoint
+    ///
+    /// # Returns
+    ///
+    /// The `Discovery` instance with the metrics registry attached
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Sets a transform script to run on every discovered target group
+    ///
+    /// # Arguments
+    ///
+    /// * `transform` - Compiled transform script
+    ///
+    /// # Returns
+    ///
+    /// The `Discovery` instance with the transform attached
+    pub fn with_transform(mut self, transform: Transform) -> Self {
+        self.transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// Returns a copy that only discovers `namespace`, for a single request
+    ///
+    /// The copy shares the client, retry budget and readiness tracking, but
+    /// starts with its own cache, retained targets and last good response, so
+    /// the narrowed results never leak into responses for the configured filter.
+    /// Its runs aren't recorded in the metrics.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - Namespace to discover instead of the configured one
+    pub fn for_namespace(&self, namespace: String) -> Self {
+        self.scoped(Config {
+            namespaces: vec![namespace],
+            ..self.config.clone()
+        })
+    }
+
+    /// Returns a copy that only discovers `region`, for a single request
+    ///
+    /// The copy crawls with the client configured for `region` through
+    /// [`Discovery::with_regions`], labelling its groups with
+    /// `__meta_cloudmap_region`, and otherwise behaves like
+    /// [`Discovery::for_namespace`]'s copy.
+    ///
+    /// # Arguments
+    ///
+    /// * `region` - Region to discover instead of all configured regions
     ///
     /// # Returns
     ///
-    /// A new `Discovery` instance ready to perform service discovery operations
-    pub fn new(client: ServiceDiscoveryClient, config: Config) -> Self {
-        Self { client, config }
+    /// The copy, or None when no client is configured for `region`
+    pub fn for_region(&self, region: &str) -> Option<Self> {
+        let client = self
+            .regions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| name == region)
+            .map(|(_, client)| client.clone())?;
+        Some(Self {
+            regions: Arc::new(Mutex::new(vec![(region.to_string(), client)])),
+            ..self.scoped(self.config.clone())
+        })
+    }
+
+    /// Returns a copy discovering with `config`, with its own per-request state
+    fn scoped(&self, config: Config) -> Self {
+        Self {
+            config,
+            retained: Arc::default(),
+            last_good: Arc::default(),
+            snapshot: None,
+            standby: Arc::default(),
+            warming: Arc::default(),
+            group_changes: Arc::default(),
+            cache: Arc::default(),
+            metrics: None,
+            ..self.clone()
+        }
     }
 
     /// Discovers all targets from AWS Cloud Map
@@ -84,10 +881,13 @@ impl Discovery {
     /// 4. Extracts IP addresses from instance attributes
     /// 5. Creates Prometheus targets with appropriate metadata labels
     ///
+    /// With `cache_ttl_seconds` set, a successful result is served to every
+    /// caller until it is that old, and concurrent callers share one crawl.
+    ///
     /// # Returns
     ///
     /// * `Ok(Vec<PrometheusTarget>)` - List of discovered targets
-    /// * `Err(Box<dyn Error>)` - AWS API error or other failure
+    /// * `Err(DiscoveryError)` - The Cloud Map operation that failed
     ///
     /// # Errors
     ///
@@ -96,330 +896,5727 @@ impl Discovery {
     /// - Network connectivity issues
     /// - AWS API rate limiting
     /// - Malformed service or instance data
-    pub async fn discover_targets(
-        &self,
-    ) -> Result<Vec<PrometheusTarget>, Box<dyn std::error::Error + Send + Sync>> {
-        let mut targets = Vec::new();
+    pub async fn discover_targets(&self) -> Result<Vec<PrometheusTarget>, DiscoveryError> {
+        let ttl = Duration::from_secs(self.config.cache_ttl_seconds);
+        if ttl.is_zero() {
+            return self.run_discovery().await;
+        }
 
-        // Log namespace filtering configuration
-        match &self.config.namespace {
-            Some(namespace) => {
-                info!("🔍 Filtering discovery to namespace: {}", namespace);
-            }
-            None => {
-                info!("🔍 Discovering all namespaces (no filter specified)");
-            }
+        let cached = fresh_targets(&*self.cache.read().await, ttl);
+        if let Some(targets) = cached {
+            debug!("📦 Serving {} cached target groups", targets.len());
+            return Ok(targets);
         }
 
-        // List namespaces
-        let namespaces_resp = self.client.list_namespaces().send().await?;
+        // Holding the write lock makes concurrent requests wait for this crawl
+        // instead of starting their own
+        let mut cache = self.cache.write().await;
+        if let Some(targets) = fresh_targets(&cache, ttl) {
+            debug!("📦 Serving {} cached target groups", targets.len());
+            return Ok(targets);
+        }
 
-        for namespace in namespaces_resp.namespaces() {
-            let namespace_name = namespace.name().unwrap_or("unknown");
-            let namespace_id = namespace.id().unwrap_or("");
+        let targets = self.run_discovery().await?;
+        *cache = Some((Instant::now(), targets.clone()));
+        Ok(targets)
+    }
 
-            // Skip if namespace filter is set and doesn't match
-            if let Some(ref filter) = self.config.namespace {
-                if namespace_name != filter {
-                    debug!("⏭️  Skipping namespace '{}' (filter: '{}')", namespace_name, filter);
-                    continue;
+    /// Crawls Cloud Map and replaces the shared cache with the result
+    ///
+    /// Unlike `discover_targets`, this always crawls, regardless of
+    /// `cache_ttl_seconds`. The cache is only locked once the crawl is done, so
+    /// readers keep getting the previous result in the meantime.
+    pub async fn refresh_cache(&self) -> Result<(), DiscoveryError> {
+        let targets = self.run_discovery().await?;
+        *self.cache.write().await = Some((Instant::now(), targets));
+        Ok(())
+    }
+
+    /// Returns the cached target groups, or an empty list before the first
+    /// successful refresh
+    pub async fn cached_targets(&self) -> Vec<PrometheusTarget> {
+        self.cache
+            .read()
+            .await
+            .as_ref()
+            .map(|(_, targets)| targets.clone())
+            .unwrap_or_default()
+    }
+
+    /// Spawns a task that refreshes the shared cache every `interval`
+    ///
+    /// The first refresh starts right away. A failed refresh is logged and
+    /// keeps the previous result cached.
+    pub fn spawn_refresh_loop(&self, interval: Duration) {
+        let discovery = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = discovery.refresh_cache().await {
+                    warn!("⚠️  Scheduled discovery refresh failed: {}", e);
                 }
             }
+        });
+    }
 
-            info!("🔍 Discovering services in namespace: {}", namespace_name);
+    /// Returns the current Cloud Map client
+    fn client(&self) -> Arc<dyn CloudMapApi> {
+        self.client.lock().unwrap().clone()
+    }
 
-            // List services in this namespace
-            let service_filter = aws_sdk_servicediscovery::types::ServiceFilter::builder()
-                .name(aws_sdk_servicediscovery::types::ServiceFilterName::NamespaceId)
-                .values(namespace_id)
-                .build()?;
-
-            let services_resp = self
-                .client
-                .list_services()
-                .filters(service_filter)
-                .send()
-                .await?;
+    /// Replaces the Cloud Map client with one freshly built by `factory`
+    ///
+    /// Used to pick up rotated AWS credentials: the new client resolves them
+    /// from scratch. All clones of this `Discovery` switch to the new client;
+    /// on failure the current client is kept.
+    pub async fn refresh_client(&self, factory: &dyn ClientFactory) -> Result<(), ClientError> {
+        let regions: Vec<String> = self
+            .regions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(region, _)| region.clone())
+            .collect();
+        // Build every client before swapping any, so a failure keeps them all
+        let client = factory.build().await?;
+        let mut clients = Vec::with_capacity(regions.len());
+        for region in regions {
+            let regional = factory.build_in_region(region.clone()).await?;
+            clients.push((region, regional));
+        }
 
-            for service in services_resp.services() {
-                debug!("🔍 Complete service object: {:?}", service);
+        *self.client.lock().unwrap() = client;
+        if !clients.is_empty() {
+            *self.regions.lock().unwrap() = clients;
+        }
+        info!("🔑 Rebuilt the Cloud Map client with refreshed credentials");
+        Ok(())
+    }
 
-                let service_name = service.name().unwrap_or("unknown");
-                let service_id = service.id().unwrap_or("");
+    /// Runs one discovery crawl and records its outcome for readiness and
+    /// metrics
+    ///
+    /// With `discovery_timeout_seconds`, a crawl still running when it expires
+    /// is dropped, which cancels its in-flight AWS calls, and the run fails.
+    async fn run_discovery(&self) -> Result<Vec<PrometheusTarget>, DiscoveryError> {
+        let started = Instant::now();
+        let result = match self.config.discovery_timeout_seconds {
+            Some(seconds) => {
+                let timeout = Duration::from_secs(seconds);
+                tokio::time::timeout(timeout, self.crawl_targets())
+                    .await
+                    .unwrap_or(Err(DiscoveryError::Timeout(timeout)))
+            }
+            None => self.crawl_targets().await,
+        };
+        self.record_outcome(result.is_ok());
+        if let Some(metrics) = &self.metrics {
+            metrics.record_discovery(started.elapsed(), result.as_ref().ok().map(Vec::len));
+        }
+        result
+    }
 
-                info!(
-                    "📋 Found service: {} in namespace: {}",
-                    service_name, namespace_name
-                );
+    /// Names the discovery path for `__meta_cloudmap_discovery_method`
+    ///
+    /// Services are either listed or looked up by ARN, and their instances
+    /// fetched with `ListInstances` or `DiscoverInstances`: `list`, `discover`,
+    /// `service_arn` or `service_arn_discover`.
+    fn discovery_method(&self) -> &'static str {
+        match (
+            self.config.service_arns.is_empty(),
+            self.config.discovery_mode,
+        ) {
+            (true, DiscoveryMode::List) => "list",
+            (true, DiscoveryMode::Discover) => "discover",
+            (false, DiscoveryMode::List) => "service_arn",
+            (false, DiscoveryMode::Discover) => "service_arn_discover",
+        }
+    }
 
-                // Get instances for this service
-                let instances_resp = self
-                    .client
-                    .list_instances()
-                    .service_id(service_id)
-                    .send()
-                    .await?;
+    /// Runs one full discovery crawl (see `discover_targets`)
+    async fn crawl_targets(&self) -> Result<Vec<PrometheusTarget>, DiscoveryError> {
+        let crawl_started = Instant::now();
+        let mut targets = Vec::new();
+        let mut counts = CrawlCounts::default();
 
-                let mut service_targets = Vec::new();
-                for instance in instances_resp.instances() {
-                    debug!("🔍 Complete instance object: {:?}", instance);
-
-                    if let Some(attributes) = instance.attributes() {
-                        debug!("🔍 Instance attributes: {:?}", attributes);
-                        // Look for IP addresses in common attribute names
-                        for ip_attr in ["AWS_INSTANCE_IPV4", "IPv4", "ip", "address"] {
-                            if let Some(ip) = attributes.get(ip_attr) {
-                                debug!("✅ Found IP {} in attribute {}", ip, ip_attr);
-                                service_targets.push(ip.clone());
-                                break;
-                            }
-                        }
-                    } else {
-                        debug!("⚠️  Instance has no attributes");
-                    }
+        let method = self.discovery_method();
+        let regions = self.regions.lock().unwrap().clone();
+        if regions.is_empty() {
+            self.crawl_region(&mut targets, &mut counts).await?;
+        }
+        // Regions are crawled one after another, so every region shares the
+        // retry budget and discovery never has more AWS calls in flight than
+        // with a single region
+        for (region, client) in regions {
+            let regional = self.in_region(&region, client);
+            // Regions without any of the configured service ARNs have nothing
+            // to discover
+            if regional.config.service_arns.is_empty() && !self.config.service_arns.is_empty() {
+                continue;
+            }
+            let mut regional_targets = Vec::new();
+            regional
+                .crawl_region(&mut regional_targets, &mut counts)
+                .await?;
+            if let Some(region) = &regional.config.region {
+                for target in &mut regional_targets {
+                    target
+                        .labels
+                        .insert(REGION_LABEL.to_string(), region.clone());
                 }
+            }
+            targets.extend(regional_targets);
+        }
 
-                if !service_targets.is_empty() {
-                    let mut labels = HashMap::new();
-                    labels.insert(
-                        "__meta_cloudmap_namespace_name".to_string(),
-                        namespace_name.to_string(),
-                    );
-                    labels.insert(
-                        "__meta_cloudmap_service_name".to_string(),
-                        service_name.to_string(),
-                    );
-
-                    targets.push(PrometheusTarget {
-                        targets: service_targets,
-                        labels,
-                    });
-                }
+        if self.config.discovery_method_label {
+            for target in &mut targets {
+                target
+                    .labels
+                    .insert(DISCOVERY_METHOD_LABEL.to_string(), method.to_string());
             }
         }
 
+        if self.config.target_retention_secs > 0 {
+            targets = self.apply_retention(targets, SystemTime::now());
+        }
+
+        if self.config.group_last_changed_label {
+            self.stamp_last_changed(&mut targets, SystemTime::now());
+        }
+
+        // Hashed after every other label is set, so the hash covers the
+        // labels Prometheus receives
+        if self.config.group_hash_label {
+            stamp_group_hash(&mut targets);
+        }
+
+        sort_output(&mut targets);
+        if let Some(label) = &self.config.sort_groups_by {
+            sort_groups(&mut targets, label);
+        }
+
+        self.check_crawl_duration(crawl_started.elapsed());
+
         info!("✅ Successfully discovered {} target groups", targets.len());
+        if let Some(metrics) = &self.metrics {
+            metrics.record_crawl(counts);
+        }
+        *self.last_good.lock().unwrap() = Some(targets.clone());
+        self.standby.lock().unwrap().take();
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.save(&targets);
+        }
         Ok(targets)
     }
 
-    /// Helper method for creating Prometheus targets from service instances
-    ///
-    /// This method is used primarily for testing to create well-formed
-    /// Prometheus targets with the correct metadata labels.
-    ///
-    /// # Arguments
-    ///
-    /// * `namespace_name` - Cloud Map namespace name
-    /// * `service_name` - Cloud Map service name
-    /// * `instance_ips` - List of IP addresses for the service instances
-    ///
-    /// # Returns
-    ///
-    /// A `PrometheusTarget` with the provided IPs and standard Cloud Map labels
-    #[cfg(test)]
-    pub fn create_prometheus_target(
+    /// Discovers the configured service ARNs, or else every matching
+    /// namespace, through the current client
+    async fn crawl_region(
         &self,
-        namespace_name: &str,
-        service_name: &str,
-        instance_ips: Vec<String>,
-    ) -> PrometheusTarget {
-        let mut labels = HashMap::new();
-        labels.insert(
-            "__meta_cloudmap_namespace_name".to_string(),
-            namespace_name.to_string(),
-        );
-        labels.insert(
-            "__meta_cloudmap_service_name".to_string(),
-            service_name.to_string(),
-        );
+        targets: &mut Vec<PrometheusTarget>,
+        counts: &mut CrawlCounts,
+    ) -> Result<(), DiscoveryError> {
+        if self.config.service_arns.is_empty() {
+            return self.crawl_namespaces(targets, counts).await;
+        }
 
-        PrometheusTarget {
-            targets: instance_ips,
-            labels,
+        let mut namespace_ids = HashSet::new();
+        for service_arn in &self.config.service_arns {
+            let (namespace, service) = self.get_service_by_arn(service_arn).await?;
+            namespace_ids.insert(namespace.id().unwrap_or("").to_string());
+            targets.extend(self.discover_service(&namespace, &service, counts).await?);
         }
+        counts.namespaces += namespace_ids.len();
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Returns a clone discovering `region` through `client`
+    ///
+    /// The clone keeps only the service ARNs in that region, and shares
+    /// everything else, including the retry budget, with this instance.
+    fn in_region(&self, region: &str, client: Arc<dyn CloudMapApi>) -> Self {
+        let mut config = self.config.clone();
+        config.region = Some(region.to_string());
+        config
+            .service_arns
+            .retain(|service_arn| service_arn.region == region);
+        Self {
+            client: Arc::new(Mutex::new(client)),
+            config,
+            ..self.clone()
+        }
+    }
 
-    fn create_test_discovery() -> Discovery {
-        let config = Config {
-            region: Some("us-west-2".to_string()),
-            namespace: None,
-        };
+    /// Lists every namespace and service and discovers each matching service
+    async fn crawl_namespaces(
+        &self,
+        targets: &mut Vec<PrometheusTarget>,
+        counts: &mut CrawlCounts,
+    ) -> Result<(), DiscoveryError> {
+        // Log namespace filtering configuration
+        if !self.config.namespaces.is_empty() {
+            info!(
+                "🔍 Filtering discovery to namespaces: {}",
+                self.config.namespaces.join(", ")
+            );
+        }
+        if let Some(regex) = &self.config.namespace_regex {
+            info!("🔍 Filtering discovery to namespaces matching: {}", regex);
+        }
+        if self.config.namespaces.is_empty() && self.config.namespace_regex.is_none() {
+            info!("🔍 Discovering all namespaces (no filter specified)");
+        }
 
-        let aws_config = aws_config::SdkConfig::builder()
-            .behavior_version(aws_config::BehaviorVersion::latest())
-            .region(aws_config::Region::new("us-west-2"))
-            .build();
-        let client = ServiceDiscoveryClient::new(&aws_config);
-        Discovery::new(client, config)
-    }
+        for namespace in &self.list_namespaces().await? {
+            let namespace_name = namespace.name().unwrap_or("unknown");
 
-    #[test]
-    fn test_create_prometheus_target() {
-        let discovery = create_test_discovery();
+            if !self.namespace_matches(namespace_name) {
+                continue;
+            }
 
-        let target = discovery.create_prometheus_target(
+            info!("🔍 Discovering services in namespace: {}", namespace_name);
+            counts.namespaces += 1;
+
+            let services = self
+                .list_namespace_services(namespace.id().unwrap_or(""))
+                .await?;
+
+            for service in &services {
+                debug!("🔍 Complete service object: {:?}", service);
+
+                info!(
+                    "📋 Found service: {} in namespace: {}",
+                    service.name().unwrap_or("unknown"),
+                    namespace_name
+                );
+
+                targets.extend(self.discover_service(namespace, service, counts).await?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up a configured service ARN and the namespace it belongs to
+    ///
+    /// Service ARNs don't carry the namespace, so it is read from the service;
+    /// this takes two lookups instead of listing every namespace and service.
+    async fn get_service_by_arn(
+        &self,
+        service_arn: &ServiceArn,
+    ) -> Result<(NamespaceSummary, ServiceSummary), DiscoveryError> {
+        let timeout = self.operation_timeout(None);
+        let service = self
+            .timed_call(
+                "get_service",
+                timeout,
+                self.retry.run("get_service", || {
+                    self.client().get_service(service_arn.service_id.clone())
+                }),
+            )
+            .await
+            .map_err(|source| DiscoveryError::GetService {
+                service_id: service_arn.service_id.clone(),
+                source,
+            })?;
+        let namespace_id = service.namespace_id().unwrap_or("").to_string();
+        let namespace = self
+            .timed_call(
+                "get_namespace",
+                timeout,
+                self.retry.run("get_namespace", || {
+                    self.client().get_namespace(namespace_id.clone())
+                }),
+            )
+            .await
+            .map_err(|source| DiscoveryError::GetNamespace {
+                namespace_id: namespace_id.clone(),
+                source,
+            })?;
+
+        info!(
+            "📋 Found service: {} in namespace: {} (by ARN)",
+            service.name().unwrap_or("unknown"),
+            namespace.name().unwrap_or("unknown")
+        );
+        Ok((namespace_summary(&namespace), service_summary(&service)))
+    }
+
+    /// Lists a service's instances, plus health and tags when configured, and
+    /// builds its target groups
+    async fn discover_service(
+        &self,
+        namespace: &NamespaceSummary,
+        service: &ServiceSummary,
+        counts: &mut CrawlCounts,
+    ) -> Result<Vec<PrometheusTarget>, DiscoveryError> {
+        let service_id = service.id().unwrap_or("");
+        let (instances, discovered_health) = match self.config.discovery_mode {
+            DiscoveryMode::List => (self.list_service_instances(service_id).await?, None),
+            DiscoveryMode::Discover => {
+                let (instances, health) = self
+                    .discover_service_instances(
+                        namespace.name().unwrap_or(""),
+                        service.name().unwrap_or(""),
+                    )
+                    .await?;
+                (instances, Some(health))
+            }
+        };
+        counts.services += 1;
+        counts.instances += instances.len();
+        let wants_health = self.config.only_healthy
+            || self.config.min_healthy_ratio.is_some()
+            || self.config.drop_unhealthy_services;
+        let health = if wants_health && has_health_checks(service) {
+            match discovered_health {
+                Some(health) => health,
+                None => self.list_instances_health(service_id, &instances).await?,
+            }
+        } else {
+            HashMap::new()
+        };
+
+        let tags = if self.config.include_service_tags {
+            self.list_service_tags(service).await?
+        } else {
+            Vec::new()
+        };
+
+        Ok(self.build_service_groups(namespace, service, &instances, &health, &tags))
+    }
+
+    /// Updates readiness after a discovery run
+    ///
+    /// A success makes discovery ready; `ready_failure_threshold` consecutive
+    /// failures make it not ready again until the next success.
+    fn record_outcome(&self, success: bool) {
+        if success {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            self.ready.store(true, Ordering::Relaxed);
+            return;
+        }
+
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = self.config.ready_failure_threshold;
+        if threshold > 0 && failures >= threshold && self.ready.swap(false, Ordering::Relaxed) {
+            warn!(
+                "🚫 Discovery failed {} times in a row, reporting not ready",
+                failures
+            );
+        }
+    }
+
+    /// Returns whether the latest discovery run failed
+    pub fn last_run_failed(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) > 0
+    }
+
+    /// Returns whether discovery is ready to serve targets
+    ///
+    /// Discovery is ready once a run has succeeded, and stays ready until
+    /// `ready_failure_threshold` runs in a row have failed.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Logs and counts a crawl that took longer than `max_crawl_duration_warn_ms`
+    ///
+    /// Slow crawls don't fail discovery; this is only an alerting guardrail.
+    fn check_crawl_duration(&self, elapsed: Duration) {
+        let Some(limit_ms) = self.config.max_crawl_duration_warn_ms else {
+            return;
+        };
+
+        if elapsed > Duration::from_millis(limit_ms) {
+            let slow_crawls = self.slow_crawls.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "🐢 Discovery crawl took {:?}, exceeding the {}ms warning threshold ({} slow crawls so far)",
+                elapsed, limit_ms, slow_crawls
+            );
+        }
+    }
+
+    /// Returns the number of crawls that exceeded `max_crawl_duration_warn_ms`
+    #[cfg(test)]
+    pub fn slow_crawl_count(&self) -> u64 {
+        self.slow_crawls.load(Ordering::Relaxed)
+    }
+
+    /// Returns the startup snapshot while discovery hasn't succeeded yet
+    ///
+    /// Serving the snapshot answers immediately instead of waiting on a full
+    /// crawl; a background refresh is started if none is running, and the
+    /// snapshot is retired as soon as a refresh succeeds.
+    pub fn standby_targets(&self) -> Option<Vec<PrometheusTarget>> {
+        let targets = self.standby.lock().unwrap().clone()?;
+        self.refresh_in_background();
+        Some(targets)
+    }
+
+    /// Starts a discovery run in the background unless one is already running
+    pub fn refresh_in_background(&self) {
+        if self.warming.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let discovery = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = discovery.discover_targets().await {
+                warn!("⚠️  Background discovery refresh failed: {}", e);
+            }
+            discovery.warming.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Returns the target groups from the latest successful discovery run
+    ///
+    /// Shared by all clones, so any request's success refreshes it. Returns
+    /// `None` until discovery has succeeded at least once.
+    pub fn last_good_targets(&self) -> Option<Vec<PrometheusTarget>> {
+        self.last_good.lock().unwrap().clone()
+    }
+
+    /// Keeps recently removed targets in the output for the retention window
+    ///
+    /// Churny deployments can briefly drop instances between refreshes. Targets
+    /// that disappear from a group (or whole groups that disappear) are served for
+    /// `target_retention_secs` after they were first found missing, in extra groups
+    /// carrying the original labels plus `__meta_cloudmap_stale_since`. Targets
+    /// that come back are served normally again.
+    ///
+    /// Groups are matched across runs by their identity (see `group_identity`),
+    /// so a group whose content-derived labels change, such as its instance IDs
+    /// or healthy ratio, is still the same group rather than a vanished one.
+    ///
+    /// # Arguments
+    ///
+    /// * `groups` - Target groups from the current discovery run
+    /// * `now` - Time of the current run
+    ///
+    /// # Returns
+    ///
+    /// The current groups followed by any stale groups still within the window
+    fn apply_retention(
+        &self,
+        mut groups: Vec<PrometheusTarget>,
+        now: SystemTime,
+    ) -> Vec<PrometheusTarget> {
+        let retention = Duration::from_secs(self.config.target_retention_secs);
+        let mut retained = self.retained.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut seen = HashSet::new();
+        for group in &groups {
+            let key = self.group_identity(&group.labels);
+            let entry = retained.entry(key.clone()).or_default();
+            let current: HashSet<String> = group.targets.iter().cloned().collect();
+
+            for target in entry.current.difference(&current) {
+                entry.removed.entry(target.clone()).or_insert(now);
+            }
+            entry.removed.retain(|target, _| !current.contains(target));
+            entry.current = current;
+            entry.labels = group.labels.clone();
+            seen.insert(key);
+        }
+
+        // Groups that vanished entirely in this run
+        for (key, entry) in retained.iter_mut() {
+            if !seen.contains(key) {
+                for target in entry.current.drain() {
+                    entry.removed.entry(target).or_insert(now);
+                }
+            }
+        }
+
+        retained.retain(|_, entry| {
+            entry.removed.retain(|_, removed_at| {
+                now.duration_since(*removed_at).unwrap_or_default() < retention
+            });
+            !entry.current.is_empty() || !entry.removed.is_empty()
+        });
+
+        for entry in retained.values() {
+            let mut by_removal: BTreeMap<SystemTime, Vec<String>> = BTreeMap::new();
+            for (target, removed_at) in &entry.removed {
+                by_removal
+                    .entry(*removed_at)
+                    .or_default()
+                    .push(target.clone());
+            }
+
+            for (removed_at, mut targets) in by_removal {
+                targets.sort();
+                let mut labels = entry.labels.clone();
+                labels.insert(STALE_SINCE_LABEL.to_string(), rfc3339(removed_at));
+                groups.push(PrometheusTarget { targets, labels });
+            }
+        }
+
+        groups
+    }
+
+    /// Labels each group with when its targets or labels last changed
+    ///
+    /// Groups are matched with the previous run by their identity (see
+    /// `group_identity`). A group whose content hash is unchanged keeps its
+    /// previous timestamp; new and changed groups get `now`. Retained groups
+    /// last changed when their targets were removed.
+    fn stamp_last_changed(&self, groups: &mut [PrometheusTarget], now: SystemTime) {
+        let mut changes = self.group_changes.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut current = HashMap::new();
+        for group in groups.iter_mut() {
+            if let Some(stale_since) = group.labels.get(STALE_SINCE_LABEL).cloned() {
+                group
+                    .labels
+                    .insert(LAST_CHANGED_LABEL.to_string(), stale_since);
+                continue;
+            }
+            let identity = self.group_identity(&group.labels);
+            let hash = group_hash(group);
+            let changed_at = match changes.get(&identity) {
+                Some((previous, changed_at)) if *previous == hash => *changed_at,
+                _ => now,
+            };
+            group
+                .labels
+                .insert(LAST_CHANGED_LABEL.to_string(), rfc3339(changed_at));
+            current.insert(identity, (hash, changed_at));
+        }
+
+        // Groups missing from this run start over if they come back
+        *changes = current;
+    }
+
+    /// Builds the identity of a target group from its `GROUP_IDENTITY_LABELS`,
+    /// plus its instance ID when every instance has its own group
+    fn group_identity(&self, labels: &BTreeMap<String, String>) -> String {
+        let mut values: Vec<Option<&String>> = GROUP_IDENTITY_LABELS
+            .iter()
+            .map(|label| labels.get(*label))
+            .collect();
+        if self.config.group_by == GroupBy::Instance {
+            values.push(labels.get(INSTANCE_ID_LABEL));
+        }
+        format!("{:?}", values)
+    }
+
+    /// Discovers the namespace and service topology from AWS Cloud Map
+    ///
+    /// Unlike `discover_targets`, this skips the per-service `list_instances` calls
+    /// and reports the instance counts from each service summary instead. The
+    /// configured namespace filter still applies.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<NamespaceTopology>)` - One entry per discovered namespace
+    /// * `Err(DiscoveryError)` - The Cloud Map operation that failed
+    pub async fn discover_topology(&self) -> Result<Vec<NamespaceTopology>, DiscoveryError> {
+        let mut topology = Vec::new();
+
+        for namespace in &self.list_namespaces().await? {
+            if !self.namespace_matches(namespace.name().unwrap_or("unknown")) {
+                continue;
+            }
+
+            let services = self
+                .list_namespace_services(namespace.id().unwrap_or(""))
+                .await?;
+            topology.push(namespace_topology(namespace, &services));
+        }
+
+        info!("✅ Successfully discovered {} namespaces", topology.len());
+        Ok(topology)
+    }
+
+    /// Checks a namespace name against the configured namespace filters
+    ///
+    /// An empty name list matches every namespace; with a regex as well, the
+    /// name must match both.
+    fn namespace_matches(&self, namespace_name: &str) -> bool {
+        let namespaces = &self.config.namespaces;
+        if !namespaces.is_empty() && !namespaces.iter().any(|filter| filter == namespace_name) {
+            debug!(
+                "⏭️  Skipping namespace '{}' (filter: '{}')",
+                namespace_name,
+                namespaces.join(", ")
+            );
+            return false;
+        }
+        if let Some(regex) = &self.config.namespace_regex
+            && !regex.is_match(namespace_name)
+        {
+            debug!(
+                "⏭️  Skipping namespace '{}' (regex: '{}')",
+                namespace_name, regex
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Lists the Cloud Map namespaces visible to the client, sorted by name
+    async fn list_namespaces(&self) -> Result<Vec<NamespaceSummary>, DiscoveryError> {
+        let mut namespaces = self
+            .paginate(
+                "list_namespaces",
+                self.operation_timeout(self.config.list_namespaces_timeout_ms),
+                |next_token| self.client().list_namespaces(next_token),
+                DiscoveryError::ListNamespaces,
+            )
+            .await?;
+
+        self.check_namespaces_found(&namespaces)?;
+
+        sort_namespaces(&mut namespaces);
+        Ok(namespaces)
+    }
+
+    /// Flags an empty namespace listing, which usually means the wrong account or region
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - Namespaces were found, or an empty listing is tolerated
+    /// * `Err(DiscoveryError::NoNamespaces)` - No namespaces were found and `fail_on_no_namespaces` is set
+    fn check_namespaces_found(
+        &self,
+        namespaces: &[NamespaceSummary],
+    ) -> Result<(), DiscoveryError> {
+        if !namespaces.is_empty() {
+            return Ok(());
+        }
+
+        if self.config.fail_on_no_namespaces {
+            return Err(DiscoveryError::NoNamespaces);
+        }
+        if self.config.warn_on_no_namespaces {
+            warn!(
+                "⚠️  list_namespaces returned no namespaces! Check that the AWS account and region are correct"
+            );
+        }
+        Ok(())
+    }
+
+    /// Lists the services registered in a Cloud Map namespace
+    ///
+    /// The namespace filter is sent with every page request.
+    async fn list_namespace_services(
+        &self,
+        namespace_id: &str,
+    ) -> Result<Vec<ServiceSummary>, DiscoveryError> {
+        self.paginate(
+            "list_services",
+            self.operation_timeout(self.config.list_services_timeout_ms),
+            |next_token| {
+                self.client()
+                    .list_services(namespace_id.to_string(), next_token)
+            },
+            |source| DiscoveryError::ListServices {
+                namespace_id: namespace_id.to_string(),
+                source,
+            },
+        )
+        .await
+    }
+
+    /// Lists the instances registered to a Cloud Map service
+    ///
+    /// Right after registration `list_instances` can briefly return nothing, so
+    /// an empty service is listed again up to `retry_empty_instances` times,
+    /// `retry_empty_instances_delay_ms` apart. A service that stays empty is
+    /// returned as such.
+    async fn list_service_instances(
+        &self,
+        service_id: &str,
+    ) -> Result<Vec<InstanceSummary>, DiscoveryError> {
+        let list = || {
+            self.paginate(
+                "list_instances",
+                self.operation_timeout(self.config.list_instances_timeout_ms),
+                |next_token| {
+                    self.client()
+                        .list_instances(service_id.to_string(), next_token)
+                },
+                |source| DiscoveryError::ListInstances {
+                    service_id: service_id.to_string(),
+                    source,
+                },
+            )
+        };
+
+        let mut instances = list().await?;
+        let delay = Duration::from_millis(self.config.retry_empty_instances_delay_ms);
+        for attempt in 1..=self.config.retry_empty_instances {
+            if !instances.is_empty() {
+                break;
+            }
+            debug!(
+                "🔁 Service {} returned no instances, listing again ({}/{})",
+                service_id, attempt, self.config.retry_empty_instances
+            );
+            self.sleep.sleep(delay).await;
+            instances = list().await?;
+        }
+        Ok(instances)
+    }
+
+    /// Fetches a service's instances with `DiscoverInstances`
+    ///
+    /// Returns the instances along with their normalized health status by
+    /// instance ID, which `DiscoverInstances` computes itself, so no separate
+    /// health lookup is needed.
+    async fn discover_service_instances(
+        &self,
+        namespace_name: &str,
+        service_name: &str,
+    ) -> Result<(Vec<InstanceSummary>, HashMap<String, String>), DiscoveryError> {
+        let health_status = self
+            .config
+            .discover_health_status
+            .map(DiscoverHealthStatus::filter);
+        let discovered = self
+            .timed_call(
+                "discover_instances",
+                self.operation_timeout(self.config.list_instances_timeout_ms),
+                self.retry.run("discover_instances", || {
+                    self.client().discover_instances(
+                        namespace_name.to_string(),
+                        service_name.to_string(),
+                        health_status.clone(),
+                    )
+                }),
+            )
+            .await
+            .map_err(|source| DiscoveryError::DiscoverInstances {
+                namespace_name: namespace_name.to_string(),
+                service_name: service_name.to_string(),
+                source,
+            })?;
+
+        let mut health = HashMap::new();
+        let instances = discovered
+            .into_iter()
+            .map(|instance| {
+                if let (Some(id), Some(status)) = (instance.instance_id(), instance.health_status())
+                {
+                    health.insert(id.to_string(), self.normalize_health(status.as_str()));
+                }
+                InstanceSummary::builder()
+                    .set_id(instance.instance_id().map(str::to_string))
+                    .set_attributes(instance.attributes().cloned())
+                    .build()
+            })
+            .collect();
+        Ok((instances, health))
+    }
+
+    /// Lists the tags of a Cloud Map service as `(key, value)` pairs
+    ///
+    /// Services without an ARN have no taggable resource and get no tags.
+    async fn list_service_tags(
+        &self,
+        service: &ServiceSummary,
+    ) -> Result<Vec<(String, String)>, DiscoveryError> {
+        let Some(arn) = service.arn() else {
+            return Ok(Vec::new());
+        };
+        self.paginate(
+            "list_tags_for_resource",
+            self.operation_timeout(None),
+            |next_token| {
+                self.client()
+                    .list_tags_for_resource(arn.to_string(), next_token)
+            },
+            |source| DiscoveryError::ListTags {
+                resource_arn: arn.to_string(),
+                source,
+            },
+        )
+        .await
+    }
+
+    /// Gets the normalized health status of a service's instances
+    ///
+    /// Instance IDs are sent in batches of `HEALTH_STATUS_BATCH_SIZE`, and each
+    /// batch's pages are merged. Instances missing from the responses are left
+    /// out and treated as `UNKNOWN`.
+    async fn list_instances_health(
+        &self,
+        service_id: &str,
+        instances: &[InstanceSummary],
+    ) -> Result<HashMap<String, String>, DiscoveryError> {
+        let instance_ids: Vec<String> = instances
+            .iter()
+            .filter_map(|instance| instance.id())
+            .map(str::to_string)
+            .collect();
+
+        let mut health = HashMap::new();
+        for batch in instance_ids.chunks(HEALTH_STATUS_BATCH_SIZE) {
+            let statuses = self
+                .paginate(
+                    "get_instances_health_status",
+                    self.operation_timeout(None),
+                    |next_token| {
+                        self.client().get_instances_health_status(
+                            service_id.to_string(),
+                            batch.to_vec(),
+                            next_token,
+                        )
+                    },
+                    |source| DiscoveryError::InstanceHealth {
+                        service_id: service_id.to_string(),
+                        source,
+                    },
+                )
+                .await?;
+            health.extend(
+                statuses
+                    .into_iter()
+                    .map(|(id, status)| (id, self.normalize_health(&status))),
+            );
+        }
+        Ok(health)
+    }
+
+    /// Normalizes a raw health status to `HEALTHY`, `UNHEALTHY` or `UNKNOWN`
+    ///
+    /// Values are first looked up in `health_status_map`, so custom integrations
+    /// reporting e.g. `OK`/`DOWN` can be mapped; anything unrecognized is `UNKNOWN`.
+    fn normalize_health(&self, raw: &str) -> String {
+        let status = self
+            .config
+            .health_status_map
+            .get(raw)
+            .map(String::as_str)
+            .unwrap_or(raw)
+            .to_ascii_uppercase();
+        match status.as_str() {
+            "HEALTHY" | "UNHEALTHY" | "UNKNOWN" => status,
+            _ => {
+                debug!("⚠️  Unrecognized health status '{}', using UNKNOWN", raw);
+                "UNKNOWN".to_string()
+            }
+        }
+    }
+
+    /// Collects every page of a Cloud Map list operation
+    ///
+    /// Cloud Map returns at most 100 items per page, so pages are followed until
+    /// the `next_token` runs out. Each page request is retried and timed out on
+    /// its own. When a token expires mid-listing, the listing starts over from
+    /// the first page, up to `pagination_restarts` times; once those run out the
+    /// listing fails with `DiscoveryError::Pagination`. Any other failure is
+    /// turned into a `DiscoveryError` by `failed`.
+    async fn paginate<T>(
+        &self,
+        operation: &str,
+        timeout: Option<Duration>,
+        mut list_page: impl FnMut(Option<String>) -> ClientFuture<Page<T>>,
+        failed: impl FnOnce(ClientError) -> DiscoveryError,
+    ) -> Result<Vec<T>, DiscoveryError> {
+        let mut restarts_left = self.config.pagination_restarts;
+        let mut items = Vec::new();
+        let mut next_token = None;
+        loop {
+            let page = match self
+                .timed_call(
+                    operation,
+                    timeout,
+                    self.retry.run(operation, || list_page(next_token.clone())),
+                )
+                .await
+            {
+                Ok(page) => page,
+                Err(e) if restarts_left > 0 && e.is::<ExpiredTokenError>() => {
+                    restarts_left -= 1;
+                    warn!(
+                        "🔄 Pagination token for {} expired, restarting from the first page",
+                        operation
+                    );
+                    items.clear();
+                    next_token = None;
+                    continue;
+                }
+                Err(e) if e.is::<ExpiredTokenError>() => {
+                    return Err(DiscoveryError::Pagination {
+                        operation: operation.to_string(),
+                        source: e,
+                    });
+                }
+                Err(e) => return Err(failed(e)),
+            };
+
+            items.extend(page.items);
+            next_token = page.next_token;
+            if next_token.is_none() {
+                return Ok(items);
+            }
+            debug!("📄 Fetching next page of {}", operation);
+        }
+    }
+
+    /// Runs an AWS operation under `with_timeout`, recording its duration when
+    /// metrics are enabled
+    async fn timed_call<T, E>(
+        &self,
+        operation: &str,
+        timeout: Option<Duration>,
+        call: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, ClientError>
+    where
+        E: Into<ClientError>,
+    {
+        let started = Instant::now();
+        let result = with_timeout(operation, timeout, call).await;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_aws_call(operation, started.elapsed());
+        }
+        result
+    }
+
+    /// Resolves an operation's timeout, falling back to `aws_call_timeout_ms`
+    fn operation_timeout(&self, operation_timeout_ms: Option<u64>) -> Option<Duration> {
+        operation_timeout_ms
+            .or(self.config.aws_call_timeout_ms)
+            .map(Duration::from_millis)
+    }
+
+    /// Builds the Prometheus target groups for a single Cloud Map service
+    ///
+    /// Instances carrying the configured exclusion attribute, or not enabled for
+    /// scraping, are dropped first; each remaining instance is reduced to the
+    /// first address found in its attributes, or to all of them when
+    /// `collect_all_addresses` is set.
+    /// When `protocol_port_attributes` is configured, one group is emitted per
+    /// protocol whose port attribute is present on at least one instance, labeled
+    /// with `__meta_cloudmap_protocol`. Instances carrying none of the configured
+    /// port attributes share a single group, expanded to one target per port
+    /// when the multi-port attribute is set. When a VIP
+    /// attribute is configured, the service's VIPs form an extra group labeled
+    /// `__meta_cloudmap_role=vip` and the instance groups are labeled
+    /// `__meta_cloudmap_role=instance`. The configured transform script, if any,
+    /// runs last and may relabel or drop groups.
+    ///
+    /// When health statuses are given, `UNHEALTHY` instances are skipped under
+    /// `only_healthy`, and instances are grouped by status, labeled with
+    /// `__meta_cloudmap_health_status`. Under `drop_unhealthy_services`, a
+    /// service none of whose remaining instances is `HEALTHY` yields no groups.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - Cloud Map namespace the service belongs to
+    /// * `service` - Cloud Map service being processed
+    /// * `instances` - Instances registered to the service
+    /// * `health` - Normalized health status by instance ID (empty = not looked up)
+    ///
+    /// # Returns
+    ///
+    /// The target groups for the service (empty if no instance has an IP)
+    fn build_service_groups(
+        &self,
+        namespace: &NamespaceSummary,
+        service: &ServiceSummary,
+        instances: &[InstanceSummary],
+        health: &HashMap<String, String>,
+        tags: &[(String, String)],
+    ) -> Vec<PrometheusTarget> {
+        let mut base_labels = service_labels(
+            namespace.name().unwrap_or("unknown"),
+            service.name().unwrap_or("unknown"),
+        );
+        for (key, value) in tags {
+            base_labels.insert(tag_label(key), value.clone());
+        }
+        if let Some(min_ratio) = self.config.min_healthy_ratio
+            && !health.is_empty()
+        {
+            let ratio = healthy_ratio(instances, health);
+            if ratio < f64::from(min_ratio) {
+                warn!(
+                    "🚫 Dropping service {}: healthy ratio {:.2} is below the minimum of {:.2}",
+                    service.name().unwrap_or("unknown"),
+                    ratio,
+                    min_ratio
+                );
+                return Vec::new();
+            }
+            base_labels.insert(HEALTHY_RATIO_LABEL.to_string(), format!("{:.2}", ratio));
+        }
+        if let Some(id) = namespace.id() {
+            base_labels.insert("__meta_cloudmap_namespace_id".to_string(), id.to_string());
+        }
+        if let Some(id) = service.id() {
+            base_labels.insert("__meta_cloudmap_service_id".to_string(), id.to_string());
+        }
+        if let Some(namespace_type) = namespace.r#type() {
+            base_labels.insert(
+                "__meta_cloudmap_namespace_type".to_string(),
+                namespace_type.as_str().to_string(),
+            );
+        }
+        if let Some(arn) = namespace.arn() {
+            base_labels.insert("__meta_cloudmap_namespace_arn".to_string(), arn.to_string());
+        }
+        if let Some(zone_id) = namespace
+            .properties()
+            .and_then(|properties| properties.dns_properties())
+            .and_then(|dns| dns.hosted_zone_id())
+        {
+            base_labels.insert(
+                "__meta_cloudmap_hosted_zone_id".to_string(),
+                zone_id.to_string(),
+            );
+        }
+        // Cloud Map deprecated a configurable FailureThreshold, but services
+        // created before that still report the value they were set up with
+        #[allow(deprecated)]
+        let failure_threshold = service
+            .health_check_custom_config()
+            .and_then(|custom| custom.failure_threshold());
+        if let Some(threshold) = failure_threshold {
+            base_labels.insert(
+                "__meta_cloudmap_health_failure_threshold".to_string(),
+                threshold.to_string(),
+            );
+        }
+
+        let mut addressed = Vec::new();
+        let mut vips = Vec::new();
+        for instance in instances {
+            debug!("🔍 Complete instance object: {:?}", instance);
+
+            if let Some(attributes) = instance.attributes() {
+                debug!("🔍 Instance attributes: {:?}", attributes);
+                if self.is_excluded(attributes) {
+                    debug!(
+                        "⏭️  Skipping excluded instance: {}",
+                        instance.id().unwrap_or("unknown")
+                    );
+                    continue;
+                }
+                if !self.scrape_enabled(attributes) {
+                    debug!(
+                        "⏭️  Skipping instance not enabled for scraping: {}",
+                        instance.id().unwrap_or("unknown")
+                    );
+                    continue;
+                }
+                let status = if health.is_empty() {
+                    None
+                } else {
+                    Some(
+                        instance
+                            .id()
+                            .and_then(|id| health.get(id))
+                            .map(String::as_str)
+                            .unwrap_or("UNKNOWN"),
+                    )
+                };
+                if self.config.only_healthy && status == Some("UNHEALTHY") {
+                    debug!(
+                        "⏭️  Skipping unhealthy instance: {}",
+                        instance.id().unwrap_or("unknown")
+                    );
+                    continue;
+                }
+                if let Some(vip) = self
+                    .config
+                    .vip_attribute
+                    .as_ref()
+                    .and_then(|attribute| attributes.get(attribute))
+                    .map(|vip| vip.trim())
+                    .filter(|vip| !vip.is_empty() && !vips.contains(vip))
+                {
+                    vips.push(vip);
+                }
+                let mut addresses = extract_addresses(&self.config.ip_attribute_names, attributes);
+                if !self.config.collect_all_addresses {
+                    addresses.truncate(1);
+                }
+                let id = instance.id().unwrap_or("unknown");
+                addressed.extend(addresses.into_iter().map(|ip| (ip, attributes, status, id)));
+            } else {
+                debug!("⚠️  Instance has no attributes");
+            }
+        }
+
+        if self.config.drop_unhealthy_services
+            && !health.is_empty()
+            && !addressed
+                .iter()
+                .any(|(_, _, status, _)| *status == Some("HEALTHY"))
+        {
+            warn!(
+                "🚫 Dropping service {}: no healthy instances",
+                service.name().unwrap_or("unknown")
+            );
+            return Vec::new();
+        }
+
+        if let Some(attribute) = &self.config.job_label_attribute {
+            let service_name = service.name().unwrap_or("unknown");
+            let job = match addressed
+                .iter()
+                .find_map(|(_, attributes, _, _)| attributes.get(attribute))
+                .map(|value| value.trim())
+            {
+                Some(value) if is_valid_label_value(value) => value,
+                Some(value) => {
+                    warn!(
+                        "⚠️  Invalid job label '{}' in attribute {} for service {}, using service name",
+                        value, attribute, service_name
+                    );
+                    service_name
+                }
+                None => service_name,
+            };
+            base_labels.insert("job".to_string(), job.to_string());
+        }
+
+        // Group instances by health status (and by AZ or instance when
+        // configured), in a stable order
+        let per_instance = self.config.group_by == GroupBy::Instance;
+        let mut partitions: Vec<(Option<&str>, Option<&str>, Option<&str>)> = addressed
+            .iter()
+            .map(|(_, attributes, status, id)| {
+                (
+                    *status,
+                    self.group_az(attributes),
+                    per_instance.then_some(*id),
+                )
+            })
+            .collect();
+        partitions.sort();
+        partitions.dedup();
+
+        let fallback_port = self.fallback_port(service.name().unwrap_or(""));
+        let mut groups = Vec::new();
+        for (status, az, instance_id) in partitions {
+            let members: Vec<(String, &HashMap<String, String>, &str)> = addressed
+                .iter()
+                .filter(|(_, attributes, member_status, id)| {
+                    (
+                        *member_status,
+                        self.group_az(attributes),
+                        per_instance.then_some(*id),
+                    ) == (status, az, instance_id)
+                })
+                .map(|(ip, attributes, _, id)| (ip.clone(), *attributes, *id))
+                .collect();
+            let mut labels = base_labels.clone();
+            if let Some(status) = status {
+                labels.insert(HEALTH_STATUS_LABEL.to_string(), status.to_string());
+            }
+            if let Some(az) = az {
+                labels.insert(INSTANCE_AZ_LABEL.to_string(), az.to_string());
+            }
+            if let Some(id) = instance_id {
+                labels.insert(INSTANCE_ID_LABEL.to_string(), id.to_string());
+                if let Some(task_arn) = members
+                    .first()
+                    .and_then(|(_, attributes, _)| self.ecs_task_arn(attributes))
+                {
+                    labels.insert(ECS_TASK_ARN_LABEL.to_string(), task_arn.to_string());
+                }
+            }
+            groups.extend(self.address_groups(&members, labels, fallback_port));
+        }
+
+        if self.config.vip_attribute.is_some() {
+            for group in &mut groups {
+                group
+                    .labels
+                    .insert("__meta_cloudmap_role".to_string(), "instance".to_string());
+            }
+            if !vips.is_empty() {
+                let mut labels = base_labels;
+                labels.insert("__meta_cloudmap_role".to_string(), "vip".to_string());
+                groups.push(PrometheusTarget {
+                    targets: vips.iter().map(|vip| vip.to_string()).collect(),
+                    labels,
+                });
+            }
+        }
+
+        for group in &mut groups {
+            self.finalize_group(group);
+        }
+
+        match &self.transform {
+            Some(transform) => groups
+                .into_iter()
+                .filter_map(|group| transform.apply(group))
+                .collect(),
+            None => groups,
+        }
+    }
+
+    /// Returns the ECS task ARN an instance registered under
+    /// `ecs_task_arn_attribute`, if any
+    fn ecs_task_arn<'a>(&self, attributes: &'a HashMap<String, String>) -> Option<&'a str> {
+        self.config
+            .ecs_task_arn_attribute
+            .as_ref()
+            .and_then(|attribute| attributes.get(attribute))
+            .map(|arn| arn.trim())
+            .filter(|arn| !arn.is_empty())
+    }
+
+    /// Returns the availability zone an instance is grouped by under
+    /// `group_by_az`
+    ///
+    /// Instances without an `AVAILABILITY_ZONE` attribute share a group without
+    /// the AZ label.
+    fn group_az<'a>(&self, attributes: &'a HashMap<String, String>) -> Option<&'a str> {
+        if !self.config.group_by_az {
+            return None;
+        }
+        attributes
+            .get(AVAILABILITY_ZONE_ATTRIBUTE)
+            .map(|az| az.trim())
+            .filter(|az| !az.is_empty())
+    }
+
+    /// Builds the target groups for a set of instance addresses
+    ///
+    /// One group is emitted per configured protocol found on the instances,
+    /// labeled with `__meta_cloudmap_protocol`, and the instances carrying none
+    /// of the protocols' port attributes share one more group without the
+    /// label. When enabled, each group is labeled with the weight total and the
+    /// IDs of the instances it contains. Instances without a usable port get
+    /// `fallback_port`.
+    fn address_groups(
+        &self,
+        addressed: &[(String, &HashMap<String, String>, &str)],
+        labels: BTreeMap<String, String>,
+        fallback_port: Option<u16>,
+    ) -> Vec<PrometheusTarget> {
+        let mut groups = Vec::new();
+
+        // Emit one group per configured protocol, in a stable order
+        let mut protocols: Vec<_> = self.config.protocol_port_attributes.iter().collect();
+        protocols.sort();
+        for (protocol, port_attr) in &protocols {
+            let members: Vec<_> = addressed
+                .iter()
+                .filter_map(|(ip, attributes, id)| {
+                    attributes
+                        .get(*port_attr)
+                        .map(|ports| (ip, *attributes, *id, ports))
+                })
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+
+            let mut labels = labels.clone();
+            labels.insert("__meta_cloudmap_protocol".to_string(), (*protocol).clone());
+            self.insert_weight_total(&mut labels, members.iter().map(|(_, a, _, _)| *a));
+            self.insert_custom_attributes(&mut labels, members.iter().map(|(_, a, _, _)| *a));
+            self.insert_instance_ids(&mut labels, members.iter().map(|(_, _, id, _)| *id));
+            groups.push(PrometheusTarget {
+                targets: members
+                    .iter()
+                    .flat_map(|(ip, attributes, _, ports)| {
+                        port_targets(ip, attributes, port_attr, ports, fallback_port)
+                    })
+                    .collect(),
+                labels,
+            });
+        }
+
+        let unlabeled: Vec<_> = addressed
+            .iter()
+            .filter(|(_, attributes, _)| {
+                !protocols
+                    .iter()
+                    .any(|(_, port_attr)| attributes.contains_key(*port_attr))
+            })
+            .collect();
+        if !unlabeled.is_empty() {
+            let mut labels = labels;
+            self.insert_weight_total(&mut labels, unlabeled.iter().map(|(_, a, _)| *a));
+            self.insert_custom_attributes(&mut labels, unlabeled.iter().map(|(_, a, _)| *a));
+            self.insert_instance_ids(&mut labels, unlabeled.iter().map(|(_, _, id)| *id));
+            groups.push(PrometheusTarget {
+                targets: unlabeled
+                    .iter()
+                    .flat_map(|(ip, attributes, _)| {
+                        self.instance_targets(ip, attributes, fallback_port)
+                    })
+                    .collect(),
+                labels,
+            });
+        }
+
+        groups
+    }
+
+    /// Adds the weight total label for a group's instances, when enabled
+    ///
+    /// The label is left out when no instance registers a weight, so services
+    /// that don't use weighted routing aren't reported as zero-weight.
+    fn insert_weight_total<'a>(
+        &self,
+        labels: &mut BTreeMap<String, String>,
+        instances: impl Iterator<Item = &'a HashMap<String, String>>,
+    ) {
+        if !self.config.service_weight_total_label {
+            return;
+        }
+        if let Some(total) = weight_total(instances) {
+            labels.insert(WEIGHT_TOTAL_LABEL.to_string(), total.to_string());
+        }
+    }
+
+    /// Adds the IDs of the group's instances as a label, when enabled
+    ///
+    /// A group holds every instance of a service (per protocol and health
+    /// status), so the distinct IDs are sorted and joined with commas; a
+    /// single-instance group carries just its instance's ID.
+    fn insert_instance_ids<'a>(
+        &self,
+        labels: &mut BTreeMap<String, String>,
+        ids: impl Iterator<Item = &'a str>,
+    ) {
+        if !self.config.instance_id_label {
+            return;
+        }
+        let mut ids: Vec<&str> = ids.collect();
+        ids.sort_unstable();
+        ids.dedup();
+        labels.insert(INSTANCE_ID_LABEL.to_string(), ids.join(","));
+    }
+
+    /// Adds a label for each custom attribute the group's instances share, when enabled
+    ///
+    /// A group holds many instances, so only attributes registered with the same
+    /// value on every instance are copied. Reserved `AWS_INSTANCE_*` attributes
+    /// are skipped, and labels set earlier take precedence. With
+    /// `namespace_scoped_attr_labels`, the labels are prefixed with the group's
+    /// namespace instead.
+    fn insert_custom_attributes<'a>(
+        &self,
+        labels: &mut BTreeMap<String, String>,
+        instances: impl Iterator<Item = &'a HashMap<String, String>>,
+    ) {
+        if !self.config.include_custom_attributes {
+            return;
+        }
+        let namespace = self
+            .config
+            .namespace_scoped_attr_labels
+            .then(|| labels.get("__meta_cloudmap_namespace_name").cloned())
+            .flatten();
+        for (key, value) in common_custom_attributes(instances) {
+            let label = match &namespace {
+                Some(namespace) => namespace_attribute_label(namespace, key),
+                None => custom_attribute_label(key),
+            };
+            labels.entry(label).or_insert_with(|| value.to_string());
+        }
+    }
+
+    /// Builds the target groups for a service without health data
+    #[cfg(test)]
+    fn build_service_targets(
+        &self,
+        namespace: &NamespaceSummary,
+        service: &ServiceSummary,
+        instances: &[InstanceSummary],
+    ) -> Vec<PrometheusTarget> {
+        self.build_service_groups(namespace, service, instances, &HashMap::new(), &[])
+    }
+
+    /// Expands an instance IP into its scrape targets
+    ///
+    /// When the configured multi-port attribute is present (e.g. `ports=9100,9200`),
+    /// its ports are expanded by [`port_targets`]. Otherwise the instance's
+    /// single address is the only target.
+    fn instance_targets(
+        &self,
+        ip: &str,
+        attributes: &HashMap<String, String>,
+        fallback_port: Option<u16>,
+    ) -> Vec<String> {
+        match self
+            .config
+            .multi_port_attribute
+            .as_ref()
+            .and_then(|attribute| attributes.get(attribute).map(|ports| (attribute, ports)))
+        {
+            Some((attribute, ports)) => {
+                port_targets(ip, attributes, attribute, ports, fallback_port)
+            }
+            None => vec![instance_address(ip, attributes, fallback_port)],
+        }
+    }
+
+    /// Returns the port for a service's instances registered without
+    /// `AWS_INSTANCE_PORT`
+    ///
+    /// Precedence: `service_port_overrides` entry for the service, then
+    /// `default_port`, then none (bare IP).
+    fn fallback_port(&self, service_name: &str) -> Option<u16> {
+        self.config
+            .service_port_overrides
+            .get(service_name)
+            .copied()
+            .or(self.config.default_port)
+    }
+
+    /// Checks whether an instance carries the configured exclusion attribute
+    fn is_excluded(&self, attributes: &HashMap<String, String>) -> bool {
+        match &self.config.exclude_instance_attribute {
+            Some((key, value)) => attributes.get(key) == Some(value),
+            None => false,
+        }
+    }
+
+    /// Resolves whether an instance should be scraped
+    ///
+    /// An explicit `scrape` attribute (`true`/`false`, `yes`/`no`, `1`/`0`) wins;
+    /// instances without one, or with an unrecognized value, follow
+    /// `default_scrape_enabled`.
+    fn scrape_enabled(&self, attributes: &HashMap<String, String>) -> bool {
+        let default = self.config.default_scrape_enabled;
+        match attributes.get(SCRAPE_ATTRIBUTE) {
+            Some(value) => match value.trim().to_ascii_lowercase().as_str() {
+                "true" | "yes" | "1" => true,
+                "false" | "no" | "0" => false,
+                _ => {
+                    warn!(
+                        "⚠️  Unrecognized {} attribute value '{}', using default ({})",
+                        SCRAPE_ATTRIBUTE, value, default
+                    );
+                    default
+                }
+            },
+            None => default,
+        }
+    }
+
+    /// Applies the group-level post-processing shared by every emitted group
+    ///
+    /// When deduplication is enabled, repeated targets (e.g. an IP registered twice
+    /// during a blue/green overlap) are removed and counted in
+    /// `__meta_cloudmap_duplicate_ip_count`. The adapter start time label is added
+    /// when enabled. Fallback labels are only merged for keys that discovery left
+    /// unset, so discovered values always win. The label cap is then enforced.
+    /// The group hash isn't computed here but once discovery has added its
+    /// last labels, see `stamp_group_hash`.
+    fn finalize_group(&self, group: &mut PrometheusTarget) {
+        if self.config.deduplicate_targets {
+            let mut seen = HashSet::new();
+            let before = group.targets.len();
+            group.targets.retain(|target| seen.insert(target.clone()));
+            group.labels.insert(
+                "__meta_cloudmap_duplicate_ip_count".to_string(),
+                (before - group.targets.len()).to_string(),
+            );
+        }
+
+        if self.config.adapter_started_label {
+            group.labels.insert(
+                "__meta_cloudmap_adapter_started".to_string(),
+                self.started_at.clone(),
+            );
+        }
+
+        for (key, value) in &self.config.fallback_labels {
+            group
+                .labels
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+
+        if let Some(max_labels) = self.config.max_labels_per_group {
+            cap_labels(group, max_labels);
+        }
+    }
+
+    /// Helper method for creating Prometheus targets from service instances
+    ///
+    /// This method is used primarily for testing to create well-formed
+    /// Prometheus targets with the correct metadata labels.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace_name` - Cloud Map namespace name
+    /// * `service_name` - Cloud Map service name
+    /// * `instance_ips` - List of IP addresses for the service instances
+    ///
+    /// # Returns
+    ///
+    /// A `PrometheusTarget` with the provided IPs and standard Cloud Map labels
+    #[cfg(test)]
+    pub fn create_prometheus_target(
+        &self,
+        namespace_name: &str,
+        service_name: &str,
+        instance_ips: Vec<String>,
+    ) -> PrometheusTarget {
+        PrometheusTarget {
+            targets: instance_ips,
+            labels: service_labels(namespace_name, service_name),
+        }
+    }
+}
+
+/// Drops dynamic labels so a group carries at most `max_labels` labels
+///
+/// Fixed meta labels are always kept, even if they alone exceed the cap. The
+/// remaining room is filled with dynamic labels in key order, so the same
+/// labels are dropped on every run.
+fn cap_labels(group: &mut PrometheusTarget, max_labels: usize) {
+    if group.labels.len() <= max_labels {
+        return;
+    }
+
+    let fixed = group
+        .labels
+        .keys()
+        .filter(|key| FIXED_LABELS.contains(&key.as_str()))
+        .count();
+    let mut dynamic: Vec<String> = group
+        .labels
+        .keys()
+        .filter(|key| !FIXED_LABELS.contains(&key.as_str()))
+        .cloned()
+        .collect();
+
+    let dropped = dynamic.split_off(dynamic.len().min(max_labels.saturating_sub(fixed)));
+    if dropped.is_empty() {
+        return;
+    }
+
+    warn!(
+        "⚠️  Target group for service {} exceeds {} labels, dropping: {}",
+        group
+            .labels
+            .get("__meta_cloudmap_service_name")
+            .map(String::as_str)
+            .unwrap_or("unknown"),
+        max_labels,
+        dropped.join(", ")
+    );
+    for key in &dropped {
+        group.labels.remove(key);
+    }
+}
+
+/// Extracts every address of an instance from its Cloud Map attributes
+///
+/// The `ip_attribute_names` are checked in order, so the first entry is the
+/// preferred address. An address registered under several attributes is only
+/// returned once.
+fn extract_addresses(
+    ip_attribute_names: &[String],
+    attributes: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut addresses: Vec<String> = Vec::new();
+    for ip_attr in ip_attribute_names {
+        if let Some(ip) = attributes.get(ip_attr) {
+            debug!("✅ Found IP {} in attribute {}", ip, ip_attr);
+            if !addresses.contains(ip) {
+                addresses.push(ip.clone());
+            }
+        }
+    }
+    addresses
+}
+
+/// Runs an AWS operation, failing it if it doesn't finish within `timeout`
+///
+/// The timeout covers the whole operation including retries, so a slow
+/// operation can't hold up discovery past its own latency budget.
+async fn with_timeout<T, E>(
+    operation: &str,
+    timeout: Option<Duration>,
+    call: impl Future<Output = Result<T, E>>,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>>
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let Some(timeout) = timeout else {
+        return call.await.map_err(Into::into);
+    };
+
+    match tokio::time::timeout(timeout, call).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(Box::new(AwsError::Network(
+            format!("{} timed out after {:?}", operation, timeout).into(),
+        ))),
+    }
+}
+
+/// Sums the `AWS_INSTANCE_WEIGHT` of distinct instances
+///
+/// Instances appear once per address, so attribute maps are deduplicated by
+/// identity. Invalid weights are ignored with a warning. Returns `None` when no
+/// instance registers a weight.
+fn weight_total<'a>(instances: impl Iterator<Item = &'a HashMap<String, String>>) -> Option<u64> {
+    let mut seen: Vec<&HashMap<String, String>> = Vec::new();
+    let mut total = None;
+    for attributes in instances {
+        if seen.iter().any(|other| std::ptr::eq(*other, attributes)) {
+            continue;
+        }
+        seen.push(attributes);
+
+        let Some(weight) = attributes.get(INSTANCE_WEIGHT_ATTRIBUTE) else {
+            continue;
+        };
+        match weight.trim().parse::<u64>() {
+            Ok(weight) => total = Some(total.unwrap_or(0) + weight),
+            Err(_) => warn!(
+                "⚠️  Ignoring invalid {} '{}'",
+                INSTANCE_WEIGHT_ATTRIBUTE, weight
+            ),
+        }
+    }
+    total
+}
+
+/// Collects the custom attributes registered with the same value on every instance
+///
+/// Attributes starting with `AWS_INSTANCE_` are reserved by Cloud Map and skipped.
+fn common_custom_attributes<'a>(
+    mut instances: impl Iterator<Item = &'a HashMap<String, String>>,
+) -> BTreeMap<&'a str, &'a str> {
+    let Some(first) = instances.next() else {
+        return BTreeMap::new();
+    };
+    let mut common: BTreeMap<&str, &str> = first
+        .iter()
+        .filter(|(key, _)| !key.starts_with(RESERVED_ATTRIBUTE_PREFIX))
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    for attributes in instances {
+        common.retain(|key, value| {
+            attributes
+                .get(*key)
+                .is_some_and(|other| other.as_str() == *value)
+        });
+    }
+    common
+}
+
+/// Builds the label name for a custom attribute
+///
+/// The key is lowercased and every character that isn't valid in a label name
+/// is replaced with `_`, so `Build-Version.Major` becomes
+/// `__meta_cloudmap_custom_build_version_major`.
+fn custom_attribute_label(key: &str) -> String {
+    prefixed_label(CUSTOM_ATTRIBUTE_LABEL_PREFIX, key)
+}
+
+/// Builds the namespace-scoped label name for a custom attribute
+///
+/// Both the namespace and the key are sanitized like in
+/// [`custom_attribute_label`], so attribute `ENV` in namespace `example.local`
+/// becomes `__meta_cloudmap_example_local_attr_env`.
+fn namespace_attribute_label(namespace: &str, key: &str) -> String {
+    let prefix = format!("{}_attr_", prefixed_label("__meta_cloudmap_", namespace));
+    prefixed_label(&prefix, key)
+}
+
+/// Builds the label name for a service tag, sanitized like custom attributes
+fn tag_label(key: &str) -> String {
+    prefixed_label(TAG_LABEL_PREFIX, key)
+}
+
+/// Appends a lowercased, sanitized key to a label name prefix
+fn prefixed_label(prefix: &str, key: &str) -> String {
+    let key: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}{}", prefix, key)
+}
+
+/// Computes the fraction of instances whose health status is `HEALTHY`
+///
+/// Instances missing from the health statuses count as not healthy. A service
+/// without instances has a ratio of 1, since nothing in it is down.
+fn healthy_ratio(instances: &[InstanceSummary], health: &HashMap<String, String>) -> f64 {
+    if instances.is_empty() {
+        return 1.0;
+    }
+    let healthy = instances
+        .iter()
+        .filter(|instance| {
+            instance
+                .id()
+                .and_then(|id| health.get(id))
+                .is_some_and(|status| status == "HEALTHY")
+        })
+        .count();
+    healthy as f64 / instances.len() as f64
+}
+
+/// Converts a service returned by `get_service` into the summary discovery
+/// works with
+fn service_summary(service: &Service) -> ServiceSummary {
+    ServiceSummary::builder()
+        .set_id(service.id().map(str::to_string))
+        .set_arn(service.arn().map(str::to_string))
+        .set_name(service.name().map(str::to_string))
+        .set_type(service.r#type().cloned())
+        .set_description(service.description().map(str::to_string))
+        .set_instance_count(service.instance_count())
+        .set_dns_config(service.dns_config().cloned())
+        .set_health_check_config(service.health_check_config().cloned())
+        .set_health_check_custom_config(service.health_check_custom_config().cloned())
+        .set_create_date(service.create_date().cloned())
+        .build()
+}
+
+/// Converts a namespace returned by `get_namespace` into the summary discovery
+/// works with
+fn namespace_summary(namespace: &Namespace) -> NamespaceSummary {
+    NamespaceSummary::builder()
+        .set_id(namespace.id().map(str::to_string))
+        .set_arn(namespace.arn().map(str::to_string))
+        .set_name(namespace.name().map(str::to_string))
+        .set_type(namespace.r#type().cloned())
+        .set_description(namespace.description().map(str::to_string))
+        .set_service_count(namespace.service_count())
+        .set_properties(namespace.properties().cloned())
+        .set_create_date(namespace.create_date().cloned())
+        .build()
+}
+
+/// Checks whether a service has Route 53 or custom health checks configured
+fn has_health_checks(service: &ServiceSummary) -> bool {
+    service.health_check_config().is_some() || service.health_check_custom_config().is_some()
+}
+
+/// Checks whether a value can be used as a `job` label value
+///
+/// Prometheus accepts any UTF-8 label value, but an empty or control-character
+/// job name is never intended and breaks dashboards keyed on it.
+fn is_valid_label_value(value: &str) -> bool {
+    !value.is_empty() && !value.chars().any(char::is_control)
+}
+
+/// Expands an instance IP into one `IP:PORT` target per port in `ports`
+///
+/// `ports` is the comma-separated value of `attribute`; entries are trimmed and
+/// anything that isn't a non-zero port number is skipped with a warning. An
+/// address carrying its own port, or one without any valid port in `ports`,
+/// falls back to [`instance_address`].
+fn port_targets(
+    ip: &str,
+    attributes: &HashMap<String, String>,
+    attribute: &str,
+    ports: &str,
+    fallback_port: Option<u16>,
+) -> Vec<String> {
+    if ip.contains(':') && !is_ipv6(ip) {
+        return vec![instance_address(ip, attributes, fallback_port)];
+    }
+
+    let targets: Vec<String> = ports
+        .split(',')
+        .map(str::trim)
+        .filter(|port| !port.is_empty())
+        .filter_map(|port| match port.parse::<u16>() {
+            Ok(port) if port > 0 => Some(host_port(ip, &port.to_string())),
+            _ => {
+                warn!(
+                    "⚠️  Ignoring invalid port '{}' in attribute {} for {}",
+                    port, attribute, ip
+                );
+                None
+            }
+        })
+        .collect();
+
+    if targets.is_empty() {
+        vec![instance_address(ip, attributes, fallback_port)]
+    } else {
+        targets
+    }
+}
+
+/// Builds the scrape address of an instance from its IP and `AWS_INSTANCE_PORT`
+///
+/// The port is appended as `IP:PORT` (`[IPv6]:PORT` for IPv6) when registered,
+/// and `fallback_port` is appended instead when it isn't. Any other address
+/// containing a colon is taken to carry its own port and is left alone, and
+/// instances without either port keep the bare address.
+fn instance_address(
+    ip: &str,
+    attributes: &HashMap<String, String>,
+    fallback_port: Option<u16>,
+) -> String {
+    if ip.contains(':') && !is_ipv6(ip) {
+        return ip.to_string();
+    }
+    match attributes
+        .get(INSTANCE_PORT_ATTRIBUTE)
+        .map(|port| port.trim())
+        .filter(|port| !port.is_empty())
+    {
+        Some(port) => host_port(ip, port),
+        None => match fallback_port {
+            Some(port) => host_port(ip, &port.to_string()),
+            None => ip.to_string(),
+        },
+    }
+}
+
+/// Joins an address and port into a target, bracketing IPv6 addresses
+///
+/// Prometheus parses `[::1]:9090` but not `::1:9090`. Bare IPv6 targets without
+/// a port are emitted unbracketed elsewhere.
+fn host_port(ip: &str, port: &str) -> String {
+    if is_ipv6(ip) {
+        format!("[{}]:{}", ip, port)
+    } else {
+        format!("{}:{}", ip, port)
+    }
+}
+
+/// Checks whether an address is a bare (unbracketed) IPv6 address
+fn is_ipv6(ip: &str) -> bool {
+    ip.parse::<std::net::Ipv6Addr>().is_ok()
+}
+
+/// Builds the topology entry for a namespace from its service summaries
+fn namespace_topology(
+    namespace: &NamespaceSummary,
+    services: &[ServiceSummary],
+) -> NamespaceTopology {
+    NamespaceTopology {
+        name: namespace.name().unwrap_or("unknown").to_string(),
+        id: namespace.id().unwrap_or("").to_string(),
+        service_count: services.len(),
+        services: services
+            .iter()
+            .map(|service| ServiceTopology {
+                name: service.name().unwrap_or("unknown").to_string(),
+                id: service.id().unwrap_or("").to_string(),
+                instance_count: service.instance_count(),
+            })
+            .collect(),
+    }
+}
+
+/// Formats a timestamp as an RFC 3339 string in UTC
+fn rfc3339(time: SystemTime) -> String {
+    DateTime::from(time)
+        .fmt(DateTimeFormat::DateTime)
+        .unwrap_or_default()
+}
+
+/// Sorts namespaces by name (then ID) so crawls visit them in a stable order
+///
+/// Every crawl then makes its AWS calls in the same order. A crawl cut short by
+/// `discovery_timeout_seconds` still fails as a whole; no partial result is
+/// served.
+fn sort_namespaces(namespaces: &mut [NamespaceSummary]) {
+    namespaces.sort_by(|a, b| {
+        (a.name().unwrap_or(""), a.id().unwrap_or(""))
+            .cmp(&(b.name().unwrap_or(""), b.id().unwrap_or("")))
+    });
+}
+
+/// Puts target groups and their targets in a deterministic order
+///
+/// AWS returns instances in no particular order, so each group's targets are
+/// sorted, and groups are sorted by namespace and service name, then by their
+/// remaining labels, so identical discovery results serialize identically.
+fn sort_output(groups: &mut [PrometheusTarget]) {
+    for group in groups.iter_mut() {
+        group.targets.sort();
+    }
+    groups.sort_by(|a, b| {
+        let names = |group: &PrometheusTarget| {
+            (
+                group.labels.get("__meta_cloudmap_namespace_name").cloned(),
+                group.labels.get("__meta_cloudmap_service_name").cloned(),
+            )
+        };
+        names(a)
+            .cmp(&names(b))
+            .then_with(|| a.labels.cmp(&b.labels))
+            .then_with(|| a.targets.cmp(&b.targets))
+    });
+}
+
+/// Sorts target groups by a label's value, then by namespace and service name
+///
+/// Groups without the label sort after all groups that have it. The sort is
+/// stable, so groups of the same service keep their relative order.
+fn sort_groups(groups: &mut [PrometheusTarget], label: &str) {
+    groups.sort_by_cached_key(|group| {
+        let value = |name: &str| group.labels.get(name).cloned();
+        (
+            value(label).is_none(),
+            value(label),
+            value("__meta_cloudmap_namespace_name"),
+            value("__meta_cloudmap_service_name"),
+        )
+    });
+}
+
+/// Computes a stable hash of a group's targets and labels
+///
+/// Targets are sorted first (labels already are), so the hash only changes when
+/// the group's content does.
+fn group_hash(group: &PrometheusTarget) -> String {
+    let mut targets: Vec<&String> = group.targets.iter().collect();
+    targets.sort();
+    let labels = group
+        .labels
+        .iter()
+        .filter(|(key, _)| key.as_str() != GROUP_HASH_LABEL);
+
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut write = |bytes: &[u8]| hash = fnv1a(hash, bytes);
+    for target in targets {
+        write(target.as_bytes());
+        write(b"\n");
+    }
+    write(b"\0");
+    for (key, value) in labels {
+        write(key.as_bytes());
+        write(b"=");
+        write(value.as_bytes());
+        write(b"\n");
+    }
+
+    format!("{:016x}", hash)
+}
+
+/// Labels every group with its `group_hash`
+fn stamp_group_hash(groups: &mut [PrometheusTarget]) {
+    for group in groups {
+        let hash = group_hash(group);
+        group.labels.insert(GROUP_HASH_LABEL.to_string(), hash);
+    }
+}
+
+/// Starting value of a 64-bit FNV-1a hash
+pub(crate) const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// Folds `bytes` into a 64-bit FNV-1a hash
+///
+/// Unlike `std`'s `DefaultHasher`, FNV-1a is stable across processes and
+/// builds, so hashes can be compared between runs and replicas.
+pub(crate) fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Returns cached target groups if they are younger than `ttl`
+fn fresh_targets(cache: &CachedTargets, ttl: Duration) -> Option<Vec<PrometheusTarget>> {
+    cache
+        .as_ref()
+        .filter(|(refreshed, _)| refreshed.elapsed() < ttl)
+        .map(|(_, targets)| targets.clone())
+}
+
+/// Creates the standard namespace and service metadata labels for a target group
+fn service_labels(namespace_name: &str, service_name: &str) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    labels.insert(
+        "__meta_cloudmap_namespace_name".to_string(),
+        namespace_name.to_string(),
+    );
+    labels.insert(
+        "__meta_cloudmap_service_name".to_string(),
+        service_name.to_string(),
+    );
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{MockClientFactory, MockCloudMapApi};
+    use crate::retry::TokioSleep;
+    use aws_sdk_servicediscovery::Client as ServiceDiscoveryClient;
+    use aws_sdk_servicediscovery::types::{
+        DnsProperties, HealthCheckCustomConfig, HttpProperties, NamespaceProperties, NamespaceType,
+    };
+    use std::sync::atomic::AtomicUsize;
+
+    fn create_test_discovery() -> Discovery {
+        create_test_discovery_with_config(Config {
+            region: Some("us-west-2".to_string()),
+            ..Default::default()
+        })
+    }
+
+    fn create_test_discovery_with_config(config: Config) -> Discovery {
+        let aws_config = aws_config::SdkConfig::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .region(aws_config::Region::new("us-west-2"))
+            .build();
+        let client = ServiceDiscoveryClient::new(&aws_config);
+        Discovery::new(client, config, SharedAsyncSleep::new(TokioSleep))
+    }
+
+    fn create_mock_discovery(client: MockCloudMapApi, config: Config) -> Discovery {
+        Discovery::new(client, config, SharedAsyncSleep::new(TokioSleep))
+    }
+
+    fn test_namespace(name: &str) -> NamespaceSummary {
+        NamespaceSummary::builder()
+            .id(format!("ns-{}", name))
+            .name(name)
+            .build()
+    }
+
+    fn test_service(name: &str) -> ServiceSummary {
+        ServiceSummary::builder()
+            .id(format!("srv-{}", name))
+            .name(name)
+            .build()
+    }
+
+    fn test_instance(id: &str, attributes: &[(&str, &str)]) -> InstanceSummary {
+        let mut builder = InstanceSummary::builder().id(id);
+        for (key, value) in attributes {
+            builder = builder.attributes(*key, *value);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_create_prometheus_target() {
+        let discovery = create_test_discovery();
+
+        let target = discovery.create_prometheus_target(
             "test-namespace",
             "test-service",
             vec!["192.168.1.1".to_string(), "192.168.1.2".to_string()],
         );
 
-        assert_eq!(target.targets, vec!["192.168.1.1", "192.168.1.2"]);
+        assert_eq!(target.targets, vec!["192.168.1.1", "192.168.1.2"]);
+        assert_eq!(
+            target.labels.get("__meta_cloudmap_namespace_name"),
+            Some(&"test-namespace".to_string())
+        );
+        assert_eq!(
+            target.labels.get("__meta_cloudmap_service_name"),
+            Some(&"test-service".to_string())
+        );
+    }
+
+    #[test]
+    fn test_create_prometheus_target_with_empty_ips() {
+        let discovery = create_test_discovery();
+
+        let target = discovery.create_prometheus_target("test-namespace", "test-service", vec![]);
+
+        assert!(target.targets.is_empty());
+        assert_eq!(
+            target.labels.get("__meta_cloudmap_namespace_name"),
+            Some(&"test-namespace".to_string())
+        );
+        assert_eq!(
+            target.labels.get("__meta_cloudmap_service_name"),
+            Some(&"test-service".to_string())
+        );
+    }
+
+    #[test]
+    fn test_create_prometheus_target_with_port() {
+        let discovery = create_test_discovery();
+
+        let target =
+            discovery.create_prometheus_target("ns1", "svc1", vec!["192.168.0.1:8080".to_string()]);
+
+        assert_eq!(target.targets, vec!["192.168.0.1:8080"]);
+        assert_eq!(
+            target.labels.get("__meta_cloudmap_namespace_name"),
+            Some(&"ns1".to_string())
+        );
+        assert_eq!(
+            target.labels.get("__meta_cloudmap_service_name"),
+            Some(&"svc1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_prometheus_target_serialization() {
+        let mut labels = BTreeMap::new();
+        labels.insert(
+            "__meta_cloudmap_namespace_name".to_string(),
+            "ns1".to_string(),
+        );
+        labels.insert(
+            "__meta_cloudmap_service_name".to_string(),
+            "svc1".to_string(),
+        );
+
+        let target = PrometheusTarget {
+            targets: vec!["192.168.0.1:8080".to_string()],
+            labels,
+        };
+
+        let json = serde_json::to_string(&target).unwrap();
+        let deserialized: PrometheusTarget = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(target, deserialized);
+    }
+
+    #[test]
+    fn test_config_creation() {
+        let config = Config {
+            region: Some("us-east-1".to_string()),
+            namespaces: vec!["production".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(config.region, Some("us-east-1".to_string()));
+        assert_eq!(config.namespaces, vec!["production".to_string()]);
+    }
+
+    #[test]
+    fn test_config_with_none_values() {
+        let config = Config {
+            region: None,
+            namespaces: Vec::new(),
+            ..Default::default()
+        };
+
+        assert_eq!(config.region, None);
+        assert!(config.namespaces.is_empty());
+    }
+
+    #[test]
+    fn test_config_clone() {
+        let config = Config {
+            region: Some("us-west-2".to_string()),
+            namespaces: vec!["test".to_string()],
+            ..Default::default()
+        };
+
+        let cloned_config = config.clone();
+        assert_eq!(config.region, cloned_config.region);
+        assert_eq!(config.namespaces, cloned_config.namespaces);
+    }
+
+    #[test]
+    fn test_prometheus_target_with_multiple_labels() {
+        let discovery = create_test_discovery();
+
+        let target = discovery.create_prometheus_target(
+            "production-namespace",
+            "web-service",
+            vec![
+                "10.0.1.1".to_string(),
+                "10.0.1.2".to_string(),
+                "10.0.1.3".to_string(),
+            ],
+        );
+
+        assert_eq!(target.targets.len(), 3);
+        assert!(target.targets.contains(&"10.0.1.1".to_string()));
+        assert!(target.targets.contains(&"10.0.1.2".to_string()));
+        assert!(target.targets.contains(&"10.0.1.3".to_string()));
+
+        assert_eq!(target.labels.len(), 2);
+        assert_eq!(
+            target.labels.get("__meta_cloudmap_namespace_name"),
+            Some(&"production-namespace".to_string())
+        );
+        assert_eq!(
+            target.labels.get("__meta_cloudmap_service_name"),
+            Some(&"web-service".to_string())
+        );
+    }
+
+    #[test]
+    fn test_config_with_namespace_filter() {
+        let config = Config {
+            region: Some("us-west-2".to_string()),
+            namespaces: vec!["production".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(config.region, Some("us-west-2".to_string()));
+        assert_eq!(config.namespaces, vec!["production".to_string()]);
+    }
+
+    #[test]
+    fn test_config_without_namespace_filter() {
+        let config = Config {
+            region: Some("us-west-2".to_string()),
+            namespaces: Vec::new(),
+            ..Default::default()
+        };
+
+        assert_eq!(config.region, Some("us-west-2".to_string()));
+        assert!(config.namespaces.is_empty());
+    }
+
+    #[test]
+    fn test_namespace_matches_single_filter() {
+        let discovery = create_test_discovery_with_config(Config {
+            namespaces: vec!["production".to_string()],
+            ..Default::default()
+        });
+
+        assert!(discovery.namespace_matches("production"));
+        assert!(!discovery.namespace_matches("staging"));
+    }
+
+    #[test]
+    fn test_namespace_matches_multiple_filters() {
+        let discovery = create_test_discovery_with_config(Config {
+            namespaces: vec!["production".to_string(), "staging".to_string()],
+            ..Default::default()
+        });
+
+        assert!(discovery.namespace_matches("production"));
+        assert!(discovery.namespace_matches("staging"));
+        assert!(!discovery.namespace_matches("development"));
+    }
+
+    #[test]
+    fn test_namespace_matches_without_filter() {
+        let discovery = create_test_discovery();
+
+        assert!(discovery.namespace_matches("production"));
+        assert!(discovery.namespace_matches("development"));
+    }
+
+    #[test]
+    fn test_namespace_matches_regex() {
+        let discovery = create_test_discovery_with_config(Config {
+            namespace_regex: Some(Regex::new("^team-.*-prod$").unwrap()),
+            ..Default::default()
+        });
+
+        assert!(discovery.namespace_matches("team-payments-prod"));
+        assert!(!discovery.namespace_matches("team-payments-staging"));
+        assert!(!discovery.namespace_matches("production"));
+    }
+
+    #[test]
+    fn test_namespace_matches_names_and_regex() {
+        let discovery = create_test_discovery_with_config(Config {
+            namespaces: vec!["team-a-prod".to_string(), "team-b-staging".to_string()],
+            namespace_regex: Some(Regex::new("-prod$").unwrap()),
+            ..Default::default()
+        });
+
+        assert!(discovery.namespace_matches("team-a-prod"));
+        assert!(!discovery.namespace_matches("team-b-staging"));
+        assert!(!discovery.namespace_matches("team-c-prod"));
+    }
+
+    #[test]
+    fn test_build_service_targets_single_group() {
+        let discovery = create_test_discovery();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+                test_instance("i-2", &[("ip", "10.0.0.2")]),
+                test_instance("i-3", &[("unrelated", "value")]),
+            ],
+        );
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].targets, vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(groups[0].labels.get("__meta_cloudmap_protocol"), None);
+    }
+
+    #[test]
+    fn test_namespace_and_service_id_labels() {
+        let discovery = create_test_discovery();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(
+            groups[0].labels["__meta_cloudmap_namespace_id"],
+            "ns-production"
+        );
+        assert_eq!(groups[0].labels["__meta_cloudmap_service_id"], "srv-web");
+        assert_eq!(groups[0].labels.len(), 4);
+    }
+
+    #[test]
+    fn test_build_service_targets_one_group_per_protocol() {
+        let discovery = create_test_discovery_with_config(Config {
+            protocol_port_attributes: HashMap::from([
+                ("grpc".to_string(), "GRPC_PORT".to_string()),
+                ("http".to_string(), "METRICS_PORT".to_string()),
+            ]),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("api"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                        ("GRPC_PORT", "9090"),
+                        ("METRICS_PORT", "8080"),
+                    ],
+                ),
+                test_instance(
+                    "i-2",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.2"),
+                        ("GRPC_PORT", "9090"),
+                        ("METRICS_PORT", "8080"),
+                    ],
+                ),
+            ],
+        );
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].targets, vec!["10.0.0.1:9090", "10.0.0.2:9090"]);
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_protocol"),
+            Some(&"grpc".to_string())
+        );
+        assert_eq!(groups[1].targets, vec!["10.0.0.1:8080", "10.0.0.2:8080"]);
+        assert_eq!(
+            groups[1].labels.get("__meta_cloudmap_protocol"),
+            Some(&"http".to_string())
+        );
+        for group in &groups {
+            assert_eq!(
+                group.labels.get("__meta_cloudmap_service_name"),
+                Some(&"api".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_protocol_groups_keep_instances_without_protocol_ports() {
+        let discovery = create_test_discovery_with_config(Config {
+            protocol_port_attributes: HashMap::from([(
+                "grpc".to_string(),
+                "GRPC_PORT".to_string(),
+            )]),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("api"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                        ("GRPC_PORT", "9090, 9091"),
+                    ],
+                ),
+                test_instance(
+                    "i-2",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.2"),
+                        ("AWS_INSTANCE_PORT", "8080"),
+                        ("GRPC_PORT", "bogus"),
+                    ],
+                ),
+                test_instance(
+                    "i-3",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.3"),
+                        ("AWS_INSTANCE_PORT", "8080"),
+                    ],
+                ),
+            ],
+        );
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups[0].targets,
+            vec!["10.0.0.1:9090", "10.0.0.1:9091", "10.0.0.2:8080"]
+        );
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_protocol"),
+            Some(&"grpc".to_string())
+        );
+        assert_eq!(groups[1].targets, vec!["10.0.0.3:8080"]);
+        assert_eq!(groups[1].labels.get("__meta_cloudmap_protocol"), None);
+    }
+
+    #[test]
+    fn test_fallback_labels_only_fill_missing_keys() {
+        let discovery = create_test_discovery_with_config(Config {
+            fallback_labels: HashMap::from([
+                ("team".to_string(), "platform".to_string()),
+                (
+                    "__meta_cloudmap_service_name".to_string(),
+                    "fallback-service".to_string(),
+                ),
+            ]),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].labels.get("team"), Some(&"platform".to_string()));
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_service_name"),
+            Some(&"web".to_string())
+        );
+    }
+
+    #[test]
+    fn test_namespace_topology_omits_instance_data() {
+        let services = vec![
+            ServiceSummary::builder()
+                .id("srv-web")
+                .name("web")
+                .instance_count(3)
+                .build(),
+            test_service("worker"),
+        ];
+
+        let topology = namespace_topology(&test_namespace("production"), &services);
+
+        assert_eq!(topology.name, "production");
+        assert_eq!(topology.id, "ns-production");
+        assert_eq!(topology.service_count, 2);
+        assert_eq!(topology.services[0].instance_count, Some(3));
+        assert_eq!(topology.services[1].instance_count, None);
+
+        let json = serde_json::to_value(&topology).unwrap();
+        let service = json["services"][0].as_object().unwrap();
+        let mut keys: Vec<&String> = service.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["id", "instance_count", "name"]);
+        assert!(json.get("targets").is_none());
+    }
+
+    #[test]
+    fn test_namespace_arn_label() {
+        let discovery = create_test_discovery();
+        let namespace = NamespaceSummary::builder()
+            .id("ns-abc123")
+            .name("production")
+            .arn("arn:aws:servicediscovery:us-west-2:123456789012:namespace/ns-abc123")
+            .build();
+
+        let groups = discovery.build_service_targets(
+            &namespace,
+            &test_service("web"),
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_namespace_arn"),
+            Some(
+                &"arn:aws:servicediscovery:us-west-2:123456789012:namespace/ns-abc123".to_string()
+            )
+        );
+    }
+
+    fn grouping_instances() -> Vec<InstanceSummary> {
+        vec![
+            test_instance(
+                "i-2",
+                &[("AWS_INSTANCE_IPV4", "10.0.0.2"), ("VERSION", "1.2.4")],
+            ),
+            test_instance(
+                "i-1",
+                &[("AWS_INSTANCE_IPV4", "10.0.0.1"), ("VERSION", "1.2.3")],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_group_by_service_shares_one_group() {
+        let discovery = create_test_discovery_with_config(Config {
+            include_custom_attributes: true,
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &grouping_instances(),
+        );
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].targets, vec!["10.0.0.2", "10.0.0.1"]);
+        assert_eq!(groups[0].labels.get(INSTANCE_ID_LABEL), None);
+        assert_eq!(groups[0].labels.get("__meta_cloudmap_custom_version"), None);
+    }
+
+    #[test]
+    fn test_group_by_instance_emits_group_per_instance() {
+        let discovery = create_test_discovery_with_config(Config {
+            group_by: GroupBy::Instance,
+            include_custom_attributes: true,
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &grouping_instances(),
+        );
+
+        let summary: Vec<(&str, &str, &str)> = groups
+            .iter()
+            .map(|group| {
+                (
+                    group.targets[0].as_str(),
+                    group.labels[INSTANCE_ID_LABEL].as_str(),
+                    group.labels["__meta_cloudmap_custom_version"].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("10.0.0.1", "i-1", "1.2.3"), ("10.0.0.2", "i-2", "1.2.4")]
+        );
+        assert!(groups.iter().all(|group| group.targets.len() == 1));
+        assert!(groups.iter().all(|group| {
+            group.labels.get("__meta_cloudmap_service_name") == Some(&"web".to_string())
+        }));
+    }
+
+    #[test]
+    fn test_ecs_task_arn_label_on_instance_groups() {
+        let task_arn = "arn:aws:ecs:us-west-2:123456789012:task/web/0123456789abcdef";
+        let discovery = create_test_discovery_with_config(Config {
+            group_by: GroupBy::Instance,
+            ecs_task_arn_attribute: Some("ECS_TASK_ARN".to_string()),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                        ("ECS_TASK_ARN", task_arn),
+                    ],
+                ),
+                test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+            ],
+        );
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].labels[INSTANCE_ID_LABEL], "i-1");
+        assert_eq!(
+            groups[0].labels.get(ECS_TASK_ARN_LABEL),
+            Some(&task_arn.to_string())
+        );
+        assert_eq!(groups[1].labels[INSTANCE_ID_LABEL], "i-2");
+        assert_eq!(groups[1].labels.get(ECS_TASK_ARN_LABEL), None);
+    }
+
+    #[test]
+    fn test_ecs_task_arn_label_absent_on_service_groups() {
+        let discovery = create_test_discovery_with_config(Config {
+            ecs_task_arn_attribute: Some("ECS_TASK_ARN".to_string()),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[test_instance(
+                "i-1",
+                &[
+                    ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                    ("ECS_TASK_ARN", "arn:aws:ecs:task"),
+                ],
+            )],
+        );
+
+        assert_eq!(groups[0].labels.get(ECS_TASK_ARN_LABEL), None);
+    }
+
+    #[test]
+    fn test_group_by_az_emits_group_per_zone() {
+        let discovery = create_test_discovery_with_config(Config {
+            group_by_az: true,
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                        ("AVAILABILITY_ZONE", "us-west-2a"),
+                    ],
+                ),
+                test_instance(
+                    "i-2",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.1.2"),
+                        ("AVAILABILITY_ZONE", "us-west-2b"),
+                    ],
+                ),
+                test_instance(
+                    "i-3",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.3"),
+                        ("AVAILABILITY_ZONE", "us-west-2a"),
+                    ],
+                ),
+                test_instance("i-4", &[("AWS_INSTANCE_IPV4", "10.0.2.4")]),
+            ],
+        );
+
+        let summary: Vec<(Option<&str>, Vec<&str>)> = groups
+            .iter()
+            .map(|group| {
+                (
+                    group.labels.get(INSTANCE_AZ_LABEL).map(String::as_str),
+                    group.targets.iter().map(String::as_str).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (None, vec!["10.0.2.4"]),
+                (Some("us-west-2a"), vec!["10.0.0.1", "10.0.0.3"]),
+                (Some("us-west-2b"), vec!["10.0.1.2"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_by_deserializes_lowercase() {
+        assert_eq!(
+            serde_json::from_str::<GroupBy>("\"instance\"").unwrap(),
+            GroupBy::Instance
+        );
+        assert_eq!(
+            serde_json::from_str::<GroupBy>("\"service\"").unwrap(),
+            GroupBy::Service
+        );
+    }
+
+    #[test]
+    fn test_instance_id_label_lists_group_instances() {
+        let discovery = create_test_discovery_with_config(Config {
+            instance_id_label: true,
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+                test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+            ],
+        );
+
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_instance_id"),
+            Some(&"i-1,i-2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_instance_id_label_follows_protocol_groups() {
+        let discovery = create_test_discovery_with_config(Config {
+            instance_id_label: true,
+            protocol_port_attributes: HashMap::from([
+                ("grpc".to_string(), "grpc_port".to_string()),
+                ("http".to_string(), "http_port".to_string()),
+            ]),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[("AWS_INSTANCE_IPV4", "10.0.0.1"), ("http_port", "8080")],
+                ),
+                test_instance(
+                    "i-2",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.2"),
+                        ("http_port", "8080"),
+                        ("grpc_port", "9090"),
+                    ],
+                ),
+            ],
+        );
+
+        let ids: Vec<(&str, &str)> = groups
+            .iter()
+            .map(|group| {
+                (
+                    group.labels["__meta_cloudmap_protocol"].as_str(),
+                    group.labels["__meta_cloudmap_instance_id"].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(ids, vec![("grpc", "i-2"), ("http", "i-1,i-2")]);
+    }
+
+    #[test]
+    fn test_custom_attribute_label_sanitizes_key() {
+        assert_eq!(
+            custom_attribute_label("Build-Version.Major"),
+            "__meta_cloudmap_custom_build_version_major"
+        );
+        assert_eq!(custom_attribute_label("ENV"), "__meta_cloudmap_custom_env");
+        assert_eq!(
+            custom_attribute_label("team/owner"),
+            "__meta_cloudmap_custom_team_owner"
+        );
+    }
+
+    #[test]
+    fn test_tag_label_sanitizes_key() {
+        assert_eq!(tag_label("cost-center"), "__meta_cloudmap_tag_cost_center");
+        assert_eq!(tag_label("Team"), "__meta_cloudmap_tag_team");
+        assert_eq!(tag_label("app.tier"), "__meta_cloudmap_tag_app_tier");
+    }
+
+    #[tokio::test]
+    async fn test_service_tags_become_labels() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            let service = ServiceSummary::builder()
+                .id("srv-web")
+                .name("web")
+                .arn("arn:aws:servicediscovery:us-west-2:123456789012:service/srv-web")
+                .build();
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![service],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+                    next_token: None,
+                })
+            })
+        });
+        client
+            .expect_list_tags_for_resource()
+            .withf(|arn, _| arn.ends_with("service/srv-web"))
+            .times(2)
+            .returning(|_, next_token| {
+                let page = match next_token {
+                    None => Page {
+                        items: vec![("cost-center".to_string(), "1234".to_string())],
+                        next_token: Some("page-2".to_string()),
+                    },
+                    Some(_) => Page {
+                        items: vec![("Team".to_string(), "platform".to_string())],
+                        next_token: None,
+                    },
+                };
+                Box::pin(async move { Ok(page) })
+            });
+        let discovery = create_mock_discovery(
+            client,
+            Config {
+                include_service_tags: true,
+                ..Default::default()
+            },
+        );
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        let labels = &targets[0].labels;
+        assert_eq!(
+            labels.get("__meta_cloudmap_tag_cost_center"),
+            Some(&"1234".to_string())
+        );
+        assert_eq!(
+            labels.get("__meta_cloudmap_tag_team"),
+            Some(&"platform".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_service_arns_discovered_directly() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().never();
+        client.expect_list_services().never();
+        client
+            .expect_get_service()
+            .withf(|service_id| service_id == "srv-web")
+            .times(1)
+            .returning(|_| {
+                let service = Service::builder()
+                    .id("srv-web")
+                    .name("web")
+                    .namespace_id("ns-production")
+                    .build();
+                Box::pin(async move { Ok(service) })
+            });
+        client
+            .expect_get_namespace()
+            .withf(|namespace_id| namespace_id == "ns-production")
+            .times(1)
+            .returning(|_| {
+                let namespace = Namespace::builder()
+                    .id("ns-production")
+                    .name("production")
+                    .build();
+                Box::pin(async move { Ok(namespace) })
+            });
+        client
+            .expect_list_instances()
+            .withf(|service_id, _| service_id == "srv-web")
+            .times(1)
+            .returning(|_, _| {
+                Box::pin(async {
+                    Ok(Page {
+                        items: vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+                        next_token: None,
+                    })
+                })
+            });
+        let discovery = create_mock_discovery(
+            client,
+            Config {
+                service_arns: vec![
+                    ServiceArn::parse(
+                        "arn:aws:servicediscovery:us-west-2:123456789012:service/srv-web",
+                    )
+                    .unwrap(),
+                ],
+                ..Default::default()
+            },
+        );
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].targets, vec!["10.0.0.1"]);
+        assert_eq!(
+            targets[0].labels.get("__meta_cloudmap_namespace_name"),
+            Some(&"production".to_string())
+        );
+        assert_eq!(
+            targets[0].labels.get("__meta_cloudmap_service_name"),
+            Some(&"web".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discovery_method_label_for_service_arns() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_get_service().returning(|_| {
+            let service = Service::builder()
+                .id("srv-web")
+                .name("web")
+                .namespace_id("ns-production")
+                .build();
+            Box::pin(async move { Ok(service) })
+        });
+        client.expect_get_namespace().returning(|_| {
+            let namespace = Namespace::builder()
+                .id("ns-production")
+                .name("production")
+                .build();
+            Box::pin(async move { Ok(namespace) })
+        });
+        client.expect_list_instances().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+                    next_token: None,
+                })
+            })
+        });
+        let discovery = create_mock_discovery(
+            client,
+            Config {
+                service_arns: vec![
+                    ServiceArn::parse(
+                        "arn:aws:servicediscovery:us-west-2:123456789012:service/srv-web",
+                    )
+                    .unwrap(),
+                ],
+                discovery_method_label: true,
+                ..Default::default()
+            },
+        );
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(
+            targets[0].labels.get("__meta_cloudmap_discovery_method"),
+            Some(&"service_arn".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discovery_method_label_for_listing() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_service("web")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+                    next_token: None,
+                })
+            })
+        });
+        let discovery = create_mock_discovery(
+            client,
+            Config {
+                discovery_method_label: true,
+                ..Default::default()
+            },
+        );
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(
+            targets[0].labels.get("__meta_cloudmap_discovery_method"),
+            Some(&"list".to_string())
+        );
+    }
+
+    #[test]
+    fn test_custom_attributes_promoted_to_labels() {
+        let discovery = create_test_discovery_with_config(Config {
+            include_custom_attributes: true,
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                        ("ENV", "prod"),
+                        ("app.version", "1.2.3"),
+                    ],
+                ),
+                test_instance(
+                    "i-2",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.2"),
+                        ("ENV", "prod"),
+                        ("app.version", "1.2.4"),
+                    ],
+                ),
+            ],
+        );
+
+        let labels = &groups[0].labels;
+        assert_eq!(
+            labels.get("__meta_cloudmap_custom_env"),
+            Some(&"prod".to_string())
+        );
+        assert_eq!(labels.get("__meta_cloudmap_custom_app_version"), None);
+        assert_eq!(labels.get("__meta_cloudmap_custom_aws_instance_ipv4"), None);
+    }
+
+    #[test]
+    fn test_namespace_scoped_attribute_labels() {
+        let discovery = create_test_discovery_with_config(Config {
+            include_custom_attributes: true,
+            namespace_scoped_attr_labels: true,
+            ..Default::default()
+        });
+        let instance = test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1"), ("ENV", "prod")]);
+
+        let production = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            std::slice::from_ref(&instance),
+        );
+        let local = discovery.build_service_targets(
+            &test_namespace("example.local"),
+            &test_service("web"),
+            &[instance],
+        );
+
+        assert_eq!(
+            production[0]
+                .labels
+                .get("__meta_cloudmap_production_attr_env"),
+            Some(&"prod".to_string())
+        );
+        assert_eq!(
+            local[0]
+                .labels
+                .get("__meta_cloudmap_example_local_attr_env"),
+            Some(&"prod".to_string())
+        );
+        assert_eq!(production[0].labels.get("__meta_cloudmap_custom_env"), None);
+    }
+
+    #[test]
+    fn test_custom_attributes_disabled_by_default() {
+        let discovery = create_test_discovery();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[test_instance(
+                "i-1",
+                &[("AWS_INSTANCE_IPV4", "10.0.0.1"), ("ENV", "prod")],
+            )],
+        );
+
+        assert_eq!(groups[0].labels.get("__meta_cloudmap_custom_env"), None);
+    }
+
+    #[test]
+    fn test_sort_groups_by_label() {
+        let group = |namespace: &str, service: &str, team: Option<&str>| {
+            let mut labels = service_labels(namespace, service);
+            if let Some(team) = team {
+                labels.insert("team".to_string(), team.to_string());
+            }
+            PrometheusTarget {
+                targets: vec![format!("{}.{}", service, namespace)],
+                labels,
+            }
+        };
+        let mut groups = vec![
+            group("production", "web", Some("platform")),
+            group("production", "api", None),
+            group("staging", "web", Some("checkout")),
+            group("production", "worker", Some("checkout")),
+        ];
+
+        sort_groups(&mut groups, "team");
+
+        let order: Vec<&str> = groups.iter().map(|g| g.targets[0].as_str()).collect();
+        assert_eq!(
+            order,
+            vec![
+                "worker.production",
+                "web.staging",
+                "web.production",
+                "api.production"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_output_orders_groups_and_targets() {
+        let group = |namespace: &str, service: &str, targets: &[&str], status: &str| {
+            let mut labels = service_labels(namespace, service);
+            labels.insert(HEALTH_STATUS_LABEL.to_string(), status.to_string());
+            PrometheusTarget {
+                targets: targets.iter().map(|target| target.to_string()).collect(),
+                labels,
+            }
+        };
+        let mut groups = vec![
+            group("staging", "api", &["10.0.0.9"], "HEALTHY"),
+            group("production", "web", &["10.0.0.3", "10.0.0.1"], "UNHEALTHY"),
+            group("production", "web", &["10.0.0.2"], "HEALTHY"),
+            group("production", "api", &["10.0.0.5", "10.0.0.4"], "HEALTHY"),
+        ];
+
+        sort_output(&mut groups);
+
+        let order: Vec<(&str, &str, &str)> = groups
+            .iter()
+            .map(|group| {
+                (
+                    group.labels["__meta_cloudmap_namespace_name"].as_str(),
+                    group.labels["__meta_cloudmap_service_name"].as_str(),
+                    group.labels[HEALTH_STATUS_LABEL].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("production", "api", "HEALTHY"),
+                ("production", "web", "HEALTHY"),
+                ("production", "web", "UNHEALTHY"),
+                ("staging", "api", "HEALTHY"),
+            ]
+        );
+        assert_eq!(groups[0].targets, vec!["10.0.0.4", "10.0.0.5"]);
+        assert_eq!(groups[2].targets, vec!["10.0.0.1", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn test_labels_serialize_in_key_order() {
+        let mut labels = BTreeMap::new();
+        labels.insert("zone".to_string(), "a".to_string());
+        labels.insert(
+            "__meta_cloudmap_service_name".to_string(),
+            "web".to_string(),
+        );
+        labels.insert("job".to_string(), "web".to_string());
+        let target = PrometheusTarget {
+            targets: vec!["10.0.0.1".to_string()],
+            labels,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&target).unwrap(),
+            r#"{"targets":["10.0.0.1"],"labels":{"__meta_cloudmap_service_name":"web","job":"web","zone":"a"}}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discovered_targets_are_sorted() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_service("web"), test_service("api")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![
+                        test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+                        test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+                    ],
+                    next_token: None,
+                })
+            })
+        });
+        let discovery = create_mock_discovery(client, Config::default());
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(targets[0].labels["__meta_cloudmap_service_name"], "api");
+        assert_eq!(targets[1].labels["__meta_cloudmap_service_name"], "web");
+        for group in &targets {
+            assert_eq!(group.targets, vec!["10.0.0.1", "10.0.0.2"]);
+        }
+    }
+
+    #[test]
+    fn test_health_failure_threshold_label() {
+        let discovery = create_test_discovery();
+        // Still settable through the SDK, as on services created before the deprecation
+        #[allow(deprecated)]
+        let service = ServiceSummary::builder()
+            .id("srv-web")
+            .name("web")
+            .health_check_custom_config(
+                HealthCheckCustomConfig::builder()
+                    .failure_threshold(3)
+                    .build(),
+            )
+            .build();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &service,
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(
+            groups[0]
+                .labels
+                .get("__meta_cloudmap_health_failure_threshold"),
+            Some(&"3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_health_failure_threshold_label_omitted_without_custom_config() {
+        let discovery = create_test_discovery();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(
+            groups[0]
+                .labels
+                .get("__meta_cloudmap_health_failure_threshold"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_namespace_arn_label_omitted_when_unavailable() {
+        let discovery = create_test_discovery();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(groups[0].labels.get("__meta_cloudmap_namespace_arn"), None);
+    }
+
+    #[test]
+    fn test_excluded_instances_are_dropped() {
+        let discovery = create_test_discovery_with_config(Config {
+            exclude_instance_attribute: Some(("monitoring".to_string(), "disabled".to_string())),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                        ("monitoring", "disabled"),
+                    ],
+                ),
+                test_instance(
+                    "i-2",
+                    &[("AWS_INSTANCE_IPV4", "10.0.0.2"), ("monitoring", "enabled")],
+                ),
+                test_instance("i-3", &[("AWS_INSTANCE_IPV4", "10.0.0.3")]),
+            ],
+        );
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].targets, vec!["10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn test_service_with_only_excluded_instances_has_no_group() {
+        let discovery = create_test_discovery_with_config(Config {
+            exclude_instance_attribute: Some(("monitoring".to_string(), "disabled".to_string())),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[test_instance(
+                "i-1",
+                &[
+                    ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                    ("monitoring", "disabled"),
+                ],
+            )],
+        );
+
+        assert!(groups.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_ips_are_deduplicated_and_counted() {
+        let discovery = create_test_discovery_with_config(Config {
+            deduplicate_targets: true,
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance("blue-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+                test_instance("green-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+                test_instance("blue-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+                test_instance("green-2", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+            ],
+        );
+
+        assert_eq!(groups[0].targets, vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_duplicate_ip_count"),
+            Some(&"2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_duplicate_ips_kept_without_flag() {
+        let discovery = create_test_discovery();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance("blue-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+                test_instance("green-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+            ],
+        );
+
+        assert_eq!(groups[0].targets, vec!["10.0.0.1", "10.0.0.1"]);
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_duplicate_ip_count"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_transform_applied_to_service_groups() {
+        let transform = Transform::from_source(
+            r#"labels["team"] = "platform"; labels["__meta_cloudmap_service_name"] != "internal""#,
+        )
+        .unwrap();
+        let discovery = create_test_discovery().with_transform(transform);
+        let instances = [test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])];
+
+        let kept = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+        let dropped = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("internal"),
+            &instances,
+        );
+
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].labels.get("team"), Some(&"platform".to_string()));
+        assert!(dropped.is_empty());
+    }
+
+    #[test]
+    fn test_adapter_started_label_present_and_stable() {
+        let discovery = create_test_discovery_with_config(Config {
+            adapter_started_label: true,
+            ..Default::default()
+        });
+        let instances = [test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])];
+
+        let first = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+        let second = discovery.clone().build_service_targets(
+            &test_namespace("production"),
+            &test_service("api"),
+            &instances,
+        );
+
+        let started = first[0]
+            .labels
+            .get("__meta_cloudmap_adapter_started")
+            .unwrap();
+        assert!(DateTime::from_str(started, DateTimeFormat::DateTime).is_ok());
+        assert_eq!(
+            second[0].labels.get("__meta_cloudmap_adapter_started"),
+            Some(started)
+        );
+    }
+
+    #[test]
+    fn test_adapter_started_label_disabled_by_default() {
+        let discovery = create_test_discovery();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_adapter_started"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_namespace_type_label() {
+        let discovery = create_test_discovery();
+
+        for (namespace_type, label) in [
+            (NamespaceType::Http, "HTTP"),
+            (NamespaceType::DnsPrivate, "DNS_PRIVATE"),
+            (NamespaceType::DnsPublic, "DNS_PUBLIC"),
+        ] {
+            let namespace = NamespaceSummary::builder()
+                .id("ns-production")
+                .name("production")
+                .r#type(namespace_type)
+                .build();
+
+            let groups = discovery.build_service_targets(
+                &namespace,
+                &test_service("web"),
+                &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+            );
+
+            assert_eq!(groups[0].labels["__meta_cloudmap_namespace_type"], label);
+        }
+    }
+
+    #[test]
+    fn test_namespace_type_label_omitted_when_unreported() {
+        let discovery = create_test_discovery();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(groups[0].labels.get("__meta_cloudmap_namespace_type"), None);
+    }
+
+    #[test]
+    fn test_hosted_zone_id_label_for_dns_namespace() {
+        let discovery = create_test_discovery();
+        let namespace = NamespaceSummary::builder()
+            .id("ns-private")
+            .name("internal.local")
+            .r#type(NamespaceType::DnsPrivate)
+            .properties(
+                NamespaceProperties::builder()
+                    .dns_properties(
+                        DnsProperties::builder()
+                            .hosted_zone_id("Z0123456789")
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+
+        let groups = discovery.build_service_targets(
+            &namespace,
+            &test_service("web"),
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_hosted_zone_id"),
+            Some(&"Z0123456789".to_string())
+        );
+    }
+
+    #[test]
+    fn test_hosted_zone_id_label_omitted_for_http_namespace() {
+        let discovery = create_test_discovery();
+        let namespace = NamespaceSummary::builder()
+            .id("ns-http")
+            .name("production")
+            .r#type(NamespaceType::Http)
+            .properties(
+                NamespaceProperties::builder()
+                    .http_properties(HttpProperties::builder().http_name("production").build())
+                    .build(),
+            )
+            .build();
+
+        let groups = discovery.build_service_targets(
+            &namespace,
+            &test_service("web"),
+            &[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+        );
+
+        assert_eq!(groups[0].labels.get("__meta_cloudmap_hosted_zone_id"), None);
+    }
+
+    #[test]
+    fn test_multi_port_attribute_expands_targets() {
+        let discovery = create_test_discovery_with_config(Config {
+            multi_port_attribute: Some("ports".to_string()),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("node"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[("AWS_INSTANCE_IPV4", "10.0.0.1"), ("ports", "9100, 9200")],
+                ),
+                test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+            ],
+        );
+
+        assert_eq!(
+            groups[0].targets,
+            vec!["10.0.0.1:9100", "10.0.0.1:9200", "10.0.0.2"]
+        );
+    }
+
+    #[test]
+    fn test_multi_port_attribute_skips_invalid_ports() {
+        let discovery = create_test_discovery_with_config(Config {
+            multi_port_attribute: Some("ports".to_string()),
+            ..Default::default()
+        });
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("node"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[
+                        ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                        ("ports", "9100,abc,,0,70000"),
+                    ],
+                ),
+                test_instance(
+                    "i-2",
+                    &[("AWS_INSTANCE_IPV4", "10.0.0.2"), ("ports", "bogus")],
+                ),
+            ],
+        );
+
+        assert_eq!(groups[0].targets, vec!["10.0.0.1:9100", "10.0.0.2"]);
+    }
+
+    #[test]
+    fn test_no_namespaces_tolerated_by_default() {
+        let discovery = create_test_discovery_with_config(Config {
+            warn_on_no_namespaces: true,
+            ..Default::default()
+        });
+
+        assert!(discovery.check_namespaces_found(&[]).is_ok());
+    }
+
+    #[test]
+    fn test_no_namespaces_fails_when_configured() {
+        let discovery = create_test_discovery_with_config(Config {
+            fail_on_no_namespaces: true,
+            ..Default::default()
+        });
+
+        let result = discovery.check_namespaces_found(&[]);
+        assert!(matches!(result, Err(DiscoveryError::NoNamespaces)));
+        assert!(
+            discovery
+                .check_namespaces_found(&[test_namespace("production")])
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_scrape_attribute_can_opt_out_by_default() {
+        let discovery = create_test_discovery();
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+                test_instance(
+                    "i-2",
+                    &[("AWS_INSTANCE_IPV4", "10.0.0.2"), ("scrape", "false")],
+                ),
+            ],
+        );
+
+        assert_eq!(groups[0].targets, vec!["10.0.0.1"]);
+    }
+
+    #[test]
+    fn test_default_scrape_disabled_requires_opt_in() {
+        let discovery = create_test_discovery_with_config(Config {
+            default_scrape_enabled: false,
+            ..Default::default()
+        });
+
+        let opted_in = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &[
+                test_instance(
+                    "i-1",
+                    &[("AWS_INSTANCE_IPV4", "10.0.0.1"), ("scrape", "true")],
+                ),
+                test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+            ],
+        );
+        let not_opted_in = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("batch"),
+            &[test_instance("i-3", &[("AWS_INSTANCE_IPV4", "10.0.0.3")])],
+        );
+
+        assert_eq!(opted_in.len(), 1);
+        assert_eq!(opted_in[0].targets, vec!["10.0.0.1"]);
+        assert!(not_opted_in.is_empty());
+    }
+
+    #[test]
+    fn test_removed_target_lingers_for_retention_window() {
+        let discovery = create_test_discovery_with_config(Config {
+            target_retention_secs: 30,
+            ..Default::default()
+        });
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let group = |targets: &[&str]| {
+            discovery.create_prometheus_target(
+                "production",
+                "web",
+                targets.iter().map(|target| target.to_string()).collect(),
+            )
+        };
+
+        let initial = discovery.apply_retention(vec![group(&["10.0.0.1", "10.0.0.2"])], start);
+        assert_eq!(initial.len(), 1);
+
+        // 10.0.0.2 disappears but is still served as stale within the window
+        let removed_at = start + Duration::from_secs(10);
+        let within = discovery.apply_retention(vec![group(&["10.0.0.1"])], removed_at);
+        assert_eq!(within.len(), 2);
+        assert_eq!(within[0].targets, vec!["10.0.0.1"]);
+        assert_eq!(within[1].targets, vec!["10.0.0.2"]);
+        assert_eq!(
+            within[1].labels.get("__meta_cloudmap_stale_since"),
+            Some(&rfc3339(removed_at))
+        );
+        assert_eq!(
+            within[1].labels.get("__meta_cloudmap_service_name"),
+            Some(&"web".to_string())
+        );
+
+        // Once the window has passed the stale target is dropped
+        let after = discovery.apply_retention(
+            vec![group(&["10.0.0.1"])],
+            removed_at + Duration::from_secs(31),
+        );
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].targets, vec!["10.0.0.1"]);
+    }
+
+    #[test]
+    fn test_group_with_changed_labels_is_not_retained_as_stale() {
+        let discovery = create_test_discovery_with_config(Config {
+            target_retention_secs: 30,
+            ..Default::default()
+        });
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let group = |ratio: &str| {
+            let mut group = discovery.create_prometheus_target(
+                "production",
+                "web",
+                vec!["10.0.0.1".to_string()],
+            );
+            group
+                .labels
+                .insert(HEALTHY_RATIO_LABEL.to_string(), ratio.to_string());
+            group
+        };
+
+        discovery.apply_retention(vec![group("1.00")], start);
+        let next = discovery.apply_retention(vec![group("0.50")], start + Duration::from_secs(5));
+
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].labels[HEALTHY_RATIO_LABEL], "0.50");
+        assert!(!next[0].labels.contains_key(STALE_SINCE_LABEL));
+    }
+
+    #[test]
+    fn test_vanished_group_is_retained() {
+        let discovery = create_test_discovery_with_config(Config {
+            target_retention_secs: 30,
+            ..Default::default()
+        });
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let group =
+            discovery.create_prometheus_target("production", "web", vec!["10.0.0.1".to_string()]);
+
+        discovery.apply_retention(vec![group], start);
+        let retained = discovery.apply_retention(vec![], start + Duration::from_secs(5));
+
+        assert_eq!(retained.len(), 1);
+        assert_eq!(retained[0].targets, vec!["10.0.0.1"]);
+        assert!(
+            retained[0]
+                .labels
+                .contains_key("__meta_cloudmap_stale_since")
+        );
+    }
+
+    #[test]
+    fn test_namespaces_are_crawled_in_name_order() {
+        let mut namespaces = vec![
+            test_namespace("staging"),
+            test_namespace("alpha"),
+            test_namespace("production"),
+        ];
+
+        sort_namespaces(&mut namespaces);
+
+        let names: Vec<&str> = namespaces.iter().filter_map(|ns| ns.name()).collect();
+        assert_eq!(names, vec!["alpha", "production", "staging"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline_truncates_crawl_after_first_namespaces() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![
+                        test_namespace("staging"),
+                        test_namespace("alpha"),
+                        test_namespace("production"),
+                    ],
+                    next_token: None,
+                })
+            })
+        });
+        let crawled = Arc::new(Mutex::new(Vec::new()));
+        let recorded = crawled.clone();
+        client
+            .expect_list_services()
+            .returning(move |namespace_id, _| {
+                recorded.lock().unwrap().push(namespace_id.clone());
+                Box::pin(async move {
+                    // The alphabetically-last namespace stalls past the deadline
+                    if namespace_id == "ns-staging" {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                    Ok(Page {
+                        items: Vec::new(),
+                        next_token: None,
+                    })
+                })
+            });
+        let discovery = create_mock_discovery(
+            client,
+            Config {
+                discovery_timeout_seconds: Some(1),
+                ..Default::default()
+            },
+        );
+
+        let result = discovery.discover_targets().await;
+
+        assert!(matches!(result, Err(DiscoveryError::Timeout(_))));
+        assert_eq!(
+            *crawled.lock().unwrap(),
+            vec!["ns-alpha", "ns-production", "ns-staging"]
+        );
+    }
+
+    #[test]
+    fn test_group_last_changed_tracks_content_changes() {
+        let discovery = create_test_discovery_with_config(Config {
+            group_last_changed_label: true,
+            ..Default::default()
+        });
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let later = start + Duration::from_secs(60);
+        let groups = |web: &[&str]| {
+            let target = |service: &str, targets: &[&str]| {
+                discovery.create_prometheus_target(
+                    "production",
+                    service,
+                    targets.iter().map(|target| target.to_string()).collect(),
+                )
+            };
+            vec![target("api", &["10.0.1.1"]), target("web", web)]
+        };
+        let last_changed = |groups: &[PrometheusTarget], i: usize| {
+            groups[i].labels.get(LAST_CHANGED_LABEL).cloned().unwrap()
+        };
+
+        let mut first = groups(&["10.0.0.1"]);
+        discovery.stamp_last_changed(&mut first, start);
+        assert_eq!(last_changed(&first, 0), rfc3339(start));
+        assert_eq!(last_changed(&first, 1), rfc3339(start));
+
+        // Only web's targets change
+        let mut second = groups(&["10.0.0.1", "10.0.0.2"]);
+        discovery.stamp_last_changed(&mut second, later);
+        assert_eq!(last_changed(&second, 0), rfc3339(start));
+        assert_eq!(last_changed(&second, 1), rfc3339(later));
+    }
+
+    #[test]
+    fn test_group_last_changed_per_instance_group() {
+        let discovery = create_test_discovery_with_config(Config {
+            group_by: GroupBy::Instance,
+            group_last_changed_label: true,
+            ..Default::default()
+        });
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let later = start + Duration::from_secs(60);
+        let groups = |second_ip: &str| {
+            discovery.build_service_targets(
+                &test_namespace("production"),
+                &test_service("web"),
+                &[
+                    test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+                    test_instance("i-2", &[("AWS_INSTANCE_IPV4", second_ip)]),
+                ],
+            )
+        };
+        let last_changed = |groups: &[PrometheusTarget]| -> Vec<String> {
+            groups
+                .iter()
+                .map(|group| group.labels[LAST_CHANGED_LABEL].clone())
+                .collect()
+        };
+
+        let mut first = groups("10.0.0.2");
+        discovery.stamp_last_changed(&mut first, start);
+        let mut unchanged = groups("10.0.0.2");
+        discovery.stamp_last_changed(&mut unchanged, later);
+        // Only i-2 moves to a new address
+        let mut moved = groups("10.0.0.3");
+        discovery.stamp_last_changed(&mut moved, later + Duration::from_secs(60));
+
+        assert_eq!(first.len(), 2);
+        assert_eq!(
+            last_changed(&unchanged),
+            vec![rfc3339(start), rfc3339(start)]
+        );
+        assert_eq!(
+            last_changed(&moved),
+            vec![rfc3339(start), rfc3339(later + Duration::from_secs(60))]
+        );
+    }
+
+    #[test]
+    fn test_group_hash_label_detects_changes() {
+        let discovery = create_test_discovery_with_config(Config {
+            group_hash_label: true,
+            ..Default::default()
+        });
+        let build = |service: &str, ips: &[&str]| {
+            let instances: Vec<InstanceSummary> = ips
+                .iter()
+                .enumerate()
+                .map(|(i, ip)| test_instance(&format!("i-{}", i), &[("AWS_INSTANCE_IPV4", *ip)]))
+                .collect();
+            let mut groups = discovery.build_service_targets(
+                &test_namespace("production"),
+                &test_service(service),
+                &instances,
+            );
+            stamp_group_hash(&mut groups);
+            groups
+        };
+        let hash =
+            |groups: &[PrometheusTarget]| groups[0].labels.get(GROUP_HASH_LABEL).cloned().unwrap();
+
+        let original = hash(&build("web", &["10.0.0.1", "10.0.0.2"]));
+
+        assert_eq!(original.len(), 16);
+        assert_eq!(hash(&build("web", &["10.0.0.1", "10.0.0.2"])), original);
+        assert_eq!(hash(&build("web", &["10.0.0.2", "10.0.0.1"])), original);
+        assert_ne!(hash(&build("web", &["10.0.0.1", "10.0.0.3"])), original);
+        assert_ne!(hash(&build("api", &["10.0.0.1", "10.0.0.2"])), original);
+    }
+
+    #[tokio::test]
+    async fn test_group_hash_covers_labels_added_after_building() {
+        let hash = |discovery_method_label| async move {
+            let discovery = create_mock_discovery(
+                slow_instances_client(Duration::ZERO),
+                Config {
+                    group_hash_label: true,
+                    discovery_method_label,
+                    ..Default::default()
+                },
+            );
+            let targets = discovery.discover_targets().await.unwrap();
+            targets[0].labels[GROUP_HASH_LABEL].clone()
+        };
+
+        assert_ne!(hash(false).await, hash(true).await);
+    }
+
+    #[test]
+    fn test_max_labels_per_group_drops_excess_dynamic_labels() {
+        let discovery = create_test_discovery_with_config(Config {
+            fallback_labels: HashMap::from([
+                ("team".to_string(), "platform".to_string()),
+                ("env".to_string(), "prod".to_string()),
+                ("zone".to_string(), "a".to_string()),
+            ]),
+            max_labels_per_group: Some(5),
+            ..Default::default()
+        });
+        let instances = vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])];
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+
+        let mut keys: Vec<&str> = groups[0].labels.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                "__meta_cloudmap_namespace_id",
+                "__meta_cloudmap_namespace_name",
+                "__meta_cloudmap_service_id",
+                "__meta_cloudmap_service_name",
+                "env"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_labels_per_group_keeps_fixed_labels() {
+        let discovery = create_test_discovery_with_config(Config {
+            fallback_labels: HashMap::from([("team".to_string(), "platform".to_string())]),
+            max_labels_per_group: Some(1),
+            min_healthy_ratio: Some(0.5),
+            ..Default::default()
+        });
+        let (instances, health) = ratio_instances();
+
+        let groups = discovery.build_service_groups(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+            &health,
+            &[],
+        );
+
+        assert_eq!(groups[0].labels.len(), 6);
+        assert!(groups[0].labels.contains_key(HEALTH_STATUS_LABEL));
+        assert!(groups[0].labels.contains_key(HEALTHY_RATIO_LABEL));
+        assert!(!groups[0].labels.contains_key("team"));
+    }
+
+    #[test]
+    fn test_instance_port_appended_to_ip() {
+        let discovery = create_test_discovery();
+        let instances = vec![
+            test_instance(
+                "i-1",
+                &[
+                    ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                    ("AWS_INSTANCE_PORT", "8080"),
+                ],
+            ),
+            test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+            test_instance(
+                "i-3",
+                &[("address", "10.0.0.3:9090"), ("AWS_INSTANCE_PORT", "8080")],
+            ),
+        ];
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+
+        assert_eq!(
+            groups[0].targets,
+            vec!["10.0.0.1:8080", "10.0.0.2", "10.0.0.3:9090"]
+        );
+    }
+
+    #[test]
+    fn test_default_port_fallback() {
+        let discovery = create_test_discovery_with_config(Config {
+            default_port: Some(9100),
+            ..Default::default()
+        });
+        let instances = vec![
+            test_instance(
+                "i-1",
+                &[
+                    ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                    ("AWS_INSTANCE_PORT", "8080"),
+                ],
+            ),
+            test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+            test_instance("i-3", &[("address", "10.0.0.3:9090")]),
+            test_instance("i-4", &[("AWS_INSTANCE_IPV6", "2001:db8::4")]),
+        ];
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+
+        assert_eq!(
+            groups[0].targets,
+            vec![
+                "10.0.0.1:8080",
+                "10.0.0.2:9100",
+                "10.0.0.3:9090",
+                "[2001:db8::4]:9100"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_service_port_overrides() {
+        let discovery = create_test_discovery_with_config(Config {
+            default_port: Some(9100),
+            service_port_overrides: HashMap::from([("api".to_string(), 8080)]),
+            ..Default::default()
+        });
+        let instances = vec![
+            test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+            test_instance(
+                "i-2",
+                &[
+                    ("AWS_INSTANCE_IPV4", "10.0.0.2"),
+                    ("AWS_INSTANCE_PORT", "9090"),
+                ],
+            ),
+        ];
+        let targets = |service: &str| {
+            discovery.build_service_targets(
+                &test_namespace("production"),
+                &test_service(service),
+                &instances,
+            )[0]
+            .targets
+            .clone()
+        };
+
+        assert_eq!(targets("api"), vec!["10.0.0.1:8080", "10.0.0.2:9090"]);
+        assert_eq!(targets("web"), vec!["10.0.0.1:9100", "10.0.0.2:9090"]);
+    }
+
+    #[test]
+    fn test_job_label_from_attribute_or_service_name() {
+        let discovery = create_test_discovery_with_config(Config {
+            job_label_attribute: Some("job".to_string()),
+            ..Default::default()
+        });
+        let job = |instances: &[InstanceSummary]| {
+            discovery.build_service_targets(
+                &test_namespace("production"),
+                &test_service("web"),
+                instances,
+            )[0]
+            .labels
+            .get("job")
+            .cloned()
+        };
+
+        assert_eq!(
+            job(&[
+                test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+                test_instance(
+                    "i-2",
+                    &[("AWS_INSTANCE_IPV4", "10.0.0.2"), ("job", " node ")]
+                ),
+            ]),
+            Some("node".to_string())
+        );
+        assert_eq!(
+            job(&[test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])]),
+            Some("web".to_string())
+        );
+        assert_eq!(
+            job(&[test_instance(
+                "i-1",
+                &[("AWS_INSTANCE_IPV4", "10.0.0.1"), ("job", "  ")]
+            )]),
+            Some("web".to_string())
+        );
+    }
+
+    #[test]
+    fn test_job_label_disabled_by_default() {
+        let discovery = create_test_discovery();
+        let instances = vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])];
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+
+        assert_eq!(groups[0].labels.get("job"), None);
+    }
+
+    #[test]
+    fn test_operation_timeouts_fall_back_to_global() {
+        let discovery = create_test_discovery_with_config(Config {
+            aws_call_timeout_ms: Some(5000),
+            list_namespaces_timeout_ms: Some(1000),
+            list_instances_timeout_ms: Some(250),
+            ..Default::default()
+        });
+        let config = &discovery.config;
+
+        assert_eq!(
+            discovery.operation_timeout(config.list_namespaces_timeout_ms),
+            Some(Duration::from_millis(1000))
+        );
+        assert_eq!(
+            discovery.operation_timeout(config.list_services_timeout_ms),
+            Some(Duration::from_millis(5000))
+        );
+        assert_eq!(
+            discovery.operation_timeout(config.list_instances_timeout_ms),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(create_test_discovery().operation_timeout(None), None);
+    }
+
+    #[tokio::test]
+    async fn test_with_timeout_fails_slow_operations() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, std::io::Error>(())
+        };
+        let error = with_timeout("list_instances", Some(Duration::from_millis(10)), slow)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("list_instances timed out"));
+
+        let fast = async { Ok::<_, std::io::Error>(42) };
+        let value = with_timeout("list_services", Some(Duration::from_secs(10)), fast)
+            .await
+            .unwrap();
+        assert_eq!(value, 42);
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_are_paginated() {
+        let mut client = MockCloudMapApi::new();
+        client
+            .expect_list_namespaces()
+            .withf(|next_token| next_token.is_none())
+            .times(1)
+            .returning(|_| {
+                Box::pin(async {
+                    Ok(Page {
+                        items: vec![test_namespace("alpha")],
+                        next_token: Some("page-2".to_string()),
+                    })
+                })
+            });
+        client
+            .expect_list_namespaces()
+            .withf(|next_token| next_token.as_deref() == Some("page-2"))
+            .times(1)
+            .returning(|_| {
+                Box::pin(async {
+                    Ok(Page {
+                        items: vec![test_namespace("beta")],
+                        next_token: None,
+                    })
+                })
+            });
+        client.expect_list_services().returning(|namespace_id, _| {
+            let service = test_service(&format!("{}-web", namespace_id));
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![service],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+                    next_token: None,
+                })
+            })
+        });
+        let discovery = create_mock_discovery(client, Config::default());
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        let services: Vec<&str> = targets
+            .iter()
+            .filter_map(|group| group.labels.get("__meta_cloudmap_service_name"))
+            .map(String::as_str)
+            .collect();
+        assert_eq!(services, vec!["ns-alpha-web", "ns-beta-web"]);
+    }
+
+    #[tokio::test]
+    async fn test_services_are_paginated() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client
+            .expect_list_services()
+            .withf(|namespace_id, next_token| {
+                namespace_id == "ns-production" && next_token.is_none()
+            })
+            .times(1)
+            .returning(|_, _| {
+                Box::pin(async {
+                    Ok(Page {
+                        items: vec![test_service("web")],
+                        next_token: Some("page-2".to_string()),
+                    })
+                })
+            });
+        client
+            .expect_list_services()
+            .withf(|namespace_id, next_token| {
+                namespace_id == "ns-production" && next_token.as_deref() == Some("page-2")
+            })
+            .times(1)
+            .returning(|_, _| {
+                Box::pin(async {
+                    Ok(Page {
+                        items: vec![test_service("api")],
+                        next_token: None,
+                    })
+                })
+            });
+        client.expect_list_instances().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+                    next_token: None,
+                })
+            })
+        });
+        let discovery = create_mock_discovery(client, Config::default());
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        let services: Vec<&str> = targets
+            .iter()
+            .filter_map(|group| group.labels.get("__meta_cloudmap_service_name"))
+            .map(String::as_str)
+            .collect();
+        assert_eq!(services, vec!["api", "web"]);
+    }
+
+    #[test]
+    fn test_vip_group_emitted_alongside_instances() {
+        let discovery = create_test_discovery_with_config(Config {
+            vip_attribute: Some("AWS_ALIAS_DNS_NAME".to_string()),
+            ..Default::default()
+        });
+        let instances = vec![
+            test_instance("vip", &[("AWS_ALIAS_DNS_NAME", "web.elb.amazonaws.com")]),
+            test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+            test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+        ];
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].targets, vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(
+            groups[0].labels.get("__meta_cloudmap_role"),
+            Some(&"instance".to_string())
+        );
+        assert_eq!(groups[1].targets, vec!["web.elb.amazonaws.com"]);
+        assert_eq!(
+            groups[1].labels.get("__meta_cloudmap_role"),
+            Some(&"vip".to_string())
+        );
+        assert_eq!(
+            groups[1].labels.get("__meta_cloudmap_service_name"),
+            Some(&"web".to_string())
+        );
+    }
+
+    #[test]
+    fn test_aws_error_found_in_source_chain() {
+        let failure: ClientError = Box::new(AwsError::Throttled("Rate exceeded".into()));
+        let wrapped = AwsError::Other(failure);
+
+        assert!(matches!(AwsError::find(&wrapped), Some(AwsError::Other(_))));
+        assert!(matches!(
+            AwsError::find(std::error::Error::source(&wrapped).unwrap()),
+            Some(AwsError::Throttled(_))
+        ));
+        let unclassified: ClientError = "boom".into();
+        assert!(AwsError::find(&*unclassified).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_pagination_token_restarts_listing() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut client = MockCloudMapApi::new();
+        let namespace_calls = calls.clone();
+        client
+            .expect_list_namespaces()
+            .times(4)
+            .returning(move |next_token| {
+                let call = namespace_calls.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move {
+                    match (call, next_token.as_deref()) {
+                        (1, Some("page-2")) => Err(ClientError::from(ExpiredTokenError(
+                            "The NextToken has expired".to_string(),
+                        ))),
+                        (_, None) => Ok(Page {
+                            items: vec![test_namespace("alpha")],
+                            next_token: Some("page-2".to_string()),
+                        }),
+                        _ => Ok(Page {
+                            items: vec![test_namespace("beta")],
+                            next_token: None,
+                        }),
+                    }
+                })
+            });
+        let discovery = create_mock_discovery(
+            client,
+            Config {
+                pagination_restarts: 1,
+                ..Default::default()
+            },
+        );
+
+        let namespaces = discovery.list_namespaces().await.unwrap();
+
+        let names: Vec<&str> = namespaces.iter().filter_map(|ns| ns.name()).collect();
+        assert_eq!(names, vec!["alpha", "beta"]);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_expired_pagination_token_fails_without_restarts() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|next_token| {
+            Box::pin(async move {
+                match next_token {
+                    None => Ok(Page {
+                        items: vec![test_namespace("alpha")],
+                        next_token: Some("page-2".to_string()),
+                    }),
+                    Some(_) => Err(ClientError::from(ExpiredTokenError("expired".to_string()))),
+                }
+            })
+        });
+        let discovery = create_mock_discovery(client, Config::default());
+
+        let error = discovery.list_namespaces().await.unwrap_err();
+
+        let DiscoveryError::Pagination { operation, source } = error else {
+            panic!("expected a pagination error, got {error:?}");
+        };
+        assert_eq!(operation, "list_namespaces");
+        assert!(source.is::<ExpiredTokenError>());
+    }
+
+    #[tokio::test]
+    async fn test_discovery_error_names_failed_operation() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_service("web")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(|_, _| {
+            Box::pin(async {
+                Err(ClientError::from(AwsError::Throttled(
+                    "Rate exceeded".into(),
+                )))
+            })
+        });
+        let discovery = create_mock_discovery(client, Config::default());
+
+        let error = discovery.discover_targets().await.unwrap_err();
+
+        assert!(matches!(error, DiscoveryError::ListInstances { .. }));
+        assert!(matches!(error.aws_error(), Some(AwsError::Throttled(_))));
+        assert!(error.to_string().contains("Rate exceeded"));
+    }
+
+    #[tokio::test]
+    async fn test_instances_are_paginated() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_service("web")],
+                    next_token: None,
+                })
+            })
+        });
+        client
+            .expect_list_instances()
+            .withf(|service_id, next_token| service_id == "srv-web" && next_token.is_none())
+            .times(1)
+            .returning(|_, _| {
+                Box::pin(async {
+                    Ok(Page {
+                        items: vec![
+                            test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+                            test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+                        ],
+                        next_token: Some("page-2".to_string()),
+                    })
+                })
+            });
+        client
+            .expect_list_instances()
+            .withf(|service_id, next_token| {
+                service_id == "srv-web" && next_token.as_deref() == Some("page-2")
+            })
+            .times(1)
+            .returning(|_, _| {
+                Box::pin(async {
+                    Ok(Page {
+                        items: vec![test_instance("i-3", &[("AWS_INSTANCE_IPV4", "10.0.0.3")])],
+                        next_token: None,
+                    })
+                })
+            });
+        let discovery = create_mock_discovery(client, Config::default());
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].targets, vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn test_extract_addresses_in_priority_order() {
+        let attributes = HashMap::from([
+            ("address".to_string(), "10.0.0.3".to_string()),
+            ("IPv4".to_string(), "10.0.0.2".to_string()),
+            ("AWS_INSTANCE_IPV4".to_string(), "10.0.0.1".to_string()),
+            ("ip".to_string(), "10.0.0.1".to_string()),
+        ]);
+
+        let names = Config::default().ip_attribute_names;
+
+        assert_eq!(
+            extract_addresses(&names, &attributes),
+            vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+        assert!(extract_addresses(&names, &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_custom_ip_attribute_names() {
+        let discovery = create_test_discovery_with_config(Config {
+            ip_attribute_names: vec!["private_ip".to_string(), "AWS_INSTANCE_IPV4".to_string()],
+            ..Default::default()
+        });
+        let instances = vec![
+            test_instance(
+                "i-1",
+                &[
+                    ("private_ip", "10.1.0.1"),
+                    ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                ],
+            ),
+            test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+            test_instance("i-3", &[("IPv4", "10.0.0.3")]),
+        ];
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].targets, vec!["10.1.0.1", "10.0.0.2"]);
+    }
+
+    #[test]
+    fn test_collect_all_addresses() {
+        let instances = vec![test_instance(
+            "i-1",
+            &[
+                ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                ("address", "192.168.0.1"),
+            ],
+        )];
+        let targets = |collect_all_addresses| {
+            create_test_discovery_with_config(Config {
+                collect_all_addresses,
+                ..Default::default()
+            })
+            .build_service_targets(
+                &test_namespace("production"),
+                &test_service("web"),
+                &instances,
+            )[0]
+            .targets
+            .clone()
+        };
+
+        assert_eq!(targets(false), vec!["10.0.0.1"]);
+        assert_eq!(targets(true), vec!["10.0.0.1", "192.168.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_served_until_first_refresh() {
+        let path =
+            std::env::temp_dir().join(format!("cloudmap-sd-standby-{}.json", std::process::id()));
+        let snapshot = vec![PrometheusTarget {
+            targets: vec!["10.0.0.9".to_string()],
+            labels: service_labels("production", "web"),
+        }];
+        std::fs::write(&path, serde_json::to_string(&snapshot).unwrap()).unwrap();
+
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_service("web")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+                    next_token: None,
+                })
+            })
+        });
+        let discovery = create_mock_discovery(client, Config::default())
+            .with_snapshot(SnapshotStore::new(&path, Duration::ZERO));
+
+        assert_eq!(discovery.standby_targets(), Some(snapshot.clone()));
+        assert_eq!(discovery.last_good_targets(), Some(snapshot));
+
+        let targets = discovery.discover_targets().await.unwrap();
+        let persisted = SnapshotStore::new(&path, Duration::ZERO).load();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(discovery.standby_targets(), None);
+        assert_eq!(persisted, Some(targets));
+    }
+
+    #[test]
+    fn test_host_port_brackets_ipv6() {
+        assert_eq!(host_port("10.0.0.1", "9090"), "10.0.0.1:9090");
+        assert_eq!(host_port("::1", "9090"), "[::1]:9090");
+        assert_eq!(host_port("2001:db8::5", "80"), "[2001:db8::5]:80");
+    }
+
+    #[test]
+    fn test_ipv6_instance_targets() {
+        let discovery = create_test_discovery();
+        let instances = vec![
+            test_instance(
+                "i-1",
+                &[
+                    ("AWS_INSTANCE_IPV6", "2001:db8::1"),
+                    ("AWS_INSTANCE_PORT", "9090"),
+                ],
+            ),
+            test_instance("i-2", &[("AWS_INSTANCE_IPV6", "2001:db8::2")]),
+            test_instance(
+                "i-3",
+                &[
+                    ("address", "[2001:db8::3]:8080"),
+                    ("AWS_INSTANCE_PORT", "9090"),
+                ],
+            ),
+        ];
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+
         assert_eq!(
-            target.labels.get("__meta_cloudmap_namespace_name"),
-            Some(&"test-namespace".to_string())
+            groups[0].targets,
+            vec!["[2001:db8::1]:9090", "2001:db8::2", "[2001:db8::3]:8080"]
+        );
+    }
+
+    fn slow_instances_client(delay: Duration) -> MockCloudMapApi {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_service("web")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(move |_, _| {
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                Ok(Page {
+                    items: vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])],
+                    next_token: None,
+                })
+            })
+        });
+        client
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_crawl_is_counted() {
+        let discovery = create_mock_discovery(
+            slow_instances_client(Duration::from_millis(500)),
+            Config {
+                max_crawl_duration_warn_ms: Some(100),
+                ..Default::default()
+            },
+        );
+
+        assert!(discovery.discover_targets().await.is_ok());
+        assert_eq!(discovery.slow_crawl_count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_discovery_times_out() {
+        let discovery = create_mock_discovery(
+            slow_instances_client(Duration::from_secs(60)),
+            Config {
+                discovery_timeout_seconds: Some(1),
+                ..Default::default()
+            },
+        );
+
+        let result = discovery.discover_targets().await;
+
+        assert!(matches!(
+            result,
+            Err(DiscoveryError::Timeout(timeout)) if timeout == Duration::from_secs(1)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_discovery_within_timeout_succeeds() {
+        let discovery = create_mock_discovery(
+            slow_instances_client(Duration::from_millis(500)),
+            Config {
+                discovery_timeout_seconds: Some(1),
+                ..Default::default()
+            },
+        );
+
+        assert!(discovery.discover_targets().await.is_ok());
+    }
+
+    /// Client expecting exactly `calls` crawls of an empty account
+    fn counted_crawls_client(calls: usize) -> MockCloudMapApi {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().times(calls).returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: Vec::<NamespaceSummary>::new(),
+                    next_token: None,
+                })
+            })
+        });
+        client
+    }
+
+    fn empty_then_populated_client(empty_calls: usize) -> MockCloudMapApi {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_service("web")],
+                    next_token: None,
+                })
+            })
+        });
+        let calls = AtomicUsize::new(0);
+        client.expect_list_instances().returning(move |_, _| {
+            let items = if calls.fetch_add(1, Ordering::SeqCst) < empty_calls {
+                Vec::new()
+            } else {
+                vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")])]
+            };
+            Box::pin(async move {
+                Ok(Page {
+                    items,
+                    next_token: None,
+                })
+            })
+        });
+        client
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_empty_instances_retried_until_populated() {
+        let discovery = create_mock_discovery(
+            empty_then_populated_client(2),
+            Config {
+                retry_empty_instances: 3,
+                ..Default::default()
+            },
+        );
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].targets, vec!["10.0.0.1"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_empty_instances_not_retried_by_default() {
+        let discovery = create_mock_discovery(empty_then_populated_client(1), Config::default());
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert!(targets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_discovery_recorded_in_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let discovery = create_mock_discovery(empty_then_populated_client(0), Config::default())
+            .with_metrics(metrics.clone());
+
+        discovery.discover_targets().await.unwrap();
+        discovery.discover_targets().await.unwrap();
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("cloudmap_sd_discovery_runs_total{result=\"success\"} 2\n"));
+        assert!(rendered.contains("cloudmap_sd_discovery_duration_seconds_count 2\n"));
+        for operation in ["list_namespaces", "list_services", "list_instances"] {
+            let line = format!(
+                "cloudmap_sd_aws_call_duration_seconds_count{{operation=\"{}\"}} 2\n",
+                operation
+            );
+            assert!(rendered.contains(&line), "{}", line);
+        }
+        assert!(rendered.contains("cloudmap_sd_namespaces 1\n"));
+        assert!(rendered.contains("cloudmap_sd_services 1\n"));
+        assert!(rendered.contains("cloudmap_sd_instances 1\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cached_results_served_within_ttl() {
+        let discovery = create_mock_discovery(
+            counted_crawls_client(1),
+            Config {
+                cache_ttl_seconds: 30,
+                ..Default::default()
+            },
+        );
+
+        assert!(discovery.discover_targets().await.is_ok());
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(discovery.clone().discover_targets().await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cache_refreshed_after_ttl() {
+        let discovery = create_mock_discovery(
+            counted_crawls_client(2),
+            Config {
+                cache_ttl_seconds: 30,
+                ..Default::default()
+            },
+        );
+
+        assert!(discovery.discover_targets().await.is_ok());
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(discovery.discover_targets().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_one_crawl() {
+        let discovery = create_mock_discovery(
+            counted_crawls_client(1),
+            Config {
+                cache_ttl_seconds: 30,
+                ..Default::default()
+            },
+        );
+
+        let (first, second) =
+            tokio::join!(discovery.discover_targets(), discovery.discover_targets());
+
+        assert!(first.is_ok() && second.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_zero_ttl_disables_cache() {
+        let discovery = create_mock_discovery(counted_crawls_client(2), Config::default());
+
+        assert!(discovery.discover_targets().await.is_ok());
+        assert!(discovery.discover_targets().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cached_targets_empty_before_first_refresh() {
+        let discovery = create_mock_discovery(counted_crawls_client(0), Config::default());
+
+        assert!(discovery.cached_targets().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_cache_is_shared_by_clones() {
+        let discovery =
+            create_mock_discovery(slow_instances_client(Duration::ZERO), Config::default());
+        let reader = discovery.clone();
+
+        discovery.refresh_cache().await.unwrap();
+        let targets = reader.cached_targets().await;
+
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].targets, vec!["10.0.0.1".to_string()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresh_loop_fills_cache() {
+        let discovery =
+            create_mock_discovery(slow_instances_client(Duration::ZERO), Config::default());
+
+        discovery.spawn_refresh_loop(Duration::from_secs(60));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        assert_eq!(discovery.cached_targets().await.len(), 1);
+    }
+
+    /// Client listing a single namespace with no services
+    fn namespace_client(name: &'static str) -> MockCloudMapApi {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(move |_| {
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![test_namespace(name)],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: Vec::<ServiceSummary>::new(),
+                    next_token: None,
+                })
+            })
+        });
+        client
+    }
+
+    #[tokio::test]
+    async fn test_refresh_client_swaps_client_for_all_clones() {
+        let discovery = create_mock_discovery(namespace_client("before"), Config::default());
+        let other = discovery.clone();
+        let mut factory = MockClientFactory::new();
+        factory.expect_build().times(1).returning(|| {
+            let client: Arc<dyn CloudMapApi> = Arc::new(namespace_client("after"));
+            Box::pin(async move { Ok(client) })
+        });
+
+        discovery.refresh_client(&factory).await.unwrap();
+        let topology = other.discover_topology().await.unwrap();
+
+        assert_eq!(topology[0].name, "after");
+    }
+
+    #[tokio::test]
+    async fn test_failed_client_refresh_keeps_current_client() {
+        let discovery = create_mock_discovery(namespace_client("before"), Config::default());
+        let mut factory = MockClientFactory::new();
+        factory.expect_build().returning(|| {
+            Box::pin(async { Err(ClientError::from("no AWS credentials provider")) })
+        });
+
+        assert!(discovery.refresh_client(&factory).await.is_err());
+        let topology = discovery.discover_topology().await.unwrap();
+
+        assert_eq!(topology[0].name, "before");
+    }
+
+    /// Client listing one `production` namespace with a `web` service backed
+    /// by a single instance at `ip`
+    fn region_client(ip: &'static str) -> MockCloudMapApi {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_service("web")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(move |_, _| {
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![test_instance("i-1", &[("AWS_INSTANCE_IPV4", ip)])],
+                    next_token: None,
+                })
+            })
+        });
+        client
+    }
+
+    #[tokio::test]
+    async fn test_regions_are_merged_with_region_label() {
+        let discovery = create_mock_discovery(counted_crawls_client(0), Config::default())
+            .with_regions(vec![
+                ("us-east-1".to_string(), Arc::new(region_client("10.0.0.1"))),
+                ("eu-west-1".to_string(), Arc::new(region_client("10.1.0.1"))),
+            ]);
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].targets, vec!["10.1.0.1"]);
+        assert_eq!(targets[0].labels[REGION_LABEL], "eu-west-1");
+        assert_eq!(targets[1].targets, vec!["10.0.0.1"]);
+        assert_eq!(targets[1].labels[REGION_LABEL], "us-east-1");
+        for target in &targets {
+            assert_eq!(target.labels["__meta_cloudmap_service_name"], "web");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_region_label_absent_without_regions() {
+        let discovery = create_mock_discovery(region_client("10.0.0.1"), Config::default());
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(targets.len(), 1);
+        assert!(!targets[0].labels.contains_key(REGION_LABEL));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_client_rebuilds_every_region() {
+        let discovery = create_mock_discovery(counted_crawls_client(0), Config::default())
+            .with_regions(vec![
+                ("us-east-1".to_string(), Arc::new(counted_crawls_client(0))),
+                ("eu-west-1".to_string(), Arc::new(counted_crawls_client(0))),
+            ]);
+        let mut factory = MockClientFactory::new();
+        factory.expect_build().times(1).returning(|| {
+            let client: Arc<dyn CloudMapApi> = Arc::new(counted_crawls_client(0));
+            Box::pin(async move { Ok(client) })
+        });
+        factory
+            .expect_build_in_region()
+            .times(2)
+            .returning(|region| {
+                let ip = if region == "us-east-1" {
+                    "10.0.0.1"
+                } else {
+                    "10.1.0.1"
+                };
+                let client: Arc<dyn CloudMapApi> = Arc::new(region_client(ip));
+                Box::pin(async move { Ok(client) })
+            });
+
+        discovery.refresh_client(&factory).await.unwrap();
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(targets[0].targets, vec!["10.1.0.1"]);
+        assert_eq!(targets[1].targets, vec!["10.0.0.1"]);
+    }
+
+    /// Client serving the `web` service of `production` through
+    /// `DiscoverInstances` only, with one healthy and one unhealthy instance
+    fn discover_instances_client(service: ServiceSummary) -> MockCloudMapApi {
+        use aws_sdk_servicediscovery::types::{HealthStatus, HttpInstanceSummary};
+
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(move |_, _| {
+            let service = service.clone();
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![service],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().never();
+        client.expect_get_instances_health_status().never();
+        client
+            .expect_discover_instances()
+            .withf(|namespace_name, service_name, health_status| {
+                namespace_name == "production"
+                    && service_name == "web"
+                    && *health_status == Some(HealthStatusFilter::All)
+            })
+            .returning(|_, _, _| {
+                let instances = [
+                    ("i-1", "10.0.0.1", HealthStatus::Healthy),
+                    ("i-2", "10.0.0.2", HealthStatus::Unhealthy),
+                ]
+                .into_iter()
+                .map(|(id, ip, health)| {
+                    HttpInstanceSummary::builder()
+                        .instance_id(id)
+                        .namespace_name("production")
+                        .service_name("web")
+                        .health_status(health)
+                        .attributes("AWS_INSTANCE_IPV4", ip)
+                        .attributes("AWS_INSTANCE_PORT", "9090")
+                        .build()
+                })
+                .collect();
+                Box::pin(async move { Ok(instances) })
+            });
+        client
+    }
+
+    #[tokio::test]
+    async fn test_discover_mode_extracts_targets() {
+        let discovery = create_mock_discovery(
+            discover_instances_client(test_service("web")),
+            Config {
+                discovery_mode: DiscoveryMode::Discover,
+                discover_health_status: Some(DiscoverHealthStatus::All),
+                ..Default::default()
+            },
+        );
+
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].targets, vec!["10.0.0.1:9090", "10.0.0.2:9090"]);
+        assert_eq!(targets[0].labels["__meta_cloudmap_service_name"], "web");
+    }
+
+    #[tokio::test]
+    async fn test_discovery_method_label_for_discover_mode() {
+        let discovery = create_mock_discovery(
+            discover_instances_client(test_service("web")),
+            Config {
+                discovery_mode: DiscoveryMode::Discover,
+                discover_health_status: Some(DiscoverHealthStatus::All),
+                discovery_method_label: true,
+                ..Default::default()
+            },
         );
+
+        let targets = discovery.discover_targets().await.unwrap();
+
         assert_eq!(
-            target.labels.get("__meta_cloudmap_service_name"),
-            Some(&"test-service".to_string())
+            targets[0].labels.get("__meta_cloudmap_discovery_method"),
+            Some(&"discover".to_string())
         );
     }
 
-    #[test]
-    fn test_create_prometheus_target_with_empty_ips() {
-        let discovery = create_test_discovery();
+    #[tokio::test]
+    async fn test_discover_mode_uses_returned_health() {
+        let service = ServiceSummary::builder()
+            .id("srv-web")
+            .name("web")
+            .health_check_custom_config(HealthCheckCustomConfig::builder().build())
+            .build();
+        let discovery = create_mock_discovery(
+            discover_instances_client(service),
+            Config {
+                discovery_mode: DiscoveryMode::Discover,
+                discover_health_status: Some(DiscoverHealthStatus::All),
+                only_healthy: true,
+                ..Default::default()
+            },
+        );
 
-        let target = discovery.create_prometheus_target("test-namespace", "test-service", vec![]);
+        let targets = discovery.discover_targets().await.unwrap();
 
-        assert!(target.targets.is_empty());
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].targets, vec!["10.0.0.1:9090"]);
+        assert_eq!(targets[0].labels[HEALTH_STATUS_LABEL], "HEALTHY");
+    }
+
+    #[test]
+    fn test_discovery_mode_deserializes_lowercase() {
         assert_eq!(
-            target.labels.get("__meta_cloudmap_namespace_name"),
-            Some(&"test-namespace".to_string())
+            serde_json::from_str::<DiscoveryMode>("\"discover\"").unwrap(),
+            DiscoveryMode::Discover
         );
         assert_eq!(
-            target.labels.get("__meta_cloudmap_service_name"),
-            Some(&"test-service".to_string())
+            serde_json::from_str::<DiscoverHealthStatus>("\"healthy_or_else_all\"").unwrap(),
+            DiscoverHealthStatus::HealthyOrElseAll
+        );
+    }
+
+    /// Client whose first `list_namespaces` call succeeds and every later call fails
+    fn succeed_then_fail_client() -> MockCloudMapApi {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(move |_| {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                if call == 0 {
+                    Ok(Page {
+                        items: Vec::<NamespaceSummary>::new(),
+                        next_token: None,
+                    })
+                } else {
+                    Err(ClientError::from("AccessDenied"))
+                }
+            })
+        });
+        client
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_before_first_success() {
+        let discovery = create_mock_discovery(succeed_then_fail_client(), Config::default());
+
+        assert!(!discovery.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_ready_after_success_until_failure_threshold() {
+        let discovery = create_mock_discovery(
+            succeed_then_fail_client(),
+            Config {
+                ready_failure_threshold: 2,
+                ..Default::default()
+            },
+        );
+
+        assert!(discovery.discover_targets().await.is_ok());
+        assert!(discovery.is_ready());
+
+        assert!(discovery.discover_targets().await.is_err());
+        assert!(discovery.is_ready());
+
+        assert!(discovery.discover_targets().await.is_err());
+        assert!(!discovery.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_zero_failure_threshold_stays_ready() {
+        let discovery = create_mock_discovery(succeed_then_fail_client(), Config::default());
+
+        assert!(discovery.discover_targets().await.is_ok());
+        for _ in 0..5 {
+            assert!(discovery.discover_targets().await.is_err());
+        }
+        assert!(discovery.is_ready());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fast_crawl_is_not_counted() {
+        let discovery = create_mock_discovery(
+            slow_instances_client(Duration::from_millis(50)),
+            Config {
+                max_crawl_duration_warn_ms: Some(100),
+                ..Default::default()
+            },
         );
+
+        assert!(discovery.discover_targets().await.is_ok());
+        assert_eq!(discovery.slow_crawl_count(), 0);
     }
 
     #[test]
-    fn test_create_prometheus_target_with_port() {
-        let discovery = create_test_discovery();
+    fn test_unhealthy_instances_are_skipped() {
+        let discovery = create_test_discovery_with_config(Config {
+            only_healthy: true,
+            ..Default::default()
+        });
+        let instances = vec![
+            test_instance("i-1", &[("AWS_INSTANCE_IPV4", "10.0.0.1")]),
+            test_instance("i-2", &[("AWS_INSTANCE_IPV4", "10.0.0.2")]),
+            test_instance("i-3", &[("AWS_INSTANCE_IPV4", "10.0.0.3")]),
+        ];
+        let health = HashMap::from([
+            ("i-1".to_string(), "HEALTHY".to_string()),
+            ("i-2".to_string(), "UNHEALTHY".to_string()),
+        ]);
 
-        let target =
-            discovery.create_prometheus_target("ns1", "svc1", vec!["192.168.0.1:8080".to_string()]);
+        let groups = discovery.build_service_groups(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+            &health,
+            &[],
+        );
 
-        assert_eq!(target.targets, vec!["192.168.0.1:8080"]);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].targets, vec!["10.0.0.1"]);
         assert_eq!(
-            target.labels.get("__meta_cloudmap_namespace_name"),
-            Some(&"ns1".to_string())
+            groups[0].labels.get(HEALTH_STATUS_LABEL),
+            Some(&"HEALTHY".to_string())
         );
+        assert_eq!(groups[1].targets, vec!["10.0.0.3"]);
         assert_eq!(
-            target.labels.get("__meta_cloudmap_service_name"),
-            Some(&"svc1".to_string())
+            groups[1].labels.get(HEALTH_STATUS_LABEL),
+            Some(&"UNKNOWN".to_string())
         );
     }
 
+    fn ratio_instances() -> (Vec<InstanceSummary>, HashMap<String, String>) {
+        let instances = (1..=4)
+            .map(|i| {
+                let ip = format!("10.0.0.{}", i);
+                test_instance(&format!("i-{}", i), &[("AWS_INSTANCE_IPV4", ip.as_str())])
+            })
+            .collect();
+        let health = HashMap::from([
+            ("i-1".to_string(), "HEALTHY".to_string()),
+            ("i-2".to_string(), "HEALTHY".to_string()),
+            ("i-3".to_string(), "HEALTHY".to_string()),
+            ("i-4".to_string(), "UNHEALTHY".to_string()),
+        ]);
+        (instances, health)
+    }
+
     #[test]
-    fn test_prometheus_target_serialization() {
-        let mut labels = HashMap::new();
-        labels.insert(
-            "__meta_cloudmap_namespace_name".to_string(),
-            "ns1".to_string(),
+    fn test_service_above_min_healthy_ratio_is_served() {
+        let discovery = create_test_discovery_with_config(Config {
+            min_healthy_ratio: Some(0.5),
+            ..Default::default()
+        });
+        let (instances, health) = ratio_instances();
+
+        let groups = discovery.build_service_groups(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+            &health,
+            &[],
         );
-        labels.insert(
-            "__meta_cloudmap_service_name".to_string(),
-            "svc1".to_string(),
+
+        assert!(!groups.is_empty());
+        assert!(
+            groups
+                .iter()
+                .all(|group| group.labels.get(HEALTHY_RATIO_LABEL) == Some(&"0.75".to_string()))
         );
+    }
 
-        let target = PrometheusTarget {
-            targets: vec!["192.168.0.1:8080".to_string()],
-            labels,
-        };
+    #[test]
+    fn test_service_below_min_healthy_ratio_is_dropped() {
+        let discovery = create_test_discovery_with_config(Config {
+            min_healthy_ratio: Some(0.8),
+            ..Default::default()
+        });
+        let (instances, health) = ratio_instances();
 
-        let json = serde_json::to_string(&target).unwrap();
-        let deserialized: PrometheusTarget = serde_json::from_str(&json).unwrap();
+        let groups = discovery.build_service_groups(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+            &health,
+            &[],
+        );
 
-        assert_eq!(target, deserialized);
+        assert!(groups.is_empty());
     }
 
     #[test]
-    fn test_config_creation() {
-        let config = Config {
-            region: Some("us-east-1".to_string()),
-            namespace: Some("production".to_string()),
-        };
+    fn test_service_without_healthy_instances_is_dropped() {
+        let discovery = create_test_discovery_with_config(Config {
+            only_healthy: true,
+            drop_unhealthy_services: true,
+            ..Default::default()
+        });
+        let (instances, _) = ratio_instances();
+        let health = instances
+            .iter()
+            .map(|instance| (instance.id().unwrap().to_string(), "UNHEALTHY".to_string()))
+            .collect();
 
-        assert_eq!(config.region, Some("us-east-1".to_string()));
-        assert_eq!(config.namespace, Some("production".to_string()));
+        let groups = discovery.build_service_groups(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+            &health,
+            &[],
+        );
+
+        assert!(groups.is_empty());
     }
 
     #[test]
-    fn test_config_with_none_values() {
-        let config = Config {
-            region: None,
-            namespace: None,
-        };
+    fn test_partially_healthy_service_is_kept() {
+        let discovery = create_test_discovery_with_config(Config {
+            only_healthy: true,
+            drop_unhealthy_services: true,
+            ..Default::default()
+        });
+        let (instances, health) = ratio_instances();
 
-        assert_eq!(config.region, None);
-        assert_eq!(config.namespace, None);
+        let groups = discovery.build_service_groups(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+            &health,
+            &[],
+        );
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].targets, vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
     }
 
     #[test]
-    fn test_config_clone() {
-        let config = Config {
-            region: Some("us-west-2".to_string()),
-            namespace: Some("test".to_string()),
-        };
+    fn test_min_healthy_ratio_ignored_without_health_data() {
+        let discovery = create_test_discovery_with_config(Config {
+            min_healthy_ratio: Some(0.8),
+            ..Default::default()
+        });
+        let (instances, _) = ratio_instances();
 
-        let cloned_config = config.clone();
-        assert_eq!(config.region, cloned_config.region);
-        assert_eq!(config.namespace, cloned_config.namespace);
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].labels.get(HEALTHY_RATIO_LABEL), None);
     }
 
     #[test]
-    fn test_prometheus_target_with_multiple_labels() {
-        let discovery = create_test_discovery();
+    fn test_health_status_map_normalizes_custom_values() {
+        let discovery = create_test_discovery_with_config(Config {
+            health_status_map: HashMap::from([
+                ("OK".to_string(), "HEALTHY".to_string()),
+                ("DOWN".to_string(), "UNHEALTHY".to_string()),
+            ]),
+            ..Default::default()
+        });
 
-        let target = discovery.create_prometheus_target(
-            "production-namespace",
-            "web-service",
-            vec![
-                "10.0.1.1".to_string(),
-                "10.0.1.2".to_string(),
-                "10.0.1.3".to_string(),
-            ],
+        assert_eq!(discovery.normalize_health("OK"), "HEALTHY");
+        assert_eq!(discovery.normalize_health("DOWN"), "UNHEALTHY");
+        assert_eq!(discovery.normalize_health("UNHEALTHY"), "UNHEALTHY");
+        assert_eq!(discovery.normalize_health("healthy"), "HEALTHY");
+        assert_eq!(discovery.normalize_health("DEGRADED"), "UNKNOWN");
+    }
+
+    #[tokio::test]
+    async fn test_health_status_fetched_in_batches() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            Box::pin(async {
+                Ok(Page {
+                    items: vec![test_namespace("production")],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            let service = ServiceSummary::builder()
+                .id("srv-web")
+                .name("web")
+                .health_check_custom_config(HealthCheckCustomConfig::builder().build())
+                .build();
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![service],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(|_, _| {
+            let instances: Vec<InstanceSummary> = (0..25)
+                .map(|i| {
+                    let ip = format!("10.0.0.{}", i);
+                    test_instance(&format!("i-{}", i), &[("AWS_INSTANCE_IPV4", ip.as_str())])
+                })
+                .collect();
+            Box::pin(async move {
+                Ok(Page {
+                    items: instances,
+                    next_token: None,
+                })
+            })
+        });
+        client
+            .expect_get_instances_health_status()
+            .withf(|service_id, instance_ids, _| {
+                service_id == "srv-web" && instance_ids.len() <= HEALTH_STATUS_BATCH_SIZE
+            })
+            .times(3)
+            .returning(|_, instance_ids, _| {
+                // The last instance is unhealthy, the rest are healthy
+                let statuses: Vec<(String, String)> = instance_ids
+                    .into_iter()
+                    .map(|id| {
+                        let status = if id == "i-24" { "UNHEALTHY" } else { "HEALTHY" };
+                        (id, status.to_string())
+                    })
+                    .collect();
+                Box::pin(async move {
+                    Ok(Page {
+                        items: statuses,
+                        next_token: None,
+                    })
+                })
+            });
+        let discovery = create_mock_discovery(
+            client,
+            Config {
+                only_healthy: true,
+                ..Default::default()
+            },
         );
 
-        assert_eq!(target.targets.len(), 3);
-        assert!(target.targets.contains(&"10.0.1.1".to_string()));
-        assert!(target.targets.contains(&"10.0.1.2".to_string()));
-        assert!(target.targets.contains(&"10.0.1.3".to_string()));
+        let targets = discovery.discover_targets().await.unwrap();
 
-        assert_eq!(target.labels.len(), 2);
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].targets.len(), 24);
+        assert!(!targets[0].targets.contains(&"10.0.0.24".to_string()));
         assert_eq!(
-            target.labels.get("__meta_cloudmap_namespace_name"),
-            Some(&"production-namespace".to_string())
+            targets[0].labels.get(HEALTH_STATUS_LABEL),
+            Some(&"HEALTHY".to_string())
         );
+    }
+
+    #[test]
+    fn test_service_weight_total_label() {
+        let discovery = create_test_discovery_with_config(Config {
+            service_weight_total_label: true,
+            collect_all_addresses: true,
+            ..Default::default()
+        });
+        let instances = vec![
+            test_instance(
+                "i-1",
+                &[
+                    ("AWS_INSTANCE_IPV4", "10.0.0.1"),
+                    ("address", "192.168.0.1"),
+                    ("AWS_INSTANCE_WEIGHT", "3"),
+                ],
+            ),
+            test_instance(
+                "i-2",
+                &[
+                    ("AWS_INSTANCE_IPV4", "10.0.0.2"),
+                    ("AWS_INSTANCE_WEIGHT", "5"),
+                ],
+            ),
+            test_instance(
+                "i-3",
+                &[
+                    ("AWS_INSTANCE_IPV4", "10.0.0.3"),
+                    ("AWS_INSTANCE_WEIGHT", "heavy"),
+                ],
+            ),
+        ];
+
+        let groups = discovery.build_service_targets(
+            &test_namespace("production"),
+            &test_service("web"),
+            &instances,
+        );
+
         assert_eq!(
-            target.labels.get("__meta_cloudmap_service_name"),
-            Some(&"web-service".to_string())
+            groups[0].labels.get(WEIGHT_TOTAL_LABEL),
+            Some(&"8".to_string())
         );
     }
 
     #[test]
-    fn test_config_with_namespace_filter() {
-        let config = Config {
-            region: Some("us-west-2".to_string()),
-            namespace: Some("production".to_string()),
-        };
+    fn test_weight_total_zero_and_absent() {
+        let zero = HashMap::from([("AWS_INSTANCE_WEIGHT".to_string(), "0".to_string())]);
+        let unweighted = HashMap::new();
 
-        assert_eq!(config.region, Some("us-west-2".to_string()));
-        assert_eq!(config.namespace, Some("production".to_string()));
+        assert_eq!(weight_total([&zero].into_iter()), Some(0));
+        assert_eq!(weight_total([&unweighted].into_iter()), None);
     }
 
-    #[test]
-    fn test_config_without_namespace_filter() {
-        let config = Config {
-            region: Some("us-west-2".to_string()),
-            namespace: None,
-        };
+    /// Runs discovery end to end against LocalStack, through the endpoint
+    /// override. Start it with `docker compose --profile localstack up -d`
+    /// and run `make test-localstack`; `LOCALSTACK_ENDPOINT` overrides the
+    /// default `http://localhost:4566`.
+    #[tokio::test]
+    #[ignore = "needs a running LocalStack; see make test-localstack"]
+    async fn test_discovery_against_localstack() {
+        let endpoint_url = std::env::var("LOCALSTACK_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:4566".to_string());
+        let sdk_config = crate::client::AwsClientFactory::new(
+            Some("us-east-1".to_string()),
+            SharedAsyncSleep::new(TokioSleep),
+        )
+        .with_endpoint_url(Some(endpoint_url))
+        .load_sdk_config()
+        .await;
+        let client = crate::client::build_client(&sdk_config).unwrap();
 
-        assert_eq!(config.region, Some("us-west-2".to_string()));
-        assert_eq!(config.namespace, None);
+        // A fresh namespace per run, so reruns against the same container
+        // don't see each other's instances
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let namespace_name = format!("sd-test-{}", nanos);
+        client
+            .create_http_namespace()
+            .name(&namespace_name)
+            .send()
+            .await
+            .unwrap();
+        let namespaces = client.list_namespaces().send().await.unwrap();
+        let namespace_id = namespaces
+            .namespaces()
+            .iter()
+            .find(|namespace| namespace.name() == Some(namespace_name.as_str()))
+            .and_then(|namespace| namespace.id())
+            .expect("created namespace is listed")
+            .to_string();
+        let service = client
+            .create_service()
+            .name("api")
+            .namespace_id(&namespace_id)
+            .send()
+            .await
+            .unwrap();
+        let service_id = service.service().and_then(|service| service.id()).unwrap();
+        client
+            .register_instance()
+            .service_id(service_id)
+            .instance_id("i-1")
+            .attributes("AWS_INSTANCE_IPV4", "10.0.0.1")
+            .attributes("AWS_INSTANCE_PORT", "8080")
+            .send()
+            .await
+            .unwrap();
+
+        let discovery = Discovery::new(
+            client,
+            Config {
+                region: Some("us-east-1".to_string()),
+                namespaces: vec![namespace_name.clone()],
+                ..Default::default()
+            },
+            SharedAsyncSleep::new(TokioSleep),
+        );
+        let targets = discovery.discover_targets().await.unwrap();
+
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].targets, vec!["10.0.0.1:8080"]);
+        assert_eq!(
+            targets[0].labels["__meta_cloudmap_namespace_name"],
+            namespace_name
+        );
+        assert_eq!(targets[0].labels["__meta_cloudmap_service_name"], "api");
     }
 }
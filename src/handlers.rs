@@ -6,24 +6,374 @@
 //! ## Endpoints
 //!
 //! - `GET /cloudmap_sd`: Returns Prometheus-compatible service discovery JSON
+//!   (or newline-delimited JSON with `Accept: application/x-ndjson`), optionally
+//!   narrowed to one namespace with `?namespace=<name>`; answers 304 Not
+//!   Modified when `If-None-Match` carries the current `ETag`
+//! - `GET /topology`: Returns namespaces and services with counts, without instances
+//! - `GET /config`: Returns the effective configuration with secrets redacted
+//! - `GET /health`: Liveness check that never calls AWS
+//! - `GET /ready`: Readiness check, 200 once discovery has succeeded
+//! - `GET /metrics`: The adapter's own metrics in Prometheus text format
+//! - `POST /refresh-credentials`: Rebuilds the AWS client to pick up rotated
+//!   credentials (token-protected, disabled unless configured)
+//!
+//! With `auth_token` configured, `/cloudmap_sd`, `/topology` and `/config`
+//! require an `Authorization: Bearer <token>` header, checked by
+//! [`bearer_auth`].
+//!
+//! `/cloudmap_sd` responses are gzip- or deflate-compressed when the request's
+//! `Accept-Encoding` allows it, and can be fetched
+//! cross-origin by configured browser origins, see [`with_cors`].
 //!
 //! ## Error Handling
 //!
-//! All AWS API errors are caught and converted to HTTP 500 responses with
-//! appropriate logging for debugging purposes.
+//! Failures are logged and turned into JSON bodies such as
+//! `{"error": "discovery_failed", "message": "..."}` by [`handle_rejection`],
+//! with these statuses:
+//!
+//! - 304 Not Modified: not an error, `If-None-Match` matched the current `ETag`
+//! - 400 Bad Request: malformed query string
+//! - 401 Unauthorized: missing or wrong bearer token, with `WWW-Authenticate`
+//! - 403 Forbidden: cross-origin request from an origin not allowed by CORS
+//! - 404 Not Found / 405 Method Not Allowed: unknown route or wrong method
+//! - 429 Too Many Requests: `max_concurrent_discoveries` crawls already
+//!   running, with `Retry-After`
+//! - 500 Internal Server Error: any other discovery failure
+//! - 503 Service Unavailable: AWS rejected the credentials or throttled
+//!   discovery (with `Retry-After`), or soft-fail mode has no earlier result
+//!   to serve
+//! - 504 Gateway Timeout: discovery exceeded `discovery_timeout_seconds`
+
+use crate::client::ClientFactory;
+use crate::config::Config as AppConfig;
+use crate::discovery::{
+    AwsError, Discovery, DiscoveryError, FNV_OFFSET_BASIS, NamespaceTopology, PrometheusTarget,
+    fnv1a,
+};
+use crate::metrics::{self, Metrics};
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use warp::cors::CorsForbidden;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::http::header::{
+    CONTENT_ENCODING, CONTENT_TYPE, ETAG, HeaderValue, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+};
+use warp::reject::{InvalidQuery, MethodNotAllowed};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// Configuration for HTTP response handling
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Content-Type sent with `/cloudmap_sd` responses
+    pub content_type: String,
+    /// Serve the last successful response with a warning header on discovery
+    /// failure (503 when there is none) instead of a 500
+    pub soft_fail: bool,
+    /// Serve the cache filled by the background refresh loop instead of
+    /// discovering on each request
+    pub background_refresh: bool,
+    /// Leave namespaces without services out of `/topology` unless the request
+    /// says otherwise
+    pub omit_empty_namespaces: bool,
+    /// Refresh interval advertised to Prometheus with each `/cloudmap_sd`
+    /// response (None = no header)
+    pub refresh_interval_hint: Option<u64>,
+    /// Smallest `/cloudmap_sd` body, in bytes, that is compressed
+    pub compression_min_bytes: usize,
+    /// Permits shared by the discoveries requests run; a request finding none
+    /// left is rejected with a 429 (None = no limit)
+    pub discovery_permits: Option<Arc<Semaphore>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            content_type: "application/json".to_string(),
+            soft_fail: false,
+            background_refresh: false,
+            omit_empty_namespaces: false,
+            refresh_interval_hint: None,
+            compression_min_bytes: 1024,
+            discovery_permits: None,
+        }
+    }
+}
+
+/// Media type for newline-delimited JSON output
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Seconds clients are asked to wait after AWS throttled discovery
+const THROTTLED_RETRY_AFTER_SECS: u64 = 30;
+
+/// Seconds clients are asked to wait when too many discoveries are running
+const BUSY_RETRY_AFTER_SECS: u64 = 1;
+
+/// Header describing a discovery failure hidden by soft-fail mode
+const WARNING_HEADER: &str = "x-cloudmap-warning";
+
+/// Header set to `true` when the served targets come from an earlier
+/// successful run because the latest one failed
+const STALE_HEADER: &str = "x-cloudmap-stale";
 
-use crate::discovery::Discovery;
-use log::error;
-use warp::{Rejection, Reply};
+/// Header advertising how often Prometheus should refresh the targets
+const REFRESH_INTERVAL_HEADER: &str = "x-prometheus-refresh-interval-seconds";
+
+/// Machine-readable kind of an error response, reported as its `error` field
+///
+/// The kind decides the response's status code and extra headers, so they
+/// never depend on how an error message is worded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Malformed query string or unknown `?region=` (400)
+    BadRequest,
+    /// Missing or wrong bearer token (401)
+    Unauthorized,
+    /// Cross-origin request from an origin not allowed (403)
+    CorsForbidden,
+    /// Unknown route (404)
+    NotFound,
+    /// Known route, wrong method (405)
+    MethodNotAllowed,
+    /// `max_concurrent_discoveries` crawls already running (429)
+    TooManyDiscoveries,
+    /// Unclassified discovery failure (500)
+    DiscoveryFailed,
+    /// Rejection no handler knows about (500)
+    InternalError,
+    /// AWS rejected the credentials (503)
+    AwsAuthFailed,
+    /// AWS throttled discovery (503)
+    AwsThrottled,
+    /// Discovery failed in soft-fail mode with no earlier result to serve (503)
+    DiscoveryUnavailable,
+    /// Discovery exceeded `discovery_timeout_seconds` (504)
+    DiscoveryTimeout,
+}
+
+impl ErrorKind {
+    /// Status code of responses of this kind
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::CorsForbidden => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::TooManyDiscoveries => StatusCode::TOO_MANY_REQUESTS,
+            Self::DiscoveryFailed | Self::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::AwsAuthFailed | Self::AwsThrottled | Self::DiscoveryUnavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Self::DiscoveryTimeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// Seconds clients are asked to wait before retrying, sent as `Retry-After`
+    fn retry_after(self) -> Option<u64> {
+        match self {
+            Self::AwsThrottled => Some(THROTTLED_RETRY_AFTER_SECS),
+            Self::TooManyDiscoveries => Some(BUSY_RETRY_AFTER_SECS),
+            _ => None,
+        }
+    }
+}
 
 /// Custom error type for Cloud Map discovery failures
 ///
 /// This error is returned when the service discovery process fails,
-/// typically due to AWS API errors or network issues.
+/// typically due to AWS API errors or network issues. It carries the
+/// underlying cause, which is reported in the error response body.
 #[derive(Debug)]
-pub struct CloudMapError;
+pub struct CloudMapError {
+    /// Description of the underlying failure
+    pub message: String,
+    /// Kind of the failure, deciding the response's status
+    pub kind: ErrorKind,
+}
+
+impl CloudMapError {
+    /// Creates an unclassified discovery failure (500) with the given cause
+    #[cfg(test)]
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            kind: ErrorKind::DiscoveryFailed,
+        }
+    }
+
+    /// Creates the 429 for a request arriving while `max_concurrent_discoveries`
+    /// discoveries are already running
+    pub fn too_many_discoveries() -> Self {
+        Self {
+            message: "too many discoveries in progress".to_string(),
+            kind: ErrorKind::TooManyDiscoveries,
+        }
+    }
+
+    /// Creates the 400 for a `?region=` without a configured regional client
+    pub fn unknown_region(region: &str) -> Self {
+        Self {
+            message: format!("no Cloud Map client is configured for region {}", region),
+            kind: ErrorKind::BadRequest,
+        }
+    }
+
+    /// Creates a discovery failure whose status reflects the classified cause
+    ///
+    /// Authentication failures and throttling are the adapter's upstream being
+    /// unavailable to it, so both map to 503; a timeout is a 504 and anything
+    /// else is a 500.
+    pub fn from_discovery(error: &DiscoveryError) -> Self {
+        let kind = match (error, error.aws_error()) {
+            (DiscoveryError::Timeout(_), _) => ErrorKind::DiscoveryTimeout,
+            (_, Some(AwsError::Auth(_))) => ErrorKind::AwsAuthFailed,
+            (_, Some(AwsError::Throttled(_))) => ErrorKind::AwsThrottled,
+            _ => ErrorKind::DiscoveryFailed,
+        };
+        Self {
+            message: error.to_string(),
+            kind,
+        }
+    }
+}
+
 impl warp::reject::Reject for CloudMapError {}
 
+/// Rejection for a request without the configured bearer token
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Filter requiring `Authorization: Bearer <token>` when a token is configured
+///
+/// Without a token every request passes. Requests with a missing or wrong
+/// token are rejected with [`Unauthorized`], answered with a 401 by
+/// [`handle_rejection`].
+///
+/// # Arguments
+///
+/// * `token` - Bearer token required by the routes, if any
+pub fn bearer_auth(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let token = token.clone();
+            async move { check_bearer_token(token.as_deref(), authorization.as_deref()) }
+        })
+        .untuple_one()
+}
+
+/// Checks a request's `Authorization` header for [`bearer_auth`]
+fn check_bearer_token(token: Option<&str>, authorization: Option<&str>) -> Result<(), Rejection> {
+    match token {
+        Some(token) if !is_authorized(authorization, token) => {
+            Err(warp::reject::custom(Unauthorized))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Allows browsers on the given origins to fetch a route cross-origin
+///
+/// Preflight `OPTIONS` requests are answered directly; requests from other
+/// origins are rejected with [`CorsForbidden`], answered with a 403 by
+/// [`handle_rejection`]. Requests without an `Origin` header, such as
+/// Prometheus', pass through untouched. Without any allowed origin the route
+/// is returned unchanged. `*` allows any origin.
+///
+/// # Arguments
+///
+/// * `allowed_origins` - Validated `cors_allowed_origins`
+/// * `route` - Route to expose cross-origin
+pub fn with_cors<F>(allowed_origins: &[String], route: F) -> BoxedFilter<(Response,)>
+where
+    F: Filter<Extract = (Response,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    if allowed_origins.is_empty() {
+        return route.boxed();
+    }
+
+    let cors = warp::cors()
+        .allow_methods(vec!["GET"])
+        .allow_headers(vec!["authorization", "if-none-match"])
+        .expose_headers(vec![
+            ETAG.as_str(),
+            STALE_HEADER,
+            WARNING_HEADER,
+            REFRESH_INTERVAL_HEADER,
+        ]);
+    let cors = if allowed_origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(allowed_origins.iter().map(String::as_str))
+    };
+    route.with(cors).map(into_response).boxed()
+}
+
+/// Converts any reply into a response, to unify filter outputs
+fn into_response(reply: impl Reply) -> Response {
+    reply.into_response()
+}
+
+/// Checks an `Accept-Encoding` header for an encoding (or `*`) not refused
+/// with `q=0`
+fn allows_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        let refused = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case(encoding) || name == "*") && !refused
+    })
+}
+
+/// Content codings `/cloudmap_sd` bodies can be compressed with, in order of
+/// preference
+const ENCODINGS: [&str; 2] = ["gzip", "deflate"];
+
+/// Picks the preferred encoding an `Accept-Encoding` header allows, if any
+fn negotiate_encoding(accept_encoding: Option<&str>) -> Option<&'static str> {
+    let accept_encoding = accept_encoding?;
+    ENCODINGS
+        .into_iter()
+        .find(|encoding| allows_encoding(accept_encoding, encoding))
+}
+
+/// Compresses a body with `gzip` or `deflate` (zlib, as HTTP's `deflate` is)
+fn compress(body: &[u8], encoding: &str) -> std::io::Result<Vec<u8>> {
+    if encoding == "gzip" {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body)?;
+        encoder.finish()
+    } else {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body)?;
+        encoder.finish()
+    }
+}
+
+/// Body of JSON error responses
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    /// Machine-readable error kind, e.g. `discovery_failed`
+    error: ErrorKind,
+    /// Human-readable description of the error
+    message: String,
+}
+
 /// HTTP handler for the `/cloudmap_sd` endpoint
 ///
 /// This handler performs AWS Cloud Map service discovery and returns
@@ -32,10 +382,17 @@ impl warp::reject::Reject for CloudMapError {}
 /// # Arguments
 ///
 /// * `discovery` - Discovery client configured with AWS credentials and settings
+/// * `config` - Response handling configuration
+/// * `accept` - Request `Accept` header, used to select NDJSON output
+/// * `if_none_match` - Request `If-None-Match` header
+/// * `accept_encoding` - Request `Accept-Encoding` header, used to compress
+///   the body
+/// * `query` - Request query parameters
 ///
 /// # Returns
 ///
-/// * `Ok(impl Reply)` - JSON response with discovered targets
+/// * `Ok(impl Reply)` - JSON response with discovered targets, or 304 Not
+///   Modified
 /// * `Err(Rejection)` - HTTP error response (500 for discovery failures)
 ///
 /// # Response Format
@@ -52,34 +409,721 @@ impl warp::reject::Reject for CloudMapError {}
 ///   }
 /// ]
 /// ```
-pub async fn cloudmap_sd_handler(discovery: Discovery) -> Result<impl Reply, Rejection> {
+///
+/// When the request accepts `application/x-ndjson`, each target group is instead
+/// written as a separate JSON object on its own line, for log-pipeline ingestion.
+///
+/// Every response carries an `ETag` hashed from its body, which is stable
+/// because discovery output is sorted. When `If-None-Match` matches it, a 304
+/// Not Modified without a body is returned instead, so Prometheus refreshing
+/// unchanged targets skips the download.
+///
+/// The body is gzip-compressed, or deflate-compressed, when `Accept-Encoding`
+/// allows it and the body is at least `compression_min_bytes` long. Compressed bodies differ byte for byte from the uncompressed
+/// one, so their `ETag` is made weak: `If-None-Match` still matches it, but it
+/// no longer claims byte equality across encodings. Every target response
+/// carries `Vary: Accept-Encoding` so caches keep the encodings apart.
+///
+/// In soft-fail mode a discovery failure returns the last successful response
+/// with an `X-Cloudmap-Stale: true` header and an `X-Cloudmap-Warning` header
+/// describing the failure, or a 503 when discovery has never succeeded.
+///
+/// While a snapshot loaded at startup is still on standby, it is returned right
+/// away and discovery is refreshed in the background.
+///
+/// With a background refresh loop running, the handler only reads the shared
+/// cache and never waits on AWS; before the first refresh completes it returns
+/// an empty array. While the latest refresh has failed, the previous result is
+/// served with `X-Cloudmap-Stale: true`.
+///
+/// With `max_concurrent_discoveries`, a request that has to crawl Cloud Map
+/// while that many crawls are already running is rejected with a 429 and a
+/// `Retry-After` header instead of waiting; responses served from a cache or
+/// snapshot are never limited.
+///
+/// `?namespace=<name>` discovers only that namespace instead of the configured
+/// one, and `?region=<name>` only that one of the `aws_regions`, answering a
+/// region without a configured client with a 400. Such requests always crawl
+/// Cloud Map: they bypass the standby snapshot, the background refresh cache
+/// and the shared `cache_ttl_seconds` cache.
+pub async fn cloudmap_sd_handler(
+    discovery: Discovery,
+    config: Config,
+    accept: Option<String>,
+    if_none_match: Option<String>,
+    accept_encoding: Option<String>,
+    query: CloudMapSdQuery,
+) -> Result<Response, Rejection> {
+    let request = SdRequest {
+        accept: accept.as_deref(),
+        if_none_match: if_none_match.as_deref(),
+        accept_encoding: accept_encoding.as_deref(),
+    };
+
+    let scoped = match &query.region {
+        Some(region) => Some(
+            discovery
+                .for_region(region)
+                .ok_or_else(|| warp::reject::custom(CloudMapError::unknown_region(region)))?,
+        ),
+        None => None,
+    };
+    let scoped = match query.namespace {
+        Some(namespace) => Some(
+            scoped
+                .as_ref()
+                .unwrap_or(&discovery)
+                .for_namespace(namespace),
+        ),
+        None => scoped,
+    };
+    if let Some(scoped) = scoped {
+        return discovery_response(&scoped, &config, request).await;
+    }
+
+    if let Some(targets) = discovery.standby_targets() {
+        return Ok(targets_response(&targets, &config, request));
+    }
+
+    if config.background_refresh {
+        let targets = discovery.cached_targets().await;
+        let mut response = targets_response(&targets, &config, request);
+        if !targets.is_empty() && discovery.last_run_failed() {
+            mark_stale(&mut response);
+        }
+        return Ok(response);
+    }
+
+    discovery_response(&discovery, &config, request).await
+}
+
+/// Request headers that shape a `/cloudmap_sd` response
+#[derive(Debug, Clone, Copy, Default)]
+struct SdRequest<'a> {
+    /// `Accept` header, used to select NDJSON output
+    accept: Option<&'a str>,
+    /// `If-None-Match` header, compared with the response's `ETag`
+    if_none_match: Option<&'a str>,
+    /// `Accept-Encoding` header, selecting the body's compression
+    accept_encoding: Option<&'a str>,
+}
+
+/// Takes one of the configured discovery permits for the duration of a crawl
+///
+/// Requests don't queue for a permit: with all of them taken, the request is
+/// rejected with a 429 so that scrapes don't pile up behind slow discoveries.
+fn acquire_discovery_permit(config: &Config) -> Result<Option<SemaphorePermit<'_>>, Rejection> {
+    let Some(permits) = &config.discovery_permits else {
+        return Ok(None);
+    };
+    match permits.try_acquire() {
+        Ok(permit) => Ok(Some(permit)),
+        Err(_) => {
+            warn!("⚠️  Too many discoveries in progress, rejecting request");
+            Err(warp::reject::custom(CloudMapError::too_many_discoveries()))
+        }
+    }
+}
+
+/// Runs discovery and builds the `/cloudmap_sd` response, applying soft-fail
+/// mode on failure
+async fn discovery_response(
+    discovery: &Discovery,
+    config: &Config,
+    request: SdRequest<'_>,
+) -> Result<Response, Rejection> {
+    let _permit = acquire_discovery_permit(config)?;
     match discovery.discover_targets().await {
-        Ok(targets) => Ok(warp::reply::json(&targets)),
+        Ok(targets) => Ok(targets_response(&targets, config, request)),
         Err(e) => {
             error!("❌ Failed to discover Cloud Map targets: {:?}", e);
             error!("❌ Error details: {}", e);
-            Err(warp::reject::custom(CloudMapError))
+            if config.soft_fail {
+                Ok(soft_fail_response(
+                    discovery.last_good_targets(),
+                    &e.to_string(),
+                    config,
+                    request,
+                ))
+            } else {
+                Err(warp::reject::custom(CloudMapError::from_discovery(&e)))
+            }
+        }
+    }
+}
+
+/// Query parameters accepted by `/cloudmap_sd`
+#[derive(Debug, Default, Deserialize)]
+pub struct CloudMapSdQuery {
+    /// Namespace to discover (overrides the configured namespace)
+    pub namespace: Option<String>,
+    /// Region to discover, one of `aws_regions` (overrides discovering them all)
+    pub region: Option<String>,
+}
+
+/// Query parameters accepted by `/topology`
+#[derive(Debug, Default, Deserialize)]
+pub struct TopologyQuery {
+    /// Leave out namespaces without services (overrides the configured default)
+    pub omit_empty: Option<bool>,
+}
+
+/// HTTP handler for the `/topology` endpoint
+///
+/// This handler lists namespaces and their services without performing the
+/// per-service instance lookups, giving a cheap view of the Cloud Map topology.
+/// Namespaces without services are left out with `?omit_empty=true`, or by
+/// default when `omit_empty_namespaces` is configured.
+///
+/// # Arguments
+///
+/// * `discovery` - Discovery client configured with AWS credentials and settings
+/// * `config` - Response handling configuration
+/// * `query` - Request query parameters
+///
+/// # Returns
+///
+/// * `Ok(impl Reply)` - JSON response with the discovered topology
+/// * `Err(Rejection)` - HTTP error response (500 for discovery failures)
+///
+/// # Response Format
+///
+/// ```json
+/// [
+///   {
+///     "name": "production",
+///     "id": "ns-abc123",
+///     "service_count": 1,
+///     "services": [
+///       { "name": "web-service", "id": "srv-def456", "instance_count": 2 }
+///     ]
+///   }
+/// ]
+/// ```
+pub async fn topology_handler(
+    discovery: Discovery,
+    config: Config,
+    query: TopologyQuery,
+) -> Result<impl Reply, Rejection> {
+    let _permit = acquire_discovery_permit(&config)?;
+    match discovery.discover_topology().await {
+        Ok(mut topology) => {
+            if query.omit_empty.unwrap_or(config.omit_empty_namespaces) {
+                omit_empty_namespaces(&mut topology);
+            }
+            Ok(warp::reply::json(&topology))
+        }
+        Err(e) => {
+            error!("❌ Failed to discover Cloud Map topology: {:?}", e);
+            error!("❌ Error details: {}", e);
+            Err(warp::reject::custom(CloudMapError::from_discovery(&e)))
+        }
+    }
+}
+
+/// Removes namespaces without any services from a topology
+fn omit_empty_namespaces(topology: &mut Vec<NamespaceTopology>) {
+    topology.retain(|namespace| namespace.service_count > 0);
+}
+
+/// Body of the `/health` response
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    status: &'static str,
+}
+
+/// HTTP handler for the `/health` endpoint
+///
+/// A liveness check for orchestrators and load balancers. It only shows that
+/// the process is serving requests and never calls AWS, so it stays fast and
+/// doesn't fail when discovery does.
+///
+/// # Response Format
+///
+/// ```json
+/// {"status": "ok"}
+/// ```
+pub async fn health_handler() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&HealthResponse { status: "ok" }))
+}
+
+/// HTTP handler for the `/ready` endpoint
+///
+/// A readiness check: 503 until a discovery run has succeeded, then 200 until
+/// `ready_failure_threshold` runs in a row have failed. It reports the state
+/// left by earlier runs and never calls AWS itself.
+///
+/// # Response Format
+///
+/// ```json
+/// {"status": "ready"}
+/// ```
+///
+/// # Arguments
+///
+/// * `discovery` - Discovery instance tracking readiness
+pub async fn ready_handler(discovery: Discovery) -> Result<impl Reply, Rejection> {
+    Ok(ready_response(discovery.is_ready()))
+}
+
+/// Builds the `/ready` response for a readiness state
+fn ready_response(ready: bool) -> Response {
+    let (status, code) = if ready {
+        ("ready", StatusCode::OK)
+    } else {
+        ("not ready", StatusCode::SERVICE_UNAVAILABLE)
+    };
+    status_response(status, code)
+}
+
+/// HTTP handler for the `/metrics` endpoint
+///
+/// Renders the adapter's own metrics (discovery runs, their duration, the
+/// target group count and responses served) in the Prometheus text format.
+///
+/// # Arguments
+///
+/// * `metrics` - Registry shared with discovery and the server
+pub async fn metrics_handler(metrics: Arc<Metrics>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::with_header(
+        metrics.render(),
+        CONTENT_TYPE,
+        metrics::CONTENT_TYPE,
+    ))
+}
+
+/// HTTP handler for the `/refresh-credentials` endpoint
+///
+/// Rebuilds the Cloud Map client so it resolves AWS credentials from scratch,
+/// for when credentials are rotated out of band. The endpoint only exists when
+/// `refresh_credentials_token` is configured, and requires the token as an
+/// `Authorization: Bearer <token>` header.
+///
+/// # Arguments
+///
+/// * `discovery` - Discovery instance whose client is rebuilt
+/// * `factory` - Builds the replacement client
+/// * `token` - Configured token (None = endpoint disabled)
+/// * `authorization` - Request `Authorization` header
+///
+/// # Returns
+///
+/// * `Ok(impl Reply)` - `{"status": "refreshed"}`, or a 502 status body
+/// * `Err(Rejection)` - 404 when the endpoint is disabled, or [`Unauthorized`]
+///   for a missing or wrong token
+pub async fn refresh_credentials_handler(
+    discovery: Discovery,
+    factory: Arc<dyn ClientFactory>,
+    token: Option<String>,
+    authorization: Option<String>,
+) -> Result<impl Reply, Rejection> {
+    let Some(token) = token else {
+        return Err(warp::reject::not_found());
+    };
+    if !is_authorized(authorization.as_deref(), &token) {
+        return Err(warp::reject::custom(Unauthorized));
+    }
+
+    match discovery.refresh_client(factory.as_ref()).await {
+        Ok(()) => Ok(status_response("refreshed", StatusCode::OK)),
+        Err(e) => {
+            error!("❌ Failed to refresh AWS credentials: {}", e);
+            Ok(status_response("failed", StatusCode::BAD_GATEWAY))
+        }
+    }
+}
+
+/// Checks an `Authorization` header against the expected bearer token
+///
+/// The token is compared in constant time, so response timing doesn't reveal
+/// how much of a guessed token was right.
+fn is_authorized(authorization: Option<&str>, token: &str) -> bool {
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+}
+
+/// Compares two byte strings in time that depends only on their lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Turns a rejection into a JSON error response
+///
+/// Discovery failures carry their cause and the status of their classified
+/// [`ErrorKind`], with a `Retry-After` header when AWS throttled discovery or
+/// too many discoveries are running; unknown routes, wrong methods and
+/// malformed query strings get their usual status codes. Used with
+/// `Filter::recover` on the combined routes.
+pub async fn handle_rejection(rejection: Rejection) -> Result<Response, Infallible> {
+    let (code, body) = error_response(&rejection);
+    let kind = body.error;
+    let mut response = warp::reply::with_status(warp::reply::json(&body), code).into_response();
+    if let Some(seconds) = kind.retry_after() {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(seconds));
+    }
+    if kind == ErrorKind::Unauthorized {
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    Ok(response)
+}
+
+/// Maps a rejection to its status code and JSON error body
+fn error_response(rejection: &Rejection) -> (StatusCode, ErrorResponse) {
+    let (error, message) = if rejection.is_not_found() {
+        (ErrorKind::NotFound, "no such endpoint".to_string())
+    } else if rejection.find::<Unauthorized>().is_some() {
+        (
+            ErrorKind::Unauthorized,
+            "missing or invalid bearer token".to_string(),
+        )
+    } else if let Some(failure) = rejection.find::<CloudMapError>() {
+        (failure.kind, failure.message.clone())
+    } else if let Some(forbidden) = rejection.find::<CorsForbidden>() {
+        (ErrorKind::CorsForbidden, forbidden.to_string())
+    } else if let Some(invalid) = rejection.find::<InvalidQuery>() {
+        (ErrorKind::BadRequest, invalid.to_string())
+    } else if let Some(not_allowed) = rejection.find::<MethodNotAllowed>() {
+        (ErrorKind::MethodNotAllowed, not_allowed.to_string())
+    } else {
+        error!("❌ Unhandled rejection: {:?}", rejection);
+        (ErrorKind::InternalError, "unhandled rejection".to_string())
+    };
+    (error.status(), ErrorResponse { error, message })
+}
+
+/// Builds a `{"status": ...}` JSON response
+fn status_response(status: &'static str, code: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::json(&HealthResponse { status }), code).into_response()
+}
+
+/// HTTP handler for the `/config` endpoint
+///
+/// Returns the effective configuration after file and environment overrides
+/// have been applied, to help debug which source a setting came from. Secret
+/// values are redacted.
+///
+/// # Arguments
+///
+/// * `config` - Effective application configuration
+pub async fn config_handler(config: AppConfig) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&config.redacted()))
+}
+
+/// Route serving `GET /config` with [`config_handler`]
+///
+/// Even redacted, the configuration names role ARNs, endpoints and file
+/// paths, so with `auth_token` set the route requires it like `/cloudmap_sd`.
+pub fn config_route(
+    config: AppConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("config")
+        .and(warp::get())
+        .and(bearer_auth(config.auth_token.clone()))
+        .and_then(move || config_handler(config.clone()))
+}
+
+/// Builds the `/cloudmap_sd` response in the format the request asked for
+///
+/// The response carries an `ETag`, and is a bodiless 304 Not Modified when the
+/// request's `If-None-Match` already matches it. The configured refresh
+/// interval hint, if any, is advertised in the
+/// `X-Prometheus-Refresh-Interval-Seconds` header.
+fn targets_response(
+    targets: &[PrometheusTarget],
+    config: &Config,
+    request: SdRequest<'_>,
+) -> Response {
+    let (body, content_type) = if wants_ndjson(request.accept) {
+        (ndjson_body(targets).into_bytes(), NDJSON_CONTENT_TYPE)
+    } else {
+        // Target groups are plain strings, so serializing them can't fail
+        (
+            serde_json::to_vec(targets).unwrap_or_default(),
+            config.content_type.as_str(),
+        )
+    };
+    let mut etag = etag(&body);
+    // Compressing a small body saves less than the encoding costs
+    let encoding = negotiate_encoding(request.accept_encoding)
+        .filter(|_| body.len() >= config.compression_min_bytes);
+    let mut response = if request
+        .if_none_match
+        .is_some_and(|header| etag_matches(header, &etag))
+    {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else if let Some(encoding) = encoding {
+        match compress(&body, encoding) {
+            Ok(compressed) => {
+                let mut response = body_response(compressed, content_type);
+                response
+                    .headers_mut()
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+                response
+            }
+            Err(e) => {
+                warn!(
+                    "⚠️  Failed to {} the response, sending it uncompressed: {}",
+                    encoding, e
+                );
+                body_response(body, content_type)
+            }
         }
+    } else {
+        body_response(body, content_type)
+    };
+    if encoding.is_some() {
+        etag = format!("W/{}", etag);
+    }
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        response.headers_mut().insert(ETAG, value);
     }
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    if let Some(seconds) = config.refresh_interval_hint {
+        response
+            .headers_mut()
+            .insert(REFRESH_INTERVAL_HEADER, HeaderValue::from(seconds));
+    }
+    response
+}
+
+/// Marks a response as serving targets from an earlier successful run
+fn mark_stale(response: &mut Response) {
+    response
+        .headers_mut()
+        .insert(STALE_HEADER, HeaderValue::from_static("true"));
+}
+
+/// Builds the soft-fail response for a failed discovery run
+///
+/// Serves the last good targets, marked with `X-Cloudmap-Stale: true`, when
+/// there are any, otherwise a 503 with the usual JSON error body. Either way
+/// the failure is described in the `X-Cloudmap-Warning` header.
+fn soft_fail_response(
+    last_good: Option<Vec<PrometheusTarget>>,
+    error: &str,
+    config: &Config,
+    request: SdRequest<'_>,
+) -> Response {
+    let (mut response, warning) = match last_good {
+        Some(targets) => {
+            warn!(
+                "⚠️  Serving {} stale target groups from the last successful discovery",
+                targets.len()
+            );
+            let mut response = targets_response(&targets, config, request);
+            mark_stale(&mut response);
+            (
+                response,
+                format!("discovery failed, serving last good response: {}", error),
+            )
+        }
+        None => {
+            let body = ErrorResponse {
+                error: ErrorKind::DiscoveryUnavailable,
+                message: format!(
+                    "discovery failed and no previous response is available: {}",
+                    error
+                ),
+            };
+            (
+                warp::reply::with_status(warp::reply::json(&body), body.error.status())
+                    .into_response(),
+                format!("discovery failed: {}", error),
+            )
+        }
+    };
+
+    // Header values can't carry control characters such as newlines
+    let warning: String = warning
+        .chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { ' ' })
+        .collect();
+    if let Ok(value) = HeaderValue::from_str(&warning) {
+        response.headers_mut().insert(WARNING_HEADER, value);
+    }
+    response
+}
+
+/// Sends a serialized body with the given Content-Type
+///
+/// The content type is validated at startup, so an unrepresentable value here
+/// simply falls back to `application/json`.
+fn body_response(body: Vec<u8>, content_type: &str) -> Response {
+    let mut response = body.into_response();
+    let value = HeaderValue::from_str(content_type)
+        .unwrap_or_else(|_| HeaderValue::from_static("application/json"));
+    response.headers_mut().insert(CONTENT_TYPE, value);
+    response
+}
+
+/// Computes the strong `ETag` of a response body
+fn etag(body: &[u8]) -> String {
+    format!("\"{:016x}\"", fnv1a(FNV_OFFSET_BASIS, body))
+}
+
+/// Checks an `If-None-Match` header against the current `ETag`
+///
+/// The header may list several tags or be `*`. As RFC 9110 requires for
+/// `If-None-Match`, weak tags (`W/"..."`) match their strong counterpart.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Checks whether an `Accept` header asks for newline-delimited JSON
+fn wants_ndjson(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            range.split(';').next().is_some_and(|media_type| {
+                media_type.trim().eq_ignore_ascii_case(NDJSON_CONTENT_TYPE)
+            })
+        })
+    })
+}
+
+/// Serializes target groups as newline-delimited JSON, one group per line
+fn ndjson_body(targets: &[PrometheusTarget]) -> String {
+    targets
+        .iter()
+        .filter_map(|target| serde_json::to_string(target).ok())
+        .map(|line| line + "\n")
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::discovery::{Config, PrometheusTarget};
-    use std::collections::HashMap;
+    use crate::client::{ClientError, CloudMapApi, MockClientFactory, MockCloudMapApi, Page};
+    use crate::discovery::Config as DiscoveryConfig;
+    use crate::retry::TokioSleep;
+    use aws_sdk_servicediscovery::config::SharedAsyncSleep;
+    use aws_sdk_servicediscovery::types::{InstanceSummary, NamespaceSummary, ServiceSummary};
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn mock_discovery() -> Discovery {
+        Discovery::new(
+            MockCloudMapApi::new(),
+            DiscoveryConfig::default(),
+            SharedAsyncSleep::new(TokioSleep),
+        )
+    }
+
+    fn refreshing_factory(times: usize) -> Arc<dyn ClientFactory> {
+        let mut factory = MockClientFactory::new();
+        factory.expect_build().times(times).returning(|| {
+            let client: Arc<dyn CloudMapApi> = Arc::new(MockCloudMapApi::new());
+            Box::pin(async move { Ok(client) })
+        });
+        Arc::new(factory)
+    }
 
     #[test]
     fn test_cloudmap_error_debug() {
-        let error = CloudMapError;
+        let error = CloudMapError::new("throttled");
         let debug_str = format!("{:?}", error);
-        assert_eq!(debug_str, "CloudMapError");
+        assert!(debug_str.starts_with("CloudMapError { message: \"throttled\""));
+    }
+
+    #[test]
+    fn test_discovery_failure_error_body() {
+        let rejection = warp::reject::custom(CloudMapError::new("list_namespaces timed out"));
+
+        let (code, body) = error_response(&rejection);
+
+        assert_eq!(code, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            serde_json::to_value(&body).unwrap(),
+            serde_json::json!({
+                "error": "discovery_failed",
+                "message": "list_namespaces timed out",
+            })
+        );
+    }
+
+    #[test]
+    fn test_not_found_error_body() {
+        let (code, body) = error_response(&warp::reject::not_found());
+
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        assert_eq!(body.error, ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_discovery_error_statuses() {
+        let status = |failure: AwsError| {
+            let error =
+                CloudMapError::from_discovery(&DiscoveryError::ListNamespaces(Box::new(failure)));
+            (error.kind.status(), error.kind)
+        };
+
+        assert_eq!(
+            status(AwsError::Auth("AccessDeniedException".into())),
+            (StatusCode::SERVICE_UNAVAILABLE, ErrorKind::AwsAuthFailed)
+        );
+        assert_eq!(
+            status(AwsError::Throttled("Rate exceeded".into())),
+            (StatusCode::SERVICE_UNAVAILABLE, ErrorKind::AwsThrottled)
+        );
+        assert_eq!(
+            status(AwsError::Network("connection refused".into())),
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorKind::DiscoveryFailed
+            )
+        );
+        assert_eq!(
+            status(AwsError::Other("NamespaceNotFound".into())),
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorKind::DiscoveryFailed
+            )
+        );
+
+        let timeout =
+            CloudMapError::from_discovery(&DiscoveryError::Timeout(Duration::from_secs(10)));
+        assert_eq!(
+            (timeout.kind.status(), timeout.kind),
+            (StatusCode::GATEWAY_TIMEOUT, ErrorKind::DiscoveryTimeout)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_throttled_rejection_sets_retry_after() {
+        let failure =
+            DiscoveryError::ListNamespaces(Box::new(AwsError::Throttled("Rate exceeded".into())));
+        let rejection = warp::reject::custom(CloudMapError::from_discovery(&failure));
+
+        let response = handle_rejection(rejection).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "30");
+    }
+
+    #[tokio::test]
+    async fn test_handle_rejection_returns_json_error() {
+        let rejection = warp::reject::custom(CloudMapError::new("throttled"));
+
+        let response = handle_rejection(rejection).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()["content-type"], "application/json");
     }
 
     #[test]
     fn test_prometheus_target_creation() {
         // Test that we can create a PrometheusTarget (used in handlers)
-        let mut labels = HashMap::new();
+        let mut labels = BTreeMap::new();
         labels.insert(
             "__meta_cloudmap_namespace_name".to_string(),
             "test-ns".to_string(),
@@ -101,17 +1145,979 @@ mod tests {
     #[test]
     fn test_discovery_config_for_handler() {
         // Test creating a discovery config that would be used by handlers
-        let config = Config {
+        let config = DiscoveryConfig {
             region: Some("us-west-2".to_string()),
-            namespace: Some("production".to_string()),
+            namespaces: vec!["production".to_string()],
+            ..Default::default()
         };
 
         assert_eq!(config.region, Some("us-west-2".to_string()));
-        assert_eq!(config.namespace, Some("production".to_string()));
+        assert_eq!(config.namespaces, vec!["production".to_string()]);
+    }
+
+    #[test]
+    fn test_json_response_default_content_type() {
+        let response = targets_response(&[], &Config::default(), SdRequest::default());
+
+        assert_eq!(response.headers()["content-type"], "application/json");
+    }
+
+    #[test]
+    fn test_json_response_content_type_override() {
+        let config = Config {
+            content_type: "application/vnd.cloudmap+json".to_string(),
+            ..Config::default()
+        };
+
+        let response = targets_response(&[], &config, SdRequest::default());
+
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/vnd.cloudmap+json"
+        );
+    }
+
+    #[test]
+    fn test_wants_ndjson() {
+        assert!(wants_ndjson(Some("application/x-ndjson")));
+        assert!(wants_ndjson(Some(
+            "application/json, application/x-ndjson;q=0.9"
+        )));
+        assert!(!wants_ndjson(Some("application/json")));
+        assert!(!wants_ndjson(Some("*/*")));
+        assert!(!wants_ndjson(None));
+    }
+
+    #[test]
+    fn test_ndjson_output_has_one_object_per_line() {
+        let targets: Vec<PrometheusTarget> = ["web", "api"]
+            .iter()
+            .map(|service| PrometheusTarget {
+                targets: vec!["10.0.0.1".to_string()],
+                labels: BTreeMap::from([(
+                    "__meta_cloudmap_service_name".to_string(),
+                    service.to_string(),
+                )]),
+            })
+            .collect();
+
+        let body = ndjson_body(&targets);
+        let lines: Vec<&str> = body.lines().collect();
+
+        assert!(body.ends_with('\n'));
+        assert_eq!(lines.len(), 2);
+        for (line, expected) in lines.iter().zip(&targets) {
+            let parsed: PrometheusTarget = serde_json::from_str(line).unwrap();
+            assert_eq!(&parsed, expected);
+        }
+
+        let request = SdRequest {
+            accept: Some(NDJSON_CONTENT_TYPE),
+            ..SdRequest::default()
+        };
+        let response = targets_response(&targets, &Config::default(), request);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+    }
+
+    #[test]
+    fn test_soft_fail_serves_last_good_with_warning() {
+        let config = Config {
+            soft_fail: true,
+            ..Config::default()
+        };
+        let last_good = vec![PrometheusTarget {
+            targets: vec!["10.0.0.1".to_string()],
+            labels: BTreeMap::new(),
+        }];
+
+        let response = soft_fail_response(
+            Some(last_good),
+            "throttled\nby AWS",
+            &config,
+            SdRequest::default(),
+        );
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(response.headers()[STALE_HEADER], "true");
+        assert_eq!(
+            response.headers()[WARNING_HEADER],
+            "discovery failed, serving last good response: throttled by AWS"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_soft_fail_without_last_good_is_unavailable() {
+        let config = Config {
+            soft_fail: true,
+            ..Config::default()
+        };
+
+        let response = soft_fail_response(None, "throttled", &config, SdRequest::default());
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert!(!response.headers().contains_key(STALE_HEADER));
+        assert_eq!(
+            response.headers()[WARNING_HEADER],
+            "discovery failed: throttled"
+        );
+        assert_eq!(
+            body_json(response).await,
+            serde_json::json!({
+                "error": "discovery_unavailable",
+                "message": "discovery failed and no previous response is available: throttled"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_handler_reports_ok() {
+        let response = health_handler().await.unwrap().into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(
+            body_json(response).await,
+            serde_json::json!({"status": "ok"})
+        );
+    }
+
+    #[test]
+    fn test_omit_empty_namespaces() {
+        let namespace = |name: &str, service_count: usize| NamespaceTopology {
+            name: name.to_string(),
+            id: format!("ns-{}", name),
+            service_count,
+            services: Vec::new(),
+        };
+        let mut topology = vec![
+            namespace("production", 2),
+            namespace("unused", 0),
+            namespace("staging", 1),
+        ];
+
+        omit_empty_namespaces(&mut topology);
+
+        let names: Vec<&str> = topology.iter().map(|ns| ns.name.as_str()).collect();
+        assert_eq!(names, vec!["production", "staging"]);
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        assert!(is_authorized(Some("Bearer s3cret"), "s3cret"));
+        assert!(!is_authorized(Some("Bearer wrong"), "s3cret"));
+        assert!(!is_authorized(Some("s3cret"), "s3cret"));
+        assert!(!is_authorized(None, "s3cret"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_credentials_rebuilds_client() {
+        let response = refresh_credentials_handler(
+            mock_discovery(),
+            refreshing_factory(1),
+            Some("s3cret".to_string()),
+            Some("Bearer s3cret".to_string()),
+        )
+        .await
+        .unwrap()
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_credentials_requires_token() {
+        let Err(rejection) = refresh_credentials_handler(
+            mock_discovery(),
+            refreshing_factory(0),
+            Some("s3cret".to_string()),
+            Some("Bearer wrong".to_string()),
+        )
+        .await
+        else {
+            panic!("expected a wrong token to be rejected");
+        };
+
+        let response = handle_rejection(rejection).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+        assert_eq!(body_json(response).await["error"], "unauthorized");
+    }
+
+    #[test]
+    fn test_bearer_token_authorized() {
+        assert!(check_bearer_token(Some("s3cret"), Some("Bearer s3cret")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_unauthorized() {
+        for authorization in [
+            None,
+            Some("Bearer wrong"),
+            Some("Bearer s3cre"),
+            Some("s3cret"),
+        ] {
+            let rejection = check_bearer_token(Some("s3cret"), authorization).unwrap_err();
+            assert!(
+                rejection.find::<Unauthorized>().is_some(),
+                "{:?}",
+                authorization
+            );
+
+            let response = handle_rejection(rejection).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+        }
+    }
+
+    #[test]
+    fn test_bearer_token_unset_allows_all() {
+        assert!(check_bearer_token(None, None).is_ok());
+        assert!(check_bearer_token(None, Some("Bearer anything")).is_ok());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cret!"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_credentials_disabled_without_token() {
+        let result =
+            refresh_credentials_handler(mock_discovery(), refreshing_factory(0), None, None).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_handler() {
+        let metrics = Arc::new(Metrics::default());
+        let discovery = succeed_then_fail_discovery().with_metrics(metrics.clone());
+        discovery.refresh_cache().await.unwrap();
+        assert!(discovery.refresh_cache().await.is_err());
+
+        let response = metrics_handler(metrics.clone())
+            .await
+            .unwrap()
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], metrics::CONTENT_TYPE);
+        let rendered = metrics.render();
+        assert!(rendered.contains("cloudmap_sd_discovery_runs_total{result=\"success\"} 1\n"));
+        assert!(rendered.contains("cloudmap_sd_discovery_runs_total{result=\"failure\"} 1\n"));
+        assert!(rendered.contains("cloudmap_sd_target_groups 1\n"));
+        assert!(rendered.contains("cloudmap_sd_last_discovery_duration_seconds "));
+    }
+
+    #[test]
+    fn test_ready_response() {
+        let ready = ready_response(true);
+        let not_ready = ready_response(false);
+
+        assert_eq!(ready.status(), StatusCode::OK);
+        assert_eq!(not_ready.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(not_ready.headers()["content-type"], "application/json");
+    }
+
+    /// Discovery whose first run succeeds with one target group and whose
+    /// later runs fail
+    fn succeed_then_fail_discovery() -> Discovery {
+        let mut client = MockCloudMapApi::new();
+        let calls = AtomicUsize::new(0);
+        client.expect_list_namespaces().returning(move |_| {
+            let first = calls.fetch_add(1, Ordering::SeqCst) == 0;
+            Box::pin(async move {
+                if !first {
+                    return Err(ClientError::from("throttled"));
+                }
+                Ok(Page {
+                    items: vec![
+                        NamespaceSummary::builder()
+                            .id("ns-production")
+                            .name("production")
+                            .build(),
+                    ],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            let service = ServiceSummary::builder().id("srv-web").name("web").build();
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![service],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(|_, _| {
+            let instance = InstanceSummary::builder()
+                .id("i-1")
+                .attributes("AWS_INSTANCE_IPV4", "10.0.0.1")
+                .build();
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![instance],
+                    next_token: None,
+                })
+            })
+        });
+        Discovery::new(
+            client,
+            DiscoveryConfig::default(),
+            SharedAsyncSleep::new(TokioSleep),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_failed_discovery_serves_stale_result() {
+        let discovery = succeed_then_fail_discovery();
+        let config = Config {
+            soft_fail: true,
+            ..Config::default()
+        };
+        let handle = || {
+            cloudmap_sd_handler(
+                discovery.clone(),
+                config.clone(),
+                None,
+                None,
+                None,
+                CloudMapSdQuery::default(),
+            )
+        };
+
+        let fresh = handle().await.unwrap().into_response();
+        let stale = handle().await.unwrap().into_response();
+
+        assert_eq!(fresh.status(), StatusCode::OK);
+        assert!(!fresh.headers().contains_key(STALE_HEADER));
+        assert_eq!(stale.status(), StatusCode::OK);
+        assert_eq!(stale.headers()[STALE_HEADER], "true");
+    }
+
+    #[tokio::test]
+    async fn test_failed_background_refresh_marks_cache_stale() {
+        let discovery = succeed_then_fail_discovery();
+        let config = Config {
+            background_refresh: true,
+            ..Config::default()
+        };
+        let handle = || {
+            cloudmap_sd_handler(
+                discovery.clone(),
+                config.clone(),
+                None,
+                None,
+                None,
+                CloudMapSdQuery::default(),
+            )
+        };
+
+        discovery.refresh_cache().await.unwrap();
+        let fresh = handle().await.unwrap().into_response();
+        assert!(discovery.refresh_cache().await.is_err());
+        let stale = handle().await.unwrap().into_response();
+
+        assert!(!fresh.headers().contains_key(STALE_HEADER));
+        assert_eq!(stale.status(), StatusCode::OK);
+        assert_eq!(stale.headers()[STALE_HEADER], "true");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_interval_header() {
+        let discovery = succeed_then_fail_discovery();
+        discovery.refresh_cache().await.unwrap();
+        let handle = |refresh_interval_hint| {
+            cloudmap_sd_handler(
+                discovery.clone(),
+                Config {
+                    background_refresh: true,
+                    refresh_interval_hint,
+                    ..Config::default()
+                },
+                None,
+                None,
+                None,
+                CloudMapSdQuery::default(),
+            )
+        };
+
+        let hinted = handle(Some(60)).await.unwrap().into_response();
+        let plain = handle(None).await.unwrap().into_response();
+
+        assert_eq!(hinted.headers()[REFRESH_INTERVAL_HEADER], "60");
+        assert!(!plain.headers().contains_key(REFRESH_INTERVAL_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_namespace_query_overrides_configured_namespace() {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().times(1).returning(|_| {
+            let namespaces = ["production", "staging"]
+                .map(|name| {
+                    NamespaceSummary::builder()
+                        .id(format!("ns-{}", name))
+                        .name(name)
+                        .build()
+                })
+                .to_vec();
+            Box::pin(async move {
+                Ok(Page {
+                    items: namespaces,
+                    next_token: None,
+                })
+            })
+        });
+        // Only the requested namespace is crawled; a call for any other
+        // namespace would have no matching expectation and panic
+        client
+            .expect_list_services()
+            .withf(|namespace_id, _| namespace_id == "ns-staging")
+            .times(1)
+            .returning(|_, _| {
+                let service = ServiceSummary::builder().id("srv-web").name("web").build();
+                Box::pin(async move {
+                    Ok(Page {
+                        items: vec![service],
+                        next_token: None,
+                    })
+                })
+            });
+        client.expect_list_instances().times(1).returning(|_, _| {
+            let instance = InstanceSummary::builder()
+                .id("i-1")
+                .attributes("AWS_INSTANCE_IPV4", "10.0.0.1")
+                .build();
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![instance],
+                    next_token: None,
+                })
+            })
+        });
+        let discovery = Discovery::new(
+            client,
+            DiscoveryConfig {
+                namespaces: vec!["production".to_string()],
+                cache_ttl_seconds: 30,
+                ..Default::default()
+            },
+            SharedAsyncSleep::new(TokioSleep),
+        );
+        let query = CloudMapSdQuery {
+            namespace: Some("staging".to_string()),
+            ..CloudMapSdQuery::default()
+        };
+
+        let response = cloudmap_sd_handler(
+            discovery.clone(),
+            Config::default(),
+            None,
+            None,
+            None,
+            query,
+        )
+        .await
+        .unwrap()
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // The override neither changes nor fills the configured discovery's cache
+        assert!(discovery.cached_targets().await.is_empty());
+    }
+
+    /// Reads a response body and parses it as JSON
+    async fn body_json(response: Response) -> serde_json::Value {
+        use http_body_util::BodyExt;
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Client serving one `web` service in `production` with a single
+    /// instance at `ip`
+    fn single_instance_client(ip: &'static str) -> MockCloudMapApi {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(|_| {
+            let namespace = NamespaceSummary::builder()
+                .id("ns-production")
+                .name("production")
+                .build();
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![namespace],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_services().returning(|_, _| {
+            let service = ServiceSummary::builder().id("srv-web").name("web").build();
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![service],
+                    next_token: None,
+                })
+            })
+        });
+        client.expect_list_instances().returning(move |_, _| {
+            let instance = InstanceSummary::builder()
+                .id("i-1")
+                .attributes("AWS_INSTANCE_IPV4", ip)
+                .build();
+            Box::pin(async move {
+                Ok(Page {
+                    items: vec![instance],
+                    next_token: None,
+                })
+            })
+        });
+        client
+    }
+
+    /// Discovery over `us-east-1`, whose client has no expectations and
+    /// panics when called, and `eu-west-1`
+    fn two_region_discovery() -> Discovery {
+        Discovery::new(
+            MockCloudMapApi::new(),
+            DiscoveryConfig::default(),
+            SharedAsyncSleep::new(TokioSleep),
+        )
+        .with_regions(vec![
+            ("us-east-1".to_string(), Arc::new(MockCloudMapApi::new())),
+            (
+                "eu-west-1".to_string(),
+                Arc::new(single_instance_client("10.1.0.1")),
+            ),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_region_query_discovers_only_that_region() {
+        let query = CloudMapSdQuery {
+            region: Some("eu-west-1".to_string()),
+            ..CloudMapSdQuery::default()
+        };
+
+        let response = cloudmap_sd_handler(
+            two_region_discovery(),
+            Config::default(),
+            None,
+            None,
+            None,
+            query,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["targets"], serde_json::json!(["10.1.0.1"]));
+        assert_eq!(body[0]["labels"]["__meta_cloudmap_region"], "eu-west-1");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_region_query_is_rejected() {
+        let query = CloudMapSdQuery {
+            region: Some("ap-south-1".to_string()),
+            ..CloudMapSdQuery::default()
+        };
+
+        let Err(rejection) = cloudmap_sd_handler(
+            two_region_discovery(),
+            Config::default(),
+            None,
+            None,
+            None,
+            query,
+        )
+        .await
+        else {
+            panic!("expected an unknown region to be rejected");
+        };
+
+        let (code, body) = error_response(&rejection);
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body.error, ErrorKind::BadRequest);
+        assert!(body.message.contains("ap-south-1"), "{}", body.message);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_targets_are_not_modified() {
+        let discovery = succeed_then_fail_discovery();
+        discovery.refresh_cache().await.unwrap();
+        let config = Config {
+            background_refresh: true,
+            ..Config::default()
+        };
+        let handle = |if_none_match: Option<String>| {
+            cloudmap_sd_handler(
+                discovery.clone(),
+                config.clone(),
+                None,
+                if_none_match,
+                None,
+                CloudMapSdQuery::default(),
+            )
+        };
+
+        let first = handle(None).await.unwrap().into_response();
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = first.headers()[ETAG].to_str().unwrap().to_string();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+        let unchanged = handle(Some(etag.clone())).await.unwrap().into_response();
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(unchanged.headers()[ETAG], etag.as_str());
+        assert!(!unchanged.headers().contains_key(CONTENT_TYPE));
+
+        let outdated = handle(Some("\"0000000000000000\"".to_string()))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(outdated.status(), StatusCode::OK);
+        assert_eq!(outdated.headers()[ETAG], etag.as_str());
+    }
+
+    /// Discovery whose every crawl takes `delay` and finds nothing
+    fn slow_discovery(delay: Duration) -> Discovery {
+        let mut client = MockCloudMapApi::new();
+        client.expect_list_namespaces().returning(move |_| {
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                Ok(Page {
+                    items: Vec::<NamespaceSummary>::new(),
+                    next_token: None,
+                })
+            })
+        });
+        Discovery::new(
+            client,
+            DiscoveryConfig::default(),
+            SharedAsyncSleep::new(TokioSleep),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_discoveries_beyond_limit_are_rejected() {
+        let discovery = slow_discovery(Duration::from_secs(10));
+        let config = Config {
+            discovery_permits: Some(Arc::new(Semaphore::new(2))),
+            ..Config::default()
+        };
+        let handle = || {
+            cloudmap_sd_handler(
+                discovery.clone(),
+                config.clone(),
+                None,
+                None,
+                None,
+                CloudMapSdQuery::default(),
+            )
+        };
+
+        let running: Vec<_> = (0..2).map(|_| tokio::spawn(handle())).collect();
+        // Let both requests start their crawls
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        let Err(rejection) = handle().await else {
+            panic!("third concurrent discovery was not rejected");
+        };
+        let response = handle_rejection(rejection).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+
+        for request in running {
+            assert!(request.await.unwrap().is_ok());
+        }
+        // Finished discoveries give their permits back
+        assert!(handle().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cached_targets_are_not_limited() {
+        let discovery = succeed_then_fail_discovery();
+        discovery.refresh_cache().await.unwrap();
+        let config = Config {
+            background_refresh: true,
+            discovery_permits: Some(Arc::new(Semaphore::new(1))),
+            ..Config::default()
+        };
+        let _busy = config
+            .discovery_permits
+            .as_ref()
+            .unwrap()
+            .try_acquire()
+            .unwrap();
+
+        let response = cloudmap_sd_handler(
+            discovery,
+            config.clone(),
+            None,
+            None,
+            None,
+            CloudMapSdQuery::default(),
+        )
+        .await
+        .unwrap()
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let current = etag(b"[]");
+
+        assert!(etag_matches(&current, &current));
+        assert!(etag_matches(&format!("W/{}", current), &current));
+        assert!(etag_matches(&format!("\"other\", {}", current), &current));
+        assert!(etag_matches("*", &current));
+        assert!(!etag_matches("\"other\"", &current));
+        assert_ne!(current, etag(b"[{}]"));
+    }
+
+    #[test]
+    fn test_allows_encoding() {
+        assert!(allows_encoding("gzip", "gzip"));
+        assert!(allows_encoding("deflate, GZIP;q=0.8", "gzip"));
+        assert!(allows_encoding("*", "deflate"));
+        assert!(!allows_encoding("gzip;q=0", "gzip"));
+        assert!(!allows_encoding("br", "gzip"));
+        assert!(!allows_encoding("gzipped", "gzip"));
+    }
+
+    /// Sends a raw `/cloudmap_sd` request with the given method and extra
+    /// header lines to a server running `routes`, returning the response head
+    async fn response_head<F>(routes: F, method: &str, headers: &str) -> String
+    where
+        F: Filter<Extract = (Response,), Error = Rejection> + Clone + Send + Sync + 'static,
+    {
+        path_response_head(routes, method, "/cloudmap_sd", headers).await
+    }
+
+    /// Like [`response_head`], for a request to `path`
+    async fn path_response_head<F>(routes: F, method: &str, path: &str, headers: &str) -> String
+    where
+        F: Filter<Extract = (Response,), Error = Rejection> + Clone + Send + Sync + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(warp::serve(routes).incoming(listener).run());
+
+        request_head(addr, method, path, headers).await
+    }
+
+    /// Sends a raw request to a server already listening on `addr`,
+    /// returning the response head
+    async fn request_head(
+        addr: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &str,
+    ) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+            method, path, headers
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response).to_lowercase();
+        response
+            .split("\r\n\r\n")
+            .next()
+            .unwrap_or_default()
+            .to_string()
     }
 
-    // Note: Testing the actual cloudmap_sd_handler function would require
-    // mocking the AWS SDK client, which is complex. The handler logic is
-    // simple - it calls discovery.discover_targets() and handles the result.
-    // The real testing happens in the discovery module tests.
+    #[test]
+    fn test_compression_is_negotiated() {
+        let targets = vec![PrometheusTarget {
+            targets: vec!["10.0.0.1:9100".to_string()],
+            labels: BTreeMap::new(),
+        }];
+        let respond = |accept_encoding| {
+            let request = SdRequest {
+                accept_encoding,
+                ..SdRequest::default()
+            };
+            let config = Config {
+                compression_min_bytes: 0,
+                ..Config::default()
+            };
+            targets_response(&targets, &config, request)
+        };
+
+        let gzip = respond(Some("deflate, gzip"));
+        let deflate = respond(Some("deflate, gzip;q=0"));
+        let plain = respond(None);
+
+        assert_eq!(gzip.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(deflate.headers()[CONTENT_ENCODING], "deflate");
+        assert!(!plain.headers().contains_key(CONTENT_ENCODING));
+        let etag = plain.headers()[ETAG].to_str().unwrap();
+        assert!(etag.starts_with('"'));
+        assert_eq!(gzip.headers()[ETAG], format!("W/{}", etag).as_str());
+        for response in [gzip, deflate, plain] {
+            assert_eq!(response.headers()[VARY], "accept-encoding");
+        }
+    }
+
+    #[test]
+    fn test_small_payload_is_sent_uncompressed() {
+        let targets = vec![PrometheusTarget {
+            targets: vec!["10.0.0.1:9100".to_string()],
+            labels: BTreeMap::new(),
+        }];
+        let request = SdRequest {
+            accept_encoding: Some("gzip"),
+            ..SdRequest::default()
+        };
+        let config = Config {
+            compression_min_bytes: 1024,
+            ..Config::default()
+        };
+
+        let response = targets_response(&targets, &config, request);
+
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert!(response.headers()[ETAG].to_str().unwrap().starts_with('"'));
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+    }
+
+    #[test]
+    fn test_compress_round_trips() {
+        use flate2::read::{GzDecoder, ZlibDecoder};
+        use std::io::Read;
+
+        let body = br#"[{"targets":["10.0.0.1:9100"],"labels":{}}]"#;
+        let mut gzip = String::new();
+        GzDecoder::new(compress(body, "gzip").unwrap().as_slice())
+            .read_to_string(&mut gzip)
+            .unwrap();
+        let mut deflate = String::new();
+        ZlibDecoder::new(compress(body, "deflate").unwrap().as_slice())
+            .read_to_string(&mut deflate)
+            .unwrap();
+
+        assert_eq!(gzip.as_bytes(), body);
+        assert_eq!(deflate.as_bytes(), body);
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        assert_eq!(negotiate_encoding(Some("gzip, deflate")), Some("gzip"));
+        assert_eq!(negotiate_encoding(Some("deflate")), Some("deflate"));
+        assert_eq!(negotiate_encoding(Some("*")), Some("gzip"));
+        assert_eq!(negotiate_encoding(Some("br")), None);
+        assert_eq!(negotiate_encoding(None), None);
+    }
+
+    /// `/cloudmap_sd` route exposed to `allowed_origins`, with rejections
+    /// turned into responses as in the server
+    fn cors_routes(allowed_origins: &[&str]) -> BoxedFilter<(Response,)> {
+        let allowed: Vec<String> = allowed_origins.iter().map(|o| o.to_string()).collect();
+        let route = warp::path("cloudmap_sd").and(with_cors(
+            &allowed,
+            warp::get().map(|| targets_response(&[], &Config::default(), SdRequest::default())),
+        ));
+        route.recover(handle_rejection).unify().boxed()
+    }
+
+    #[tokio::test]
+    async fn test_cors_header_set_for_allowed_origin_only() {
+        let routes = cors_routes(&["https://dashboard.example.com"]);
+
+        let allowed = response_head(
+            routes.clone(),
+            "GET",
+            "Origin: https://dashboard.example.com\r\n",
+        )
+        .await;
+        let other = response_head(
+            routes.clone(),
+            "GET",
+            "Origin: https://evil.example.com\r\n",
+        )
+        .await;
+        let no_origin = response_head(routes, "GET", "").await;
+
+        assert!(allowed.starts_with("http/1.1 200 ok"), "{}", allowed);
+        assert!(
+            allowed.contains("access-control-allow-origin: https://dashboard.example.com"),
+            "{}",
+            allowed
+        );
+        assert!(other.starts_with("http/1.1 403"), "{}", other);
+        assert!(!other.contains("access-control-allow-origin"), "{}", other);
+        assert!(no_origin.starts_with("http/1.1 200 ok"), "{}", no_origin);
+        assert!(
+            !no_origin.contains("access-control-allow-origin"),
+            "{}",
+            no_origin
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight() {
+        let preflight = "Origin: https://dashboard.example.com\r\n\
+                         Access-Control-Request-Method: GET\r\n\
+                         Access-Control-Request-Headers: authorization\r\n";
+
+        let enabled = response_head(
+            cors_routes(&["https://dashboard.example.com"]),
+            "OPTIONS",
+            preflight,
+        )
+        .await;
+        let disabled = response_head(cors_routes(&[]), "OPTIONS", preflight).await;
+
+        assert!(enabled.starts_with("http/1.1 200 ok"), "{}", enabled);
+        assert!(
+            enabled.contains("access-control-allow-origin: https://dashboard.example.com"),
+            "{}",
+            enabled
+        );
+        assert!(
+            enabled.contains("access-control-allow-methods: get"),
+            "{}",
+            enabled
+        );
+        assert!(
+            !disabled.contains("access-control-allow-origin"),
+            "{}",
+            disabled
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_requires_bearer_token() {
+        let config = AppConfig {
+            auth_token: Some("s3cret".to_string()),
+            ..AppConfig::default()
+        };
+        // Served with the rejection handler, which turns the missing token into a 401
+        let routes = config_route(config).recover(handle_rejection);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(warp::serve(routes).incoming(listener).run());
+
+        let anonymous = request_head(addr, "GET", "/config", "").await;
+        let authorized =
+            request_head(addr, "GET", "/config", "Authorization: Bearer s3cret\r\n").await;
+
+        assert!(anonymous.starts_with("http/1.1 401"), "{}", anonymous);
+        assert!(authorized.starts_with("http/1.1 200"), "{}", authorized);
+    }
 }
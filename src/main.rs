@@ -7,11 +7,18 @@
 //! ## Features
 //!
 //! - HTTP API endpoint at `/cloudmap_sd`
+//! - Lightweight topology endpoint at `/topology`
+//! - Effective (redacted) configuration at `/config`
+//! - Liveness endpoint at `/health`
+//! - Readiness endpoint at `/ready`
+//! - Self-metrics in Prometheus format at `/metrics`
+//! - Optional credentials refresh endpoint at `/refresh-credentials`
 //! - Real-time discovery from AWS Cloud Map
 //! - Optional namespace filtering
 //! - Prometheus-compatible JSON output
 //! - Configurable via JSON file and environment variables
 //! - Structured logging with configurable levels
+//! - One-shot `file_sd` generation with `--once --output <path>`
 //!
 //! ## Usage
 //!
@@ -24,17 +31,47 @@
 //!
 //! # Test the endpoint
 //! curl http://localhost:3030/cloudmap_sd
+//!
+//! # Write file_sd targets once and exit
+//! cargo run -- --once --output /etc/prometheus/cloudmap.json
 //! ```
 
+mod cli;
+mod client;
 mod config;
 mod discovery;
 mod handlers;
+mod metrics;
+mod retry;
+mod server;
+mod snapshot;
+mod transform;
 
+use aws_config::SdkConfig;
 use aws_sdk_servicediscovery::Client as ServiceDiscoveryClient;
+use aws_sdk_servicediscovery::config::SharedAsyncSleep;
+use cli::Mode;
+use client::{AwsClientFactory, ClientFactory};
 use config::Config;
-use discovery::Discovery;
-use handlers::cloudmap_sd_handler;
-use log::{info, warn};
+use discovery::{Discovery, RegionalClients};
+use handlers::{
+    CloudMapSdQuery, TopologyQuery, bearer_auth, cloudmap_sd_handler, config_route,
+    handle_rejection, health_handler, metrics_handler, ready_handler, refresh_credentials_handler,
+    topology_handler, with_cors,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use log::{error, info, warn};
+use metrics::Metrics;
+use retry::TokioSleep;
+use snapshot::SnapshotStore;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use transform::Transform;
 use warp::Filter;
 
 #[tokio::main]
@@ -42,25 +79,86 @@ async fn main() {
     // Initialize the logger
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    // Load configuration
-    let config = Config::load();
+    // Prints usage and exits 2 on invalid arguments, or 0 for --help
+    let args = cli::parse(std::env::args_os()).unwrap_or_else(|e| e.exit());
+
+    // Load configuration; command-line flags win over the environment
+    let mut config = Config::load(args.config_file);
+    args.overrides.apply(&mut config);
+    if let Err(e) = config.validate() {
+        error!("❌ Invalid configuration: {}", e);
+        std::process::exit(1);
+    }
+
+    // Shared by the AWS SDK and our own retry backoff
+    let sleep = SharedAsyncSleep::new(TokioSleep);
 
     // Initialize AWS SDK
-    let aws_config = match config.aws_region.as_ref() {
-        Some(region) => {
-            info!("🌍 Using AWS region from config: {}", region);
-            aws_config::defaults(aws_config::BehaviorVersion::latest())
-                .region(aws_config::Region::new(region.clone()))
-                .load()
-                .await
+    let aws_regions = match config.parse_aws_regions() {
+        Ok(regions) => regions,
+        Err(e) => {
+            error!("❌ {}", e);
+            std::process::exit(1);
         }
-        None => {
-            info!("🌍 Using default AWS region from environment/profile");
-            aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await
+    };
+    if aws_regions.is_empty() {
+        match config.aws_region.as_ref() {
+            Some(region) => info!("🌍 Using AWS region from config: {}", region),
+            None => info!("🌍 Using default AWS region from environment/profile"),
+        }
+        if config.aws_region.is_some() {
+            // The configured region is forced on the client, but say so when the
+            // environment or profile would have picked another one
+            let resolved = AwsClientFactory::default_region().await;
+            if let Some(mismatch) =
+                client::region_mismatch(config.aws_region.as_deref(), resolved.as_deref())
+            {
+                warn!("⚠️  {}", mismatch);
+            }
+        }
+    } else {
+        info!("🌍 Discovering AWS regions: {}", aws_regions.join(", "));
+        if let Some(region) = &config.aws_region {
+            warn!("⚠️  aws_regions is set, ignoring aws_region {}", region);
+        }
+    }
+    // The region of the primary client: aws_region, or the first of aws_regions
+    let primary_region = aws_regions
+        .first()
+        .cloned()
+        .or_else(|| config.aws_region.clone());
+    let assume_role = match config.parse_assume_role() {
+        Ok(assume_role) => assume_role,
+        Err(e) => {
+            error!("❌ {}", e);
+            std::process::exit(1);
         }
     };
+    if let Some(assume_role) = &assume_role {
+        info!(
+            "🔑 Assuming IAM role {} for Cloud Map calls",
+            assume_role.role_arn
+        );
+    }
+    if let Some(endpoint_url) = &config.aws_endpoint_url {
+        warn!(
+            "⚠️  Sending Cloud Map requests to {} instead of AWS",
+            endpoint_url
+        );
+    }
+    let client_factory = AwsClientFactory::new(primary_region.clone(), sleep.clone())
+        .with_endpoint_url(config.aws_endpoint_url.clone())
+        .with_assume_role(assume_role);
+    let aws_config = client_factory.load_sdk_config().await;
+
+    let servicediscovery_client = build_client_or_exit(&aws_config, config.offline_mode);
 
-    let servicediscovery_client = ServiceDiscoveryClient::new(&aws_config);
+    let mut regional_clients: RegionalClients = Vec::new();
+    for region in &aws_regions {
+        let sdk_config = client_factory.in_region(region).load_sdk_config().await;
+        let client = build_client_or_exit(&sdk_config, config.offline_mode);
+        regional_clients.push((region.clone(), Arc::new(client)));
+    }
 
     // Log the actual region being used
     if let Some(region) = aws_config.region() {
@@ -69,40 +167,434 @@ async fn main() {
         warn!("⚠️  No AWS region configured!");
     }
 
+    let sort_groups_by = match config.parse_sort_groups_by() {
+        Ok(label) => label,
+        Err(e) => {
+            error!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let namespace_regex = match config.parse_namespace_regex() {
+        Ok(regex) => regex,
+        Err(e) => {
+            error!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // TLS acceptor built from tls_cert_path and tls_key_path, if both are set
+    let tls = match config.parse_tls().and_then(|paths| {
+        paths
+            .map(|(cert, key)| server::tls_acceptor(&cert, &key))
+            .transpose()
+    }) {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let service_arns = match config.parse_service_arns() {
+        Ok(service_arns) => service_arns,
+        Err(e) => {
+            error!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+    if !aws_regions.is_empty() {
+        for service_arn in service_arns
+            .iter()
+            .filter(|arn| !aws_regions.contains(&arn.region))
+        {
+            warn!(
+                "⚠️  Service {} is in {}, which is not in aws_regions; it won't be discovered",
+                service_arn.service_id, service_arn.region
+            );
+        }
+    } else if let Some(region) = &config.aws_region {
+        for service_arn in service_arns.iter().filter(|arn| &arn.region != region) {
+            warn!(
+                "⚠️  Service {} is in {}, but the client uses {}; its lookups will fail",
+                service_arn.service_id, service_arn.region, region
+            );
+        }
+    }
+
     // Create discovery instance
     let discovery_config = discovery::Config {
-        region: config.aws_region.clone(),
-        namespace: config.cloudmap_namespace.clone(),
+        region: primary_region,
+        namespaces: config.cloudmap_namespace.clone(),
+        namespace_regex,
+        service_arns,
+        protocol_port_attributes: config.protocol_port_attributes.clone(),
+        fallback_labels: config.fallback_labels.clone(),
+        exclude_instance_attribute: config.exclude_instance_attribute.clone(),
+        deduplicate_targets: config.deduplicate_targets,
+        multi_port_attribute: config.multi_port_attribute.clone(),
+        default_port: config.default_port,
+        service_port_overrides: config.service_port_overrides.clone(),
+        ip_attribute_names: config.ip_attribute_names.clone(),
+        warn_on_no_namespaces: config.warn_on_no_namespaces,
+        fail_on_no_namespaces: config.fail_on_no_namespaces,
+        max_retries: config.max_retries,
+        retry_base_delay_ms: config.retry_base_delay_ms,
+        retry_budget: config.retry_budget,
+        retry_budget_window_secs: config.retry_budget_window_secs,
+        aws_call_timeout_ms: config.aws_call_timeout_ms,
+        list_namespaces_timeout_ms: config.list_namespaces_timeout_ms,
+        list_services_timeout_ms: config.list_services_timeout_ms,
+        list_instances_timeout_ms: config.list_instances_timeout_ms,
+        pagination_restarts: config.pagination_restarts,
+        retry_empty_instances: config.retry_empty_instances,
+        retry_empty_instances_delay_ms: config.retry_empty_instances_delay_ms,
+        max_crawl_duration_warn_ms: config.max_crawl_duration_warn_ms,
+        discovery_timeout_seconds: config.discovery_timeout_seconds,
+        group_by: config.group_by,
+        group_by_az: config.group_by_az,
+        discovery_mode: config.discovery_mode,
+        discover_health_status: config.discover_health_status,
+        include_service_tags: config.include_service_tags,
+        group_last_changed_label: config.group_last_changed_label,
+        instance_id_label: config.instance_id_label,
+        discovery_method_label: config.discovery_method_label,
+        include_custom_attributes: config.include_custom_attributes,
+        namespace_scoped_attr_labels: config.namespace_scoped_attr_labels,
+        sort_groups_by,
+        cache_ttl_seconds: config.cache_ttl_seconds,
+        ready_failure_threshold: config.ready_failure_threshold,
+        adapter_started_label: config.adapter_started_label,
+        default_scrape_enabled: config.default_scrape_enabled,
+        only_healthy: config.only_healthy,
+        min_healthy_ratio: config.min_healthy_ratio,
+        drop_unhealthy_services: config.drop_unhealthy_services,
+        health_status_map: config.health_status_map.clone(),
+        target_retention_secs: config.target_retention_secs,
+        group_hash_label: config.group_hash_label,
+        service_weight_total_label: config.service_weight_total_label,
+        collect_all_addresses: config.collect_all_addresses,
+        max_labels_per_group: config.max_labels_per_group,
+        job_label_attribute: config.job_label_attribute.clone(),
+        vip_attribute: config.vip_attribute.clone(),
+        ecs_task_arn_attribute: config.ecs_task_arn_attribute.clone(),
     };
-    let discovery = Discovery::new(servicediscovery_client, discovery_config);
+    let mut discovery = Discovery::new(servicediscovery_client, discovery_config, sleep);
+    if !regional_clients.is_empty() {
+        discovery = discovery.with_regions(regional_clients);
+    }
+
+    let metrics = config.metrics_enabled.then(|| Arc::new(Metrics::default()));
+    if let Some(metrics) = &metrics {
+        discovery = discovery.with_metrics(metrics.clone());
+    }
+
+    if let Some(path) = config.transform_script.as_ref() {
+        match Transform::from_file(path) {
+            Ok(transform) => {
+                info!("📜 Loaded target transform script from {}", path);
+                discovery = discovery.with_transform(transform);
+            }
+            Err(e) => {
+                error!("❌ {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Mode::Once { output } = args.mode {
+        match discovery.discover_targets().await {
+            Ok(targets) => match snapshot::write_targets(&output, &targets) {
+                Ok(()) => {
+                    info!(
+                        "📝 Wrote {} target groups to {}",
+                        targets.len(),
+                        output.display()
+                    );
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    error!("❌ Failed to write {}: {}", output.display(), e);
+                    std::process::exit(1);
+                }
+            },
+            Err(e) => {
+                error!("❌ Discovery failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    // Single route for Cloud Map service discovery
-    let cloudmap_route = warp::path("cloudmap_sd")
+    if let Some(path) = config.snapshot_file.as_ref() {
+        discovery = discovery.with_snapshot(SnapshotStore::new(
+            path,
+            Duration::from_secs(config.snapshot_interval_secs),
+        ));
+        // Start the first crawl now so a loaded snapshot is retired quickly
+        discovery.refresh_in_background();
+    }
+
+    if config.refresh_interval_seconds > 0 {
+        info!(
+            "🔄 Refreshing discovery in the background every {}s",
+            config.refresh_interval_seconds
+        );
+        discovery.spawn_refresh_loop(Duration::from_secs(config.refresh_interval_seconds));
+    }
+
+    let content_type = match config.parse_response_content_type() {
+        Ok(content_type) => content_type,
+        Err(e) => {
+            warn!("⚠️  {}, using application/json", e);
+            "application/json".to_string()
+        }
+    };
+    let handler_config = handlers::Config {
+        content_type,
+        soft_fail: config.soft_fail,
+        background_refresh: config.refresh_interval_seconds > 0,
+        omit_empty_namespaces: config.topology_omit_empty_namespaces,
+        refresh_interval_hint: config.refresh_interval_hint_seconds,
+        compression_min_bytes: config.compression_min_bytes,
+        discovery_permits: config
+            .max_concurrent_discoveries
+            .map(|permits| Arc::new(Semaphore::new(permits))),
+    };
+
+    // Browser origins allowed to fetch /cloudmap_sd cross-origin
+    let cors_origins = match config.parse_cors_allowed_origins() {
+        Ok(origins) => origins,
+        Err(e) => {
+            error!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+    if !cors_origins.is_empty() {
+        info!("🌐 CORS enabled for: {}", cors_origins.join(", "));
+    }
+
+    // Route for Cloud Map service discovery; the path is matched before CORS
+    // so that only this route answers preflight requests
+    let sd_discovery = discovery.clone();
+    let sd_config = handler_config.clone();
+    let sd_handler = warp::get()
+        .and(bearer_auth(config.auth_token.clone()))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::query::<CloudMapSdQuery>())
+        .and_then(
+            move |accept: Option<String>,
+                  if_none_match: Option<String>,
+                  accept_encoding: Option<String>,
+                  query: CloudMapSdQuery| {
+                let discovery = sd_discovery.clone();
+                let handler_config = sd_config.clone();
+                cloudmap_sd_handler(
+                    discovery,
+                    handler_config,
+                    accept,
+                    if_none_match,
+                    accept_encoding,
+                    query,
+                )
+            },
+        );
+    let cloudmap_route = warp::path("cloudmap_sd").and(with_cors(&cors_origins, sd_handler));
+
+    // Readiness route reporting whether discovery has succeeded
+    let ready_discovery = discovery.clone();
+    let ready_route = warp::path("ready").and(warp::get()).and_then(move || {
+        let discovery = ready_discovery.clone();
+        ready_handler(discovery)
+    });
+
+    // Token-protected route that rebuilds the AWS client with fresh credentials
+    let refresh_discovery = discovery.clone();
+    let client_factory: Arc<dyn ClientFactory> = Arc::new(client_factory);
+    let refresh_token = config.refresh_credentials_token.clone();
+    let refresh_route = warp::path("refresh-credentials")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |authorization: Option<String>| {
+            let discovery = refresh_discovery.clone();
+            let factory = client_factory.clone();
+            refresh_credentials_handler(discovery, factory, refresh_token.clone(), authorization)
+        });
+
+    // Route for the namespace/service topology view
+    let topology_route = warp::path("topology")
         .and(warp::get())
-        .and_then(move || {
+        .and(bearer_auth(config.auth_token.clone()))
+        .and(warp::query::<TopologyQuery>())
+        .and_then(move |query: TopologyQuery| {
             let discovery = discovery.clone();
-            cloudmap_sd_handler(discovery)
-        })
+            let handler_config = handler_config.clone();
+            topology_handler(discovery, handler_config, query)
+        });
+
+    // Route for the effective, redacted configuration
+    let config_route = config_route(config.clone());
+
+    // Route for the adapter's own metrics, when enabled
+    let metrics_registry = metrics.clone();
+    let metrics_route = warp::path("metrics").and(warp::get()).and_then(move || {
+        let metrics = metrics_registry.clone();
+        async move {
+            match metrics {
+                Some(metrics) => metrics_handler(metrics).await,
+                None => Err(warp::reject::not_found()),
+            }
+        }
+    });
+
+    // Liveness route that never touches AWS
+    let health_route = warp::path("health")
+        .and(warp::get())
+        .and_then(health_handler);
+
+    let routes = cloudmap_route
+        .or(topology_route)
+        .or(config_route)
+        .or(health_route)
+        .or(ready_route)
+        .or(refresh_route)
+        .or(metrics_route)
+        .recover(handle_rejection)
+        .with(warp::log::custom(move |info| {
+            if let Some(metrics) = &metrics {
+                metrics.record_request(info.status().as_u16());
+            }
+        }))
         .with(warp::log("api"));
 
+    if let Some(socket) = config.unix_socket.as_ref() {
+        let path = Path::new(socket);
+        let listener = match server::bind_unix(path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("❌ Failed to bind {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+
+        if tls.is_some() {
+            warn!("⚠️  TLS is not used on a Unix socket, serving plain HTTP");
+        }
+        info!("🚀 Server starting...");
+        info!("📡 Listening on unix:{}", path.display());
+        log_endpoints(&config);
+        warn!("Press Ctrl+C to stop the server");
+
+        server::serve_unix(listener, routes, server::shutdown_signal()).await;
+        server::remove_unix_socket(path);
+        return;
+    }
+
     let host = match config.parse_host() {
-        Ok(host_array) => host_array,
+        Ok(host) => host,
         Err(e) => {
             warn!(
                 "⚠️  Failed to parse host '{}': {}, using 0.0.0.0",
                 config.host, e
             );
-            [0, 0, 0, 0]
+            IpAddr::from([0, 0, 0, 0])
+        }
+    };
+    let addr = SocketAddr::new(host, config.port);
+
+    let listener = match server::bind_with_retry(
+        addr,
+        config.bind_retries,
+        Duration::from_millis(config.bind_retry_delay_ms),
+    )
+    .await
+    {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("❌ Failed to bind {}: {}", addr, e);
+            std::process::exit(1);
         }
     };
-    let addr = (host, config.port);
 
+    let scheme = if tls.is_some() { "https" } else { "http" };
     info!("🚀 Server starting...");
-    info!("📡 Listening on http://{}:{}", config.host, config.port);
-    info!("📋 Available endpoint:");
-    info!("  GET /cloudmap_sd - AWS Cloud Map service discovery for Prometheus");
-    info!("🔗 Try: http://localhost:{}/cloudmap_sd", config.port);
+    info!("📡 Listening on {}://{}", scheme, addr);
+    log_endpoints(&config);
+    info!("🔗 Try: {}://localhost:{}/cloudmap_sd", scheme, config.port);
     warn!("Press Ctrl+C to stop the server");
 
-    warp::serve(cloudmap_route).run(addr).await;
+    let Some(acceptor) = tls else {
+        warp::serve(routes).incoming(listener).run().await;
+        return;
+    };
+
+    // HTTPS: each accepted connection completes its TLS handshake and is then
+    // served by the same routes through hyper
+    let service = TowerToHyperService::new(warp::service(routes));
+    let shutdown = server::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("⚠️  Failed to accept a connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("⚠️  TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                warn!("⚠️  Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Builds a Cloud Map client, exiting when it can't be built
+///
+/// In offline mode a client is built anyway, so the server can start and
+/// serve a snapshot until credentials become available.
+fn build_client_or_exit(sdk_config: &SdkConfig, offline_mode: bool) -> ServiceDiscoveryClient {
+    match client::build_client(sdk_config) {
+        Ok(client) => client,
+        Err(e) if offline_mode => {
+            warn!("⚠️  {}, continuing in offline mode", e);
+            ServiceDiscoveryClient::new(sdk_config)
+        }
+        Err(e) => {
+            error!("❌ Failed to create the Cloud Map client: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Logs the endpoints the server exposes with this configuration
+fn log_endpoints(config: &Config) {
+    info!("📋 Available endpoints:");
+    info!("  GET /cloudmap_sd - AWS Cloud Map service discovery for Prometheus");
+    info!("  GET /topology - Cloud Map namespaces and services without instances");
+    info!("  GET /config - Effective configuration with secrets redacted");
+    info!("  GET /health - Liveness check (no AWS calls)");
+    info!("  GET /ready - Readiness check (200 once discovery has succeeded)");
+    if config.metrics_enabled {
+        info!("  GET /metrics - Adapter metrics in Prometheus text format");
+    }
+    if config.refresh_credentials_token.is_some() {
+        info!("  POST /refresh-credentials - Rebuild the AWS client with fresh credentials");
+    }
 }
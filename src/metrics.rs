@@ -0,0 +1,368 @@
+//! # Self-Metrics
+//!
+//! This module keeps counters, gauges and histograms describing the adapter
+//! itself and renders them in the Prometheus text exposition format for
+//! `GET /metrics`. A single [`Metrics`] registry is shared by discovery, which
+//! records each run and AWS call, and the HTTP server, which records each
+//! response. Without a registry nothing is recorded.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Content-Type of the Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of the duration histogram buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// What a successful discovery run saw in Cloud Map
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CrawlCounts {
+    /// Namespaces discovered (after filtering)
+    pub namespaces: usize,
+    /// Services discovered in those namespaces
+    pub services: usize,
+    /// Instances listed for those services
+    pub instances: usize,
+}
+
+/// Histogram of durations in seconds, with cumulative `DURATION_BUCKETS`
+#[derive(Debug)]
+struct Histogram {
+    /// Observations at or below each bucket's bound
+    buckets: Vec<u64>,
+    /// Sum of all observations
+    sum: f64,
+    /// Number of observations
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; DURATION_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    /// Records one observation
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    /// Writes the `_bucket`, `_sum` and `_count` series, with `labels`
+    /// (e.g. `operation="list_instances"`) on each
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let bounds = DURATION_BUCKETS.iter().map(f64::to_string);
+        for (bound, count) in bounds
+            .chain(["+Inf".to_string()])
+            .zip(self.buckets.iter().chain([&self.count]))
+        {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, count
+            );
+        }
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+/// Registry of the adapter's own metrics
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Discovery runs that succeeded
+    discovery_successes: AtomicU64,
+    /// Discovery runs that failed
+    discovery_failures: AtomicU64,
+    /// Duration of the latest discovery run in seconds, as `f64` bits
+    last_discovery_duration: AtomicU64,
+    /// Target groups returned by the latest successful discovery run
+    target_groups: AtomicU64,
+    /// Durations of all discovery runs
+    discovery_duration: Mutex<Histogram>,
+    /// Durations of AWS calls, including retries, keyed by operation
+    aws_calls: Mutex<BTreeMap<String, Histogram>>,
+    /// Namespaces, services and instances seen by the latest successful run
+    namespaces: AtomicU64,
+    services: AtomicU64,
+    instances: AtomicU64,
+    /// HTTP responses served, keyed by status code
+    requests: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl Metrics {
+    /// Records the outcome of a discovery run
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - How long the run took
+    /// * `target_groups` - Number of target groups found, or `None` if the run failed
+    pub fn record_discovery(&self, duration: Duration, target_groups: Option<usize>) {
+        self.last_discovery_duration
+            .store(duration.as_secs_f64().to_bits(), Ordering::Relaxed);
+        self.discovery_duration
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .observe(duration);
+        match target_groups {
+            Some(count) => {
+                self.discovery_successes.fetch_add(1, Ordering::Relaxed);
+                self.target_groups.store(count as u64, Ordering::Relaxed);
+            }
+            None => {
+                self.discovery_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Records what a successful discovery run saw in Cloud Map
+    pub fn record_crawl(&self, counts: CrawlCounts) {
+        self.namespaces
+            .store(counts.namespaces as u64, Ordering::Relaxed);
+        self.services
+            .store(counts.services as u64, Ordering::Relaxed);
+        self.instances
+            .store(counts.instances as u64, Ordering::Relaxed);
+    }
+
+    /// Records the duration of an AWS call, including its retries
+    pub fn record_aws_call(&self, operation: &str, duration: Duration) {
+        let mut aws_calls = self.aws_calls.lock().unwrap_or_else(|e| e.into_inner());
+        aws_calls
+            .entry(operation.to_string())
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Records an HTTP response served with `status`
+    pub fn record_request(&self, status: u16) {
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        *requests.entry(status).or_default() += 1;
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "cloudmap_sd_discovery_runs_total",
+            "counter",
+            "Discovery runs by result.",
+        );
+        for (result, count) in [
+            ("success", &self.discovery_successes),
+            ("failure", &self.discovery_failures),
+        ] {
+            let _ = writeln!(
+                out,
+                "cloudmap_sd_discovery_runs_total{{result=\"{}\"}} {}",
+                result,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "cloudmap_sd_last_discovery_duration_seconds",
+            "gauge",
+            "Duration of the latest discovery run.",
+        );
+        let _ = writeln!(
+            out,
+            "cloudmap_sd_last_discovery_duration_seconds {}",
+            f64::from_bits(self.last_discovery_duration.load(Ordering::Relaxed))
+        );
+
+        header(
+            &mut out,
+            "cloudmap_sd_discovery_duration_seconds",
+            "histogram",
+            "Duration of discovery runs.",
+        );
+        self.discovery_duration
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .render(&mut out, "cloudmap_sd_discovery_duration_seconds", "");
+
+        header(
+            &mut out,
+            "cloudmap_sd_aws_call_duration_seconds",
+            "histogram",
+            "Duration of AWS calls, including retries, by operation.",
+        );
+        let aws_calls = self.aws_calls.lock().unwrap_or_else(|e| e.into_inner());
+        for (operation, histogram) in aws_calls.iter() {
+            histogram.render(
+                &mut out,
+                "cloudmap_sd_aws_call_duration_seconds",
+                &format!("operation=\"{}\"", operation),
+            );
+        }
+        drop(aws_calls);
+
+        for (name, help, value) in [
+            (
+                "cloudmap_sd_target_groups",
+                "Target groups returned by the latest successful discovery run.",
+                &self.target_groups,
+            ),
+            (
+                "cloudmap_sd_namespaces",
+                "Namespaces seen by the latest successful discovery run.",
+                &self.namespaces,
+            ),
+            (
+                "cloudmap_sd_services",
+                "Services seen by the latest successful discovery run.",
+                &self.services,
+            ),
+            (
+                "cloudmap_sd_instances",
+                "Instances seen by the latest successful discovery run.",
+                &self.instances,
+            ),
+        ] {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        header(
+            &mut out,
+            "cloudmap_sd_http_requests_total",
+            "counter",
+            "HTTP responses served by status code.",
+        );
+        let requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        for (status, count) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "cloudmap_sd_http_requests_total{{code=\"{}\"}} {}",
+                status, count
+            );
+        }
+
+        out
+    }
+}
+
+/// Writes the `# HELP` and `# TYPE` lines introducing a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_lists_every_metric() {
+        let rendered = Metrics::default().render();
+
+        for name in [
+            "cloudmap_sd_discovery_runs_total",
+            "cloudmap_sd_last_discovery_duration_seconds",
+            "cloudmap_sd_discovery_duration_seconds",
+            "cloudmap_sd_aws_call_duration_seconds",
+            "cloudmap_sd_target_groups",
+            "cloudmap_sd_namespaces",
+            "cloudmap_sd_services",
+            "cloudmap_sd_instances",
+            "cloudmap_sd_http_requests_total",
+        ] {
+            assert!(rendered.contains(&format!("# TYPE {} ", name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_record_discovery() {
+        let metrics = Metrics::default();
+
+        metrics.record_discovery(Duration::from_millis(1500), Some(4));
+        metrics.record_discovery(Duration::from_millis(250), None);
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("cloudmap_sd_discovery_runs_total{result=\"success\"} 1\n"));
+        assert!(rendered.contains("cloudmap_sd_discovery_runs_total{result=\"failure\"} 1\n"));
+        assert!(rendered.contains("cloudmap_sd_last_discovery_duration_seconds 0.25\n"));
+        assert!(rendered.contains("cloudmap_sd_target_groups 4\n"));
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+
+        metrics.record_aws_call("list_instances", Duration::from_millis(20));
+        metrics.record_aws_call("list_instances", Duration::from_millis(700));
+        let rendered = metrics.render();
+
+        let series = "cloudmap_sd_aws_call_duration_seconds";
+        for (bound, count) in [("0.01", 0), ("0.025", 1), ("1", 2), ("+Inf", 2)] {
+            let line = format!(
+                "{}_bucket{{operation=\"list_instances\",le=\"{}\"}} {}\n",
+                series, bound, count
+            );
+            assert!(rendered.contains(&line), "{}", line);
+        }
+        assert!(rendered.contains(&format!(
+            "{}_count{{operation=\"list_instances\"}} 2\n",
+            series
+        )));
+        assert!(
+            rendered.contains("cloudmap_sd_discovery_duration_seconds_bucket{le=\"+Inf\"} 0\n")
+        );
+    }
+
+    #[test]
+    fn test_record_crawl() {
+        let metrics = Metrics::default();
+
+        metrics.record_crawl(CrawlCounts {
+            namespaces: 2,
+            services: 5,
+            instances: 12,
+        });
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("cloudmap_sd_namespaces 2\n"));
+        assert!(rendered.contains("cloudmap_sd_services 5\n"));
+        assert!(rendered.contains("cloudmap_sd_instances 12\n"));
+    }
+
+    #[test]
+    fn test_record_request() {
+        let metrics = Metrics::default();
+
+        metrics.record_request(200);
+        metrics.record_request(200);
+        metrics.record_request(503);
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("cloudmap_sd_http_requests_total{code=\"200\"} 2\n"));
+        assert!(rendered.contains("cloudmap_sd_http_requests_total{code=\"503\"} 1\n"));
+    }
+}
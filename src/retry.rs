@@ -0,0 +1,280 @@
+//! # Retry Budget
+//!
+//! This module retries failed AWS calls made during discovery. Retries are drawn
+//! from a budget shared by every discovery run: during a widespread AWS incident,
+//! independent per-call retries would otherwise multiply the load on an already
+//! struggling API. Once the budget for the current window is spent, failed calls
+//! return their error immediately until the window rolls over.
+//!
+//! Backoff delays go through an injectable [`AsyncSleep`] implementation, the
+//! same one handed to the AWS SDK, so tests can verify timing without waiting.
+
+use aws_sdk_servicediscovery::config::{AsyncSleep, SharedAsyncSleep, Sleep};
+use log::warn;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// [`AsyncSleep`] implementation backed by the Tokio timer
+#[derive(Debug, Default)]
+pub struct TokioSleep;
+
+impl AsyncSleep for TokioSleep {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Sleep::new(tokio::time::sleep(duration))
+    }
+}
+
+/// Token bucket limiting how many retries may happen per time window
+#[derive(Debug)]
+pub struct RetryBudget {
+    /// Retries allowed per window
+    capacity: u32,
+    /// Length of a budget window
+    window: Duration,
+    /// Current window start and remaining tokens
+    state: Mutex<BudgetState>,
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    window_start: Instant,
+    remaining: u32,
+}
+
+impl RetryBudget {
+    /// Creates a budget allowing `capacity` retries per `window`
+    pub fn new(capacity: u32, window: Duration) -> Self {
+        Self {
+            capacity,
+            window,
+            state: Mutex::new(BudgetState {
+                window_start: Instant::now(),
+                remaining: capacity,
+            }),
+        }
+    }
+
+    /// Takes one retry token, refilling the bucket when a new window starts
+    ///
+    /// # Returns
+    ///
+    /// `true` if a retry is allowed, `false` if the budget is exhausted
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if state.window_start.elapsed() >= self.window {
+            state.window_start = Instant::now();
+            state.remaining = self.capacity;
+        }
+
+        if state.remaining > 0 {
+            state.remaining -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Retry behavior for individual AWS calls, backed by a shared budget
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum retries for a single call
+    max_retries: u32,
+    /// Delay before the first retry, doubled on each subsequent retry
+    base_delay: Duration,
+    /// Budget shared by every clone of this policy
+    budget: Arc<RetryBudget>,
+    /// Sleep implementation used for backoff delays
+    sleep: SharedAsyncSleep,
+}
+
+impl RetryPolicy {
+    /// Creates a retry policy
+    ///
+    /// # Arguments
+    ///
+    /// * `max_retries` - Maximum retries for a single call
+    /// * `base_delay` - Delay before the first retry (doubled on each retry)
+    /// * `budget` - Retry budget shared by all calls using this policy
+    /// * `sleep` - Sleep implementation used for backoff delays
+    pub fn new(
+        max_retries: u32,
+        base_delay: Duration,
+        budget: RetryBudget,
+        sleep: SharedAsyncSleep,
+    ) -> Self {
+        Self {
+            max_retries,
+            base_delay,
+            budget: Arc::new(budget),
+            sleep,
+        }
+    }
+
+    /// Runs an operation, retrying failures while the shared budget allows
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - Operation name used in log messages
+    /// * `call` - Produces a fresh future for each attempt
+    ///
+    /// # Returns
+    ///
+    /// The first successful result, or the last error once retries or the
+    /// budget are exhausted
+    pub async fn run<T, E, F, Fut>(&self, operation: &str, mut call: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Display,
+    {
+        let mut delay = self.base_delay;
+        let mut attempt = 0;
+
+        loop {
+            let error = match call().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+
+            if attempt >= self.max_retries {
+                return Err(error);
+            }
+            if !self.budget.try_acquire() {
+                warn!(
+                    "⚠️  Retry budget exhausted, not retrying {}: {}",
+                    operation, error
+                );
+                return Err(error);
+            }
+
+            attempt += 1;
+            warn!(
+                "⚠️  {} failed: {}, retrying in {:?} ({}/{})",
+                operation, error, delay, attempt, self.max_retries
+            );
+            self.sleep.sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Sleeper that records requested delays and returns immediately
+    #[derive(Debug, Clone, Default)]
+    struct RecordingSleep {
+        delays: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    impl AsyncSleep for RecordingSleep {
+        fn sleep(&self, duration: Duration) -> Sleep {
+            self.delays.lock().unwrap().push(duration);
+            Sleep::new(std::future::ready(()))
+        }
+    }
+
+    fn test_policy(max_retries: u32, base_delay: Duration, budget: u32) -> RetryPolicy {
+        RetryPolicy::new(
+            max_retries,
+            base_delay,
+            RetryBudget::new(budget, Duration::from_secs(60)),
+            SharedAsyncSleep::new(RecordingSleep::default()),
+        )
+    }
+
+    fn failing_call(calls: &AtomicU32) -> impl Future<Output = Result<(), String>> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        async { Err("boom".to_string()) }
+    }
+
+    #[test]
+    fn test_retry_budget_exhausts_and_refills() {
+        let budget = RetryBudget::new(2, Duration::from_millis(50));
+
+        assert!(budget.try_acquire());
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(budget.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy_succeeds_after_retry() {
+        let policy = test_policy(3, Duration::from_millis(1), 10);
+        let calls = AtomicU32::new(0);
+
+        let result = policy
+            .run("test", || {
+                let attempt = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if attempt == 0 {
+                        Err("transient".to_string())
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!(result, Ok(1));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy_respects_max_retries() {
+        let policy = test_policy(2, Duration::from_millis(1), 10);
+        let calls = AtomicU32::new(0);
+
+        let result = policy.run("test", || failing_call(&calls)).await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retries_stop_once_budget_is_exhausted() {
+        let policy = test_policy(5, Duration::from_millis(1), 2);
+        let calls = AtomicU32::new(0);
+
+        // The first call spends the whole budget on its two retries
+        assert!(policy.run("first", || failing_call(&calls)).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // A clone shares the budget, so later calls are not retried at all
+        let shared = policy.clone();
+        assert!(shared.run("second", || failing_call(&calls)).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_backoff_timing_with_mock_sleeper() {
+        let sleeper = RecordingSleep::default();
+        let policy = RetryPolicy::new(
+            3,
+            Duration::from_secs(10),
+            RetryBudget::new(10, Duration::from_secs(60)),
+            SharedAsyncSleep::new(sleeper.clone()),
+        );
+        let calls = AtomicU32::new(0);
+        let started = Instant::now();
+
+        assert!(policy.run("test", || failing_call(&calls)).await.is_err());
+
+        assert_eq!(
+            *sleeper.delays.lock().unwrap(),
+            vec![
+                Duration::from_secs(10),
+                Duration::from_secs(20),
+                Duration::from_secs(40)
+            ]
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
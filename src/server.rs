@@ -0,0 +1,313 @@
+//! # HTTP Listener Startup
+//!
+//! This module binds the listener used by the HTTP server. TCP binding is retried
+//! with exponential backoff so that fast container restarts, which can briefly
+//! leave the port in use, don't kill the process on the first attempt.
+//!
+//! In sidecar deployments the server can listen on a Unix domain socket instead,
+//! so no TCP port is exposed. Connections accepted on it are served through
+//! hyper directly, as warp only serves TCP listeners itself.
+//!
+//! For HTTPS, the certificate chain and private key are loaded into a TLS
+//! acceptor wrapping the accepted TCP connections.
+
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use log::{info, warn};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use warp::{Filter, Reply};
+
+/// Upper bound for the delay between two bind attempts
+const MAX_BIND_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Binds a TCP listener, retrying with exponential backoff on failure
+///
+/// # Arguments
+///
+/// * `addr` - Socket address to bind
+/// * `retries` - Number of retries after the initial attempt fails
+/// * `retry_delay` - Delay before the first retry, doubled after every failure
+///
+/// # Returns
+///
+/// * `Ok(TcpListener)` - Listener bound to `addr`
+/// * `Err(io::Error)` - The last bind error once all retries are exhausted
+pub async fn bind_with_retry(
+    addr: SocketAddr,
+    retries: u32,
+    retry_delay: Duration,
+) -> std::io::Result<TcpListener> {
+    let mut delay = retry_delay;
+    let mut attempt = 0;
+
+    loop {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "⚠️  Failed to bind {}: {}, retrying in {:?} ({}/{})",
+                    addr, e, delay, attempt, retries
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_BIND_RETRY_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Binds a Unix domain socket listener at `path`
+///
+/// A socket file left behind by an earlier run that wasn't shut down cleanly
+/// is removed first; any other kind of file at `path` is left alone and the
+/// bind fails.
+pub fn bind_unix(path: &Path) -> std::io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if is_socket(&metadata) => {
+            warn!("⚠️  Removing stale socket file {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+/// Serves `routes` on connections accepted from a Unix domain socket
+///
+/// `routes` must already recover its rejections into replies. Stops accepting
+/// once `shutdown` resolves; connections already accepted are left to finish
+/// on their own.
+pub async fn serve_unix<F, R>(listener: UnixListener, routes: F, shutdown: impl Future<Output = ()>)
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let service = TowerToHyperService::new(warp::service(routes));
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("⚠️  Failed to accept a connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                warn!("⚠️  Connection on the Unix socket failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Loads a PEM certificate chain and private key into a TLS acceptor
+///
+/// The acceptor offers HTTP/2 and HTTP/1.1 through ALPN.
+///
+/// # Returns
+///
+/// * `Ok(TlsAcceptor)` - Acceptor presenting the certificate chain
+/// * `Err(String)` - A file can't be read, holds no PEM item of the expected
+///   kind, or the key doesn't match the certificate
+pub fn tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read certificate {}: {}", cert_path.display(), e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Failed to read private key {}: {}", key_path.display(), e))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Removes the socket file once the server has shut down
+pub fn remove_unix_socket(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => info!("🧹 Removed socket file {}", path.display()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("⚠️  Failed to remove socket file {}: {}", path.display(), e),
+    }
+}
+
+/// Checks whether file metadata describes a Unix domain socket
+fn is_socket(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_socket()
+}
+
+/// Resolves once the process receives SIGINT or SIGTERM
+pub async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("⚠️  Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("🛑 Shutting down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::PrometheusTarget;
+    use crate::handlers::handle_rejection;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    /// Socket path unique to this test process and `name`
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cloudmap-sd-{}-{}.sock", std::process::id(), name))
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_retry_free_port() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        let listener = bind_with_retry(addr, 0, Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        assert!(listener.local_addr().unwrap().port() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_retry_gives_up_on_occupied_port() {
+        let occupied = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = occupied.local_addr().unwrap();
+
+        let result = bind_with_retry(addr, 2, Duration::from_millis(10)).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_retry_succeeds_once_port_is_released() {
+        let occupied = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = occupied.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(occupied);
+        });
+
+        let listener = bind_with_retry(addr, 5, Duration::from_millis(20))
+            .await
+            .unwrap();
+
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn test_request_over_unix_socket_returns_targets() {
+        let path = socket_path("serve");
+        let listener = bind_unix(&path).unwrap();
+        let targets = vec![PrometheusTarget {
+            targets: vec!["10.0.0.1:9100".to_string()],
+            labels: BTreeMap::new(),
+        }];
+        let routes = warp::path("cloudmap_sd")
+            .map(move || warp::reply::json(&targets))
+            .recover(handle_rejection);
+        tokio::spawn(serve_unix(listener, routes, std::future::pending()));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /cloudmap_sd HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        remove_unix_socket(&path);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains(r#"[{"targets":["10.0.0.1:9100"],"labels":{}}]"#));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix_replaces_stale_socket() {
+        let path = socket_path("stale");
+        drop(bind_unix(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = bind_unix(&path);
+        remove_unix_socket(&path);
+
+        assert!(listener.is_ok());
+    }
+
+    #[test]
+    fn test_tls_acceptor_rejects_files_without_pem_items() {
+        let path = socket_path("not-pem");
+        std::fs::write(&path, "not a certificate").unwrap();
+
+        let result = tls_acceptor(&path, &path);
+        std::fs::remove_file(&path).unwrap();
+
+        let Err(e) = result else {
+            panic!("expected an error for a file without PEM items");
+        };
+        assert!(e.contains("Failed to read"), "{}", e);
+    }
+
+    #[test]
+    fn test_tls_acceptor_rejects_missing_files() {
+        let missing = Path::new("/nonexistent/cert.pem");
+
+        assert!(tls_acceptor(missing, missing).is_err());
+    }
+
+    #[test]
+    fn test_bind_unix_refuses_regular_file() {
+        let path = socket_path("file");
+        std::fs::write(&path, "not a socket").unwrap();
+
+        let result = bind_unix(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::AlreadyExists);
+    }
+}
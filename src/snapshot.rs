@@ -0,0 +1,194 @@
+//! # Warm Standby Snapshots
+//!
+//! This module persists the latest discovered target groups to disk and loads
+//! them again at startup, so a restarted adapter can answer Prometheus right
+//! away instead of waiting for its first full Cloud Map crawl.
+//!
+//! Snapshots use the same JSON format as the `/cloudmap_sd` response. They are
+//! written to a temporary file and renamed into place, so a crash mid-write
+//! never leaves a truncated snapshot behind. A snapshot that can't be read or
+//! parsed is ignored with a warning.
+//!
+//! The same atomic writer produces the `file_sd` output of `--once` mode.
+
+use crate::discovery::PrometheusTarget;
+use log::{debug, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// On-disk store for the latest discovery snapshot
+#[derive(Debug)]
+pub struct SnapshotStore {
+    /// Snapshot file location
+    path: PathBuf,
+    /// Minimum time between two snapshot writes
+    interval: Duration,
+    /// When the snapshot was last written by this process
+    last_written: Mutex<Option<Instant>>,
+}
+
+impl SnapshotStore {
+    /// Creates a snapshot store
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Snapshot file location
+    /// * `interval` - Minimum time between two snapshot writes
+    pub fn new(path: impl Into<PathBuf>, interval: Duration) -> Self {
+        Self {
+            path: path.into(),
+            interval,
+            last_written: Mutex::new(None),
+        }
+    }
+
+    /// Loads the snapshot from disk
+    ///
+    /// # Returns
+    ///
+    /// The stored target groups, or `None` if the file is missing or corrupt
+    pub fn load(&self) -> Option<Vec<PrometheusTarget>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) => {
+                debug!("📦 No snapshot loaded from {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+
+        match serde_json::from_str(&content) {
+            Ok(targets) => Some(targets),
+            Err(e) => {
+                warn!(
+                    "⚠️  Ignoring corrupt snapshot {}: {}",
+                    self.path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Writes the snapshot if the write interval has elapsed since the last write
+    ///
+    /// Failures are logged and otherwise ignored; a stale snapshot only matters
+    /// after a restart.
+    pub fn save(&self, targets: &[PrometheusTarget]) {
+        let mut last_written = self.last_written.lock().unwrap();
+        if last_written.is_some_and(|written| written.elapsed() < self.interval) {
+            return;
+        }
+
+        match write_targets(&self.path, targets) {
+            Ok(()) => {
+                debug!("📦 Wrote snapshot to {}", self.path.display());
+                *last_written = Some(Instant::now());
+            }
+            Err(e) => warn!(
+                "⚠️  Failed to write snapshot {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Writes target groups as Prometheus `file_sd` JSON, atomically
+///
+/// The JSON is written to `<path>.tmp` and renamed over `path`, so readers such
+/// as Prometheus' file watcher never see a partially written file.
+pub fn write_targets(path: &Path, targets: &[PrometheusTarget]) -> std::io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, serde_json::to_vec(targets)?)?;
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "cloudmap-sd-snapshot-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn test_targets() -> Vec<PrometheusTarget> {
+        vec![PrometheusTarget {
+            targets: vec!["10.0.0.1:8080".to_string()],
+            labels: BTreeMap::from([(
+                "__meta_cloudmap_service_name".to_string(),
+                "web".to_string(),
+            )]),
+        }]
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let path = test_path("round-trip");
+        let store = SnapshotStore::new(&path, Duration::ZERO);
+
+        store.save(&test_targets());
+        let loaded = SnapshotStore::new(&path, Duration::ZERO).load();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, Some(test_targets()));
+    }
+
+    #[test]
+    fn test_corrupt_snapshot_is_ignored() {
+        let path = test_path("corrupt");
+        fs::write(&path, "[{\"targets\": [\"10.0.0.1\"").unwrap();
+
+        let loaded = SnapshotStore::new(&path, Duration::ZERO).load();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, None);
+    }
+
+    #[test]
+    fn test_missing_snapshot_is_ignored() {
+        let store = SnapshotStore::new(test_path("missing"), Duration::ZERO);
+
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn test_write_targets_produces_file_sd_json() {
+        let path = test_path("file-sd");
+
+        write_targets(&path, &test_targets()).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let tmp_left = Path::new(&format!("{}.tmp", path.display())).exists();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&content).unwrap(),
+            serde_json::json!([{
+                "targets": ["10.0.0.1:8080"],
+                "labels": {"__meta_cloudmap_service_name": "web"}
+            }])
+        );
+        assert!(!tmp_left);
+    }
+
+    #[test]
+    fn test_save_respects_interval() {
+        let path = test_path("interval");
+        let store = SnapshotStore::new(&path, Duration::from_secs(3600));
+
+        store.save(&test_targets());
+        store.save(&[]);
+        let loaded = store.load();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, Some(test_targets()));
+    }
+}
@@ -0,0 +1,204 @@
+//! # Target Group Transforms
+//!
+//! This module applies an optional user-provided [Rhai](https://rhai.rs) script to
+//! every discovered target group, so operators can drop or relabel groups without
+//! recompiling the service.
+//!
+//! ## Script Contract
+//!
+//! The script runs once per target group with two variables in scope:
+//!
+//! - `targets`: array of target address strings
+//! - `labels`: map of label names to label values
+//!
+//! Changes made to either variable are written back to the group. If the script
+//! evaluates to `false` the group is dropped; any other result keeps it.
+//!
+//! ```rhai
+//! // Drop internal services and tag everything else with a team label
+//! labels["team"] = "platform";
+//! labels["__meta_cloudmap_service_name"] != "internal"
+//! ```
+//!
+//! Runtime errors fail closed: the affected group is skipped and the error logged.
+
+use crate::discovery::PrometheusTarget;
+use log::{debug, error};
+use rhai::{AST, Array, Dynamic, Engine, Map, Scope};
+
+/// Compiled transform script applied to each discovered target group
+pub struct Transform {
+    /// Rhai engine used to evaluate the script
+    engine: Engine,
+    /// Pre-compiled script
+    ast: AST,
+}
+
+impl std::fmt::Debug for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transform").finish_non_exhaustive()
+    }
+}
+
+impl Transform {
+    /// Compiles a transform script from a file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the Rhai script
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Transform)` - Compiled transform ready to apply
+    /// * `Err(String)` - The file could not be read or does not compile
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|e| format!("Failed to compile transform script '{}': {}", path, e))?;
+        Ok(Self { engine, ast })
+    }
+
+    /// Compiles a transform script from source text
+    #[cfg(test)]
+    pub fn from_source(script: &str) -> Result<Self, String> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile(script)
+            .map_err(|e| format!("Failed to compile transform script: {}", e))?;
+        Ok(Self { engine, ast })
+    }
+
+    /// Runs the script against a target group
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - Target group produced by discovery
+    ///
+    /// # Returns
+    ///
+    /// * `Some(PrometheusTarget)` - The (possibly relabeled) group to emit
+    /// * `None` - The script dropped the group or failed
+    pub fn apply(&self, mut group: PrometheusTarget) -> Option<PrometheusTarget> {
+        let targets: Array = group.targets.iter().cloned().map(Dynamic::from).collect();
+        let labels: Map = group
+            .labels
+            .iter()
+            .map(|(key, value)| (key.as_str().into(), Dynamic::from(value.clone())))
+            .collect();
+
+        let mut scope = Scope::new();
+        scope.push("targets", targets);
+        scope.push("labels", labels);
+
+        let result = match self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+        {
+            Ok(result) => result,
+            Err(e) => {
+                error!("❌ Transform script failed, skipping target group: {}", e);
+                return None;
+            }
+        };
+
+        if result.as_bool() == Ok(false) {
+            debug!(
+                "⏭️  Transform script dropped target group: {:?}",
+                group.labels
+            );
+            return None;
+        }
+
+        match (
+            scope.get_value::<Array>("targets"),
+            scope.get_value::<Map>("labels"),
+        ) {
+            (Some(targets), Some(labels)) => {
+                group.targets = targets
+                    .into_iter()
+                    .map(|target| target.to_string())
+                    .collect();
+                group.labels = labels
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect();
+                Some(group)
+            }
+            _ => {
+                error!(
+                    "❌ Transform script left `targets` or `labels` with an unexpected type, skipping target group"
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn test_group(service_name: &str) -> PrometheusTarget {
+        PrometheusTarget {
+            targets: vec!["10.0.0.1".to_string()],
+            labels: BTreeMap::from([(
+                "__meta_cloudmap_service_name".to_string(),
+                service_name.to_string(),
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_transform_relabels_group() {
+        let transform = Transform::from_source(
+            r#"
+            labels["team"] = "platform";
+            targets.push("10.0.0.2");
+            "#,
+        )
+        .unwrap();
+
+        let group = transform.apply(test_group("web")).unwrap();
+
+        assert_eq!(group.targets, vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(group.labels.get("team"), Some(&"platform".to_string()));
+        assert_eq!(
+            group.labels.get("__meta_cloudmap_service_name"),
+            Some(&"web".to_string())
+        );
+    }
+
+    #[test]
+    fn test_transform_drops_group() {
+        let transform =
+            Transform::from_source(r#"labels["__meta_cloudmap_service_name"] != "internal""#)
+                .unwrap();
+
+        assert!(transform.apply(test_group("internal")).is_none());
+        assert!(transform.apply(test_group("web")).is_some());
+    }
+
+    #[test]
+    fn test_transform_error_fails_closed() {
+        let transform = Transform::from_source(r#"throw "boom";"#).unwrap();
+
+        assert!(transform.apply(test_group("web")).is_none());
+    }
+
+    #[test]
+    fn test_transform_compile_error() {
+        let result = Transform::from_source("let = ;");
+
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Failed to compile"));
+    }
+
+    #[test]
+    fn test_transform_missing_file() {
+        let result = Transform::from_file("/nonexistent/transform.rhai");
+
+        assert!(result.is_err());
+    }
+}